@@ -9,12 +9,13 @@ extern crate log;
 extern crate libcustomlogger;
 extern crate libcsearch;
 
-use libcsearch::reader::{POST_ENTRY_SIZE, IndexReader};
+use libcsearch::reader::IndexReader;
 use libcsearch::regexp::Query;
 
 use std::collections::BTreeSet;
 use std::env;
 use std::io::{self, Write};
+use std::path::Path;
 
 fn main() {
     libcustomlogger::init(log::LogLevelFilter::Info).unwrap();
@@ -64,33 +65,34 @@ fn main() {
     if let Some(t) = matches.value_of("with-trigram") {
         let t_num = u32::from_str_radix(t, 10).unwrap();
         let mut h: Option<BTreeSet<u32>> = None;
-        let file_ids = libcsearch::reader::PostReader::list(&idx, t_num, &mut h);
+        let file_ids = libcsearch::reader::PostReader::list(&idx, t_num, &mut h).unwrap();
         println!("{:?}", file_ids);
     }
 
 }
 
 fn print_indexed_files(idx: &IndexReader) {
-    let post = idx.query(Query::all());
+    let post = idx.query(Query::all()).unwrap();
     for each_fileid in post.into_inner() {
-        println!("{}: {}", each_fileid, idx.name(each_fileid));
+        println!("{}: {}", each_fileid, Path::new(&idx.name(each_fileid)).display());
     }
 }
 
 fn dump_posting_list(idx: &IndexReader) -> io::Result<()> {
+    let entry_size = idx.post_entry_size();
     let d: &[u8] = unsafe {
         idx.as_slice()
             .split_at(idx.post_index)
             .1
-            .split_at(POST_ENTRY_SIZE * idx.num_post)
+            .split_at(entry_size * idx.num_post)
             .0
     };
     for i in 0..idx.num_post {
         try!(writeln!(&mut std::io::stdout(),
                       "{} {} {}",
-                      d[i * POST_ENTRY_SIZE],
-                      d[i * POST_ENTRY_SIZE + 1],
-                      d[i * POST_ENTRY_SIZE + 2]));
+                      d[i * entry_size],
+                      d[i * entry_size + 1],
+                      d[i * entry_size + 2]));
     }
     Ok(())
 }
@@ -7,6 +7,7 @@
 extern crate clap;
 extern crate glob;
 extern crate regex;
+extern crate tempfile;
 #[macro_use]
 extern crate log;
 extern crate walkdir;
@@ -19,9 +20,12 @@ extern crate libprofiling;
 extern crate libvarint;
 
 
-use libcsearch::reader::IndexReader;
-use libcindex::writer::{IndexWriter, IndexErrorKind};
+use libcsearch::reader::{IndexReader, IndexVersion};
+use libcindex::indexfile::IndexLock;
+use libcindex::walk::{IgnoreRules, SeenFiles};
+use libcindex::writer::{IndexOptions, IndexWriter, IndexErrorKind, StoredOptions};
 use log::LogLevelFilter;
+use tempfile::NamedTempFile;
 use walkdir::{WalkDir, WalkDirIterator};
 
 use std::collections::HashSet;
@@ -115,13 +119,61 @@ or, equivalently:
 
 If cindex is invoked with no paths, it reindexes the paths that have
 already been added, in case the files have changed.  Thus, 'cindex' by
-itself is a useful command to run in a nightly cron job.
+itself is a useful command to run in a nightly cron job.  Files whose
+modification time and size haven't changed since they were last indexed
+are copied from the existing index instead of being read again, unless
+they were modified after that indexing run started.
 
 By default cindex adds the named paths to the index but preserves
 information about other paths that might already be indexed
 (the ones printed by cindex --list).  The --reset flag causes cindex to
 delete the existing index before indexing the new paths.
-With no path arguments, cindex -reset removes the index.";
+With no path arguments, cindex -reset removes the index.  To stop
+indexing some of the paths instead, cindex --remove path... rewrites the
+index without them and the files in them.  Removing a directory inside an
+indexed path leaves that path indexed, so reindexing adds it back.
+
+Files and directories listed in .gitignore, .ignore and .csearchignore
+files aren't indexed.  The files use the .gitignore syntax, and each one
+applies to the directory it's in and everything below it.  Rules in
+.csearchignore take precedence over the other two, so it can re-include
+files git ignores with '!'.  The --no-ignore flag indexes everything.
+
+Symbolic links are followed, except with --no-follow-simlinks, which
+skips them.  Links back to a directory being walked are skipped with a
+warning.  Links (symbolic or hard) can still make the same file
+reachable under several paths; --dedupe-inodes indexes each file only
+once, under the first path found, by its device and inode numbers.
+Separate files with the same contents, such as vendored copies of a
+library, are still indexed separately, unless --dedupe-contents is given.
+Then copies share the posting entries of the first one, found by the
+SHA-256 digests of their contents, and the index is smaller.
+
+The --relative-to flag stores paths relative to a root directory, so the
+index can be copied to another machine with the files under a different
+directory (see csearch --root-map).  Every indexed path has to be inside
+the root.  When adding to an existing relative index the root it was
+built with is used.
+
+The limits set with --maxFileLen, --maxLineLen, --maxtrigrams and
+--maxinvalidutf8ratio are stored in the index, and used again when adding
+to it or reindexing it unless they're given again.
+
+The --stats flag prints what happened while indexing: the number of files
+indexed and skipped (and why), the most common trigrams and the time each
+phase took.  The statistics cover the files indexed by this run; the index
+size is that of the final index.  --stats-format json prints them as one
+line of JSON, without the usual progress messages.
+
+The index is never written in place: cindex writes a new one next to it
+and renames it over the old one once it's complete, so searches keep
+working while cindex runs and a crash leaves the old index intact.  Runs
+that update the same index take turns, holding a lock on a .lock file next
+to it, and remove the temporary files left by runs that crashed.
+
+The --go-compatible flag writes the index format of the Go version of
+csearch, so both can use the same index.  Adding to an index in that
+format keeps it in that format.";
 
 fn main() {
     let matches = clap::App::new("cindex")
@@ -141,6 +193,12 @@ fn main() {
             .conflicts_with("path")
             .conflicts_with("list-paths")
             .help("discard existing index"))
+        .arg(clap::Arg::with_name("remove")
+            .long("remove")
+            .requires("path")
+            .conflicts_with("list-paths")
+            .conflicts_with("reset-index")
+            .help("remove the paths and the files in them from the index"))
         .arg(clap::Arg::with_name("INDEX_FILE")
             .long("indexpath")
             .takes_value(true)
@@ -148,6 +206,12 @@ fn main() {
         .arg(clap::Arg::with_name("no-follow-simlinks")
             .long("no-follow-simlinks")
             .help("do not follow symlinked files and directories"))
+        .arg(clap::Arg::with_name("dedupe-inodes")
+            .long("dedupe-inodes")
+            .help("index a file reachable under several paths only once, by device and inode"))
+        .arg(clap::Arg::with_name("dedupe-contents")
+            .long("dedupe-contents")
+            .help("store the trigrams of files with the same contents only once"))
         .arg(clap::Arg::with_name("MAX_FILE_SIZE_BYTES")
             .long("maxFileLen")
             .takes_value(true)
@@ -169,10 +233,35 @@ fn main() {
             .long("exclude")
             .takes_value(true)
             .help("path to file containing a list of file patterns to exclude from indexing"))
+        .arg(clap::Arg::with_name("no-ignore")
+            .long("no-ignore")
+            .help("index files listed in .gitignore, .ignore and .csearchignore files"))
         .arg(clap::Arg::with_name("FILE")
             .long("filelist")
             .takes_value(true)
             .help("path to file containing a list of file paths to index"))
+        .arg(clap::Arg::with_name("ROOT")
+            .long("relative-to")
+            .takes_value(true)
+            .help("store paths relative to ROOT so the index can be moved"))
+        .arg(clap::Arg::with_name("go-compatible")
+            .long("go-compatible")
+            .conflicts_with("ROOT")
+            .help("write an index the Go version of csearch can read"))
+        .arg(clap::Arg::with_name("N")
+            .long("jobs")
+            .short("j")
+            .takes_value(true)
+            .help("read files on N threads (default: one per CPU)"))
+        .arg(clap::Arg::with_name("stats")
+            .long("stats")
+            .help("print statistics about the files indexed"))
+        .arg(clap::Arg::with_name("FORMAT")
+            .long("stats-format")
+            .takes_value(true)
+            .possible_values(&["text", "json"])
+            .requires("stats")
+            .help("format of the statistics printed by --stats (default: text)"))
         .arg(clap::Arg::with_name("verbose")
             .long("verbose")
             .help("print extra information"))
@@ -183,16 +272,19 @@ fn main() {
 
     let max_log_level = if matches.is_present("verbose") {
         LogLevelFilter::Trace
+    } else if matches.value_of("FORMAT") == Some("json") {
+        // keep the progress messages out of the JSON
+        LogLevelFilter::Warn
     } else {
         LogLevelFilter::Info
     };
     libcustomlogger::init(max_log_level).unwrap();
 
-    let mut excludes: Vec<glob::Pattern> = vec![glob::Pattern::new(".csearchindex").unwrap()];
-    let mut args = Vec::<String>::new();
+    let mut excludes: Vec<glob::Pattern> = Vec::new();
+    let mut args = Vec::<OsString>::new();
 
-    if let Some(p) = matches.values_of("path") {
-        args.extend(p.map(String::from));
+    if let Some(p) = matches.values_of_os("path") {
+        args.extend(p.map(OsString::from));
     }
 
     matches.value_of("INDEX_FILE").map(|p| {
@@ -202,11 +294,12 @@ fn main() {
     if matches.is_present("list-paths") {
         let i = open_index_or_fail();
         for each_file in i.indexed_paths() {
-            println!("{}", each_file);
+            println!("{}", Path::new(&each_file).display());
         }
         return;
     }
     if matches.is_present("reset-index") {
+        let _lock = lock_index_or_fail();
         let index_path = libcsearch::csearch_index();
         let p = Path::new(&index_path);
         if !p.exists() {
@@ -219,6 +312,10 @@ fn main() {
         }
         return;
     }
+    if matches.is_present("remove") {
+        remove_paths(&args);
+        return;
+    }
     if let Some(exc_path_str) = matches.value_of("EXCLUDE_FILE") {
         let exclude_path = Path::new(exc_path_str);
         let f = BufReader::new(File::open(exclude_path).expect("exclude file open error"));
@@ -228,9 +325,11 @@ fn main() {
     if let Some(file_list_str) = matches.value_of("FILE") {
         let file_list = Path::new(file_list_str);
         let f = BufReader::new(File::open(file_list).expect("filelist file open error"));
-        args.extend(f.lines().map(|f| f.unwrap().trim().to_string()));
+        args.extend(f.lines().map(|f| OsString::from(f.unwrap().trim())));
     }
 
+    // held until the new index replaces the old one
+    let lock = lock_index_or_fail();
     if args.is_empty() {
         let i = open_index_or_fail();
         for each_file in i.indexed_paths() {
@@ -245,58 +344,131 @@ fn main() {
         .collect();
     paths.sort();
 
-    let mut index_path = libcsearch::csearch_index();
-    let needs_merge = if Path::new(&index_path).exists() {
-        index_path.push('~');
-        true
+    let index_path = libcsearch::csearch_index();
+    let needs_merge = Path::new(&index_path).exists();
+    let new_index = temp_file_or_fail(&lock);
+
+    // settings of an existing index are kept unless overridden
+    let existing = if needs_merge {
+        Some(open_index_or_fail())
+    } else {
+        None
+    };
+    let root = match matches.value_of_os("ROOT") {
+        Some(r) => Some(normalize(r).expect("failed to resolve --relative-to")),
+        None => existing.as_ref().and_then(|i| i.root()).map(PathBuf::from),
+    };
+    let version = if matches.is_present("go-compatible") ||
+                     existing.as_ref().map(|i| i.version()) == Some(IndexVersion::V1) {
+        IndexVersion::V1
     } else {
-        false
+        IndexVersion::V2
+    };
+    if matches.is_present("go-compatible") &&
+       existing.as_ref().map(|i| i.version()) == Some(IndexVersion::V2) {
+        warn!("the existing index isn't in the Go format, so the merged index won't be either. \
+               use --reset to start over");
+    }
+    let stored = match existing.as_ref().map(StoredOptions::read) {
+        Some(Ok(stored)) => stored,
+        Some(Err(e)) => {
+            warn!("ignoring the options stored in the existing index: {}", e);
+            None
+        }
+        None => None,
     };
+    let mut options = match stored {
+        Some(s) => {
+            debug!("using options stored by {}: {:?}", s.tool_version, s.options);
+            s.options.to_builder()
+        }
+        None => IndexOptions::builder(),
+    };
+    if let Some(t) = get_value_from_matches::<u64>(&matches, "MAX_TRIGRAMS_COUNT") {
+        options = options.max_trigram_count(t);
+    }
+    if let Some(u) = get_value_from_matches::<f64>(&matches, "MAX_INVALID_UTF8_RATIO") {
+        options = options.max_utf8_invalid(u);
+    }
+    if let Some(s) = get_value_from_matches::<u64>(&matches, "MAX_FILE_SIZE_BYTES") {
+        options = options.max_file_len(s);
+    }
+    if let Some(b) = get_value_from_matches::<u64>(&matches, "MAX_LINE_LEN_BYTES") {
+        options = options.max_line_len(b);
+    }
+    let options = match options.build() {
+        Ok(options) => options,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    // files that haven't changed are reused from the existing index, and if
+    // it has no other paths the new index replaces it instead of being
+    // merged into it
+    let replaces_existing = existing.as_ref().map_or(false, |i| {
+        i.indexed_paths().iter().all(|old| paths.iter().any(|p| Path::new(old).starts_with(p)))
+    });
+    let previous = existing.filter(|i| i.version() == IndexVersion::V2);
+    if version == IndexVersion::V1 && root.is_some() {
+        error!("an index with a root (see --relative-to) can't be written in the Go format");
+        std::process::exit(2);
+    }
+    if let Some(ref root) = root {
+        if let Some(p) = paths.iter().find(|p| !p.starts_with(root)) {
+            error!("{} is outside of the index root {}", p.display(), root.display());
+            std::process::exit(2);
+        }
+    }
 
     let (tx, rx) = mpsc::channel::<OsString>();
     // copying these variables into the worker thread
-    let index_path_cloned = index_path.clone();
+    let new_index_path = new_index.path().to_path_buf();
     let paths_cloned = paths.clone();
     let log_skipped = matches.is_present("logskip");
+    let print_stats = matches.is_present("stats");
+    let dedupe_contents = matches.is_present("dedupe-contents");
+    let stats_format = matches.value_of("FORMAT").unwrap_or("text").to_string();
+    let jobs = get_value_from_matches::<usize>(&matches, "N")
+        .unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
     let h = thread::spawn(move || {
         let mut seen = HashSet::<OsString>::new();
-        let mut i = match IndexWriter::new(index_path_cloned) {
+        let mut i = match IndexWriter::new(new_index_path) {
             Ok(i) => i,
             Err(e) => panic!("IndexWriter: {}", e),
         };
-        if let Some(t) = get_value_from_matches::<u64>(&matches, "MAX_TRIGRAMS_COUNT") {
-            i.max_trigram_count = t;
-        }
-        if let Some(u) = get_value_from_matches::<f64>(&matches, "MAX_INVALID_UTF8_RATIO") {
-            i.max_utf8_invalid = u;
-        }
-        if let Some(s) = get_value_from_matches::<u64>(&matches, "MAX_FILE_SIZE_BYTES") {
-            i.max_file_len = s;
-        }
-        if let Some(b) = get_value_from_matches::<u64>(&matches, "MAX_LINE_LEN_BYTES") {
-            i.max_line_len = b;
+        i.set_options(options);
+        i.root = root;
+        i.version = version;
+        i.deduplicate = dedupe_contents;
+        if let Some(ix) = previous {
+            i.set_previous(ix);
         }
         i.add_paths(paths_cloned.into_iter().map(PathBuf::into_os_string));
         let _frame = libprofiling::profile("Index files");
-        while let Ok(f) = rx.recv() {
-            if seen.contains(&f) {
-                continue;
-            }
-            if let Err(ref e) = i.add_file(&f) {
+        let files = rx.iter().filter(|f| seen.insert(f.clone()));
+        i.add_files(files, jobs, |f, e| {
                 match e.kind() {
-                    IndexErrorKind::IoError(_) => warn!("{}: {}", Path::new(&f).display(), e),
+                    IndexErrorKind::IoError(_) => warn!("{}: {}", f.display(), e),
                     _ if log_skipped => warn!("{:?}: skipped. {}", f, e),
                     _ => (),
                 }
-            }
-            seen.insert(f);
-        }
+            })
+            .expect("failed to write index");
         info!("flush index");
-        i.flush().expect("failed to flush index to disk");
+        let stats = i.flush().expect("failed to flush index to disk");
         drop(_frame);
         libprofiling::print_profiling();
+        stats
     });
 
+    let use_ignore_files = !matches.is_present("no-ignore");
+    let follow_links = !matches.is_present("no-follow-simlinks");
+    let mut seen_files = if matches.is_present("dedupe-inodes") {
+        Some(SeenFiles::new())
+    } else {
+        None
+    };
     for each_path in paths {
         if !each_path.exists() || !each_path.is_dir() {
             warn!("{} - directory doesn't exist. Skipping...",
@@ -305,37 +477,139 @@ fn main() {
         }
         info!("index {}", each_path.display());
         let tx = tx.clone();
+        let ignore_rules = IgnoreRules::new(&each_path);
         let files = WalkDir::new(each_path)
-            .follow_links(true)
+            .follow_links(follow_links)
             .into_iter()
             .filter_entry(|d| {
                 let p = d.path();
-                !excludes.iter().any(|r| r.matches_path(&p))
+                if lock.is_index_file(p) || excludes.iter().any(|r| r.matches_path(&p)) {
+                    return false;
+                }
+                // paths given on the command line are indexed even if ignored
+                !use_ignore_files || d.depth() == 0 ||
+                !ignore_rules.is_ignored(p, d.file_type().is_dir())
+            })
+            .filter_map(|r| match r {
+                Ok(d) => Some(d),
+                Err(e) => {
+                    if let Some(ancestor) = e.loop_ancestor() {
+                        warn!("{}: symlink loop back to {}. Skipping...",
+                              e.path().unwrap_or(ancestor).display(),
+                              ancestor.display());
+                    }
+                    None
+                }
             })
-            .filter_map(Result::ok)
-            .filter(|d| !d.file_type().is_dir());
+            .filter(|d| !d.file_type().is_dir() && !d.file_type().is_symlink());
 
         for d in files {
+            if let Some(ref mut seen) = seen_files {
+                match seen.first_visit(d.path()) {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        debug!("{}: already indexed under another path", d.path().display());
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("{}: {}", d.path().display(), e);
+                        continue;
+                    }
+                }
+            }
             tx.send(OsString::from(d.path())).unwrap();
         }
     }
     drop(tx);
-    h.join().unwrap();
-    if needs_merge {
-        let dest_path = index_path.clone() + &"~";
-        let src1_path = libcsearch::csearch_index();
-        let src2_path = index_path.clone();
-        info!("merge {} {}", src1_path, src2_path);
-        libcindex::merge::merge(dest_path, src1_path, src2_path).unwrap();
-        fs::remove_file(index_path.clone()).unwrap();
-        fs::remove_file(libcsearch::csearch_index()).unwrap();
-        fs::rename(index_path + &"~", libcsearch::csearch_index()).unwrap();
+    let mut stats = h.join().unwrap();
+    if needs_merge && !replaces_existing {
+        let merged = temp_file_or_fail(&lock);
+        info!("merge {} {}", index_path, new_index.path().display());
+        if let Err(e) = libcindex::merge::merge(merged.path(), &index_path, new_index.path()) {
+            error!("merge: {}", e);
+            std::process::exit(1);
+        }
+        replace_or_fail(&lock, merged);
+        stats.index_bytes = fs::metadata(&index_path).unwrap().len();
+    } else {
+        info!("replace {}", index_path);
+        replace_or_fail(&lock, new_index);
+    }
+    if print_stats {
+        if stats_format == "json" {
+            println!("{}", stats.to_json());
+        } else {
+            println!("{}", stats);
+        }
     }
 
     info!("done");
     libprofiling::print_profiling();
 }
 
+/// Rewrites the index without `args` and the files in them
+fn remove_paths(args: &[OsString]) {
+    let lock = lock_index_or_fail();
+    let paths = args.iter()
+        .map(|f| env::current_dir().unwrap().join(f))
+        // removed directories might not exist anymore
+        .map(|f| normalize(&f).unwrap_or(f))
+        .collect::<Vec<_>>();
+    let indexed = open_index_or_fail().indexed_paths();
+    for p in &paths {
+        if !indexed.iter().any(|i| Path::new(i).starts_with(p) || p.starts_with(i)) {
+            warn!("{} isn't indexed", p.display());
+        }
+    }
+    let index_path = libcsearch::csearch_index();
+    let dest = temp_file_or_fail(&lock);
+    info!("remove {} paths from {}", paths.len(), index_path);
+    if let Err(e) = libcindex::merge::remove(dest.path(), &index_path, &paths) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    replace_or_fail(&lock, dest);
+}
+
+/// Locks the index against other runs of cindex, and removes the files
+/// left behind by runs that crashed
+fn lock_index_or_fail() -> IndexLock {
+    let index_path = libcsearch::csearch_index();
+    let lock = match IndexLock::acquire(&index_path) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("lock {}: {}", index_path, e);
+            std::process::exit(1);
+        }
+    };
+    match lock.remove_stale_files() {
+        Ok(removed) => {
+            for p in removed {
+                info!("removed {}, left by an earlier run", p.display());
+            }
+        }
+        Err(e) => warn!("removing temporary files: {}", e),
+    }
+    lock
+}
+
+fn temp_file_or_fail(lock: &IndexLock) -> NamedTempFile {
+    match lock.temp_file() {
+        Ok(f) => f,
+        Err(e) => {
+            error!("creating a temporary file next to {}: {}", lock.index().display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn replace_or_fail(lock: &IndexLock, new_index: NamedTempFile) {
+    if let Err(e) = lock.replace(new_index) {
+        error!("replace {}: {}", lock.index().display(), e);
+        std::process::exit(1);
+    }
+}
+
 fn open_index_or_fail() -> IndexReader {
     let index_path = libcsearch::csearch_index();
     match IndexReader::open(&index_path) {
@@ -21,10 +21,11 @@ extern crate libcustomlogger;
 extern crate libcsearch;
 extern crate libvarint;
 
+use libcsearch::pathbytes;
 use libcsearch::reader::IndexReader;
 use libcsearch::regexp::{RegexInfo, Query};
+use libcsearch::source::{ContentSource, FileSource};
 
-use std::fs::File;
 use std::io::{Read, Write};
 use std::collections::BTreeSet;
 use std::env;
@@ -32,7 +33,6 @@ use std::path::{Path, PathBuf};
 
 use grep::{GrepBuilder, Grep};
 use regex::bytes;
-use regex::Regex;
 use termcolor::{Color, ColorChoice, ColorSpec, Stdout, WriteColor};
 
 
@@ -77,6 +77,11 @@ overwrites it.  Run cindex --help for more.
 
 Csearch uses the index stored in $CSEARCHINDEX or, if that variable is unset or
 empty, $HOME/.csearchindex.
+
+An index built on another machine, or before its files were moved, can be
+used with --root-map OLD=NEW, which searches files under NEW wherever the
+index has files under OLD.  For an index built with cindex --relative-to,
+OLD is the root it was built with.
 ";
 
 
@@ -164,6 +169,12 @@ pub fn main() {
             .long("indexpath")
             .takes_value(true)
             .help("use specified INDEX_FILE as the index path. overrides $CSEARCHINDEX."))
+        .arg(clap::Arg::with_name("OLD=NEW")
+            .long("root-map")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("search files under NEW where the index has files under OLD"))
         .get_matches();
 
     // possibly add ignore case flag to the pattern
@@ -202,14 +213,28 @@ pub fn main() {
 
     // Get the index from file
     let index_path = libcsearch::csearch_index();
-    let index_reader = match IndexReader::open(index_path) {
+    let mut index_reader = match IndexReader::open(&index_path) {
         Ok(i) => i,
-        Err(e) => panic!("{}", e),
+        Err(e) => {
+            error!("open {}: {}", index_path, e);
+            std::process::exit(2);
+        }
     };
+    if let Some(maps) = matches.values_of("OLD=NEW") {
+        for m in maps {
+            match m.find('=') {
+                Some(i) => index_reader.map_root(&m[..i], &m[i + 1..]),
+                None => {
+                    error!("--root-map {}: expected OLD=NEW", m);
+                    std::process::exit(2);
+                }
+            }
+        }
+    }
 
     // Find all possibly matching files using the pseudo-regexp
-    let mut post: BTreeSet<u32> = if matches.is_present("bruteforce") {
-        index_reader.query(Query::all()).into_inner()
+    let post = if matches.is_present("bruteforce") {
+        index_reader.query(Query::all())
     } else {
         // Get the pseudo-regexp (built using trigrams)
         let expr = regex_syntax::ExprBuilder::new()
@@ -220,19 +245,26 @@ pub fn main() {
         let q = RegexInfo::new(expr).unwrap().query;
         // panic!("query = {} --- {:?}", q.format_as_string(), q);
 
-        index_reader.query(q).into_inner()
+        index_reader.query(q)
+    };
+    let mut post: BTreeSet<u32> = match post {
+        Ok(p) => p.into_inner(),
+        Err(e) => {
+            error!("{}: {}", index_path, e);
+            std::process::exit(2);
+        }
     };
     // println!("identified {} possible queries", post.len());
 
     // If provided, filter possibly matching files via FILE_PATTERN
     if let Some(ref file_pattern_str) = matches.value_of("FILE_PATTERN") {
-        let file_pattern = match Regex::new(&file_pattern_str) {
+        let file_pattern = match bytes::Regex::new(&file_pattern_str) {
             Ok(r) => r,
             Err(e) => panic!("FILE_PATTERN: {}", e),
         };
         post = post.into_iter()
             .filter(|file_id| {
-                let name = index_reader.name(*file_id);
+                let name = index_reader.name_bytes(*file_id);
                 file_pattern.is_match(&name)
             })
             .collect::<BTreeSet<_>>();
@@ -266,19 +298,22 @@ pub fn main() {
         Stdout::new(ColorChoice::Never)
     };
     let mut tmp = Vec::new();
+    let source = FileSource;
     for file_id in post {
         // println!("next file");
         buffer.resize(normal_alloc_size, 0);
-        let name = index_reader.name(file_id);
+        let name_bytes = index_reader.name_bytes(file_id);
         // writeln!(io::stderr(), "searching {}", name).unwrap();
-        let mut reader = match File::open(&name) {
+        let mut reader = match source.open(&name_bytes) {
             Ok(r) => r,
             Err(cause) => {
-                warn!("{} - File open failure: {}", name, cause);
+                warn!("{} - File open failure: {}",
+                      String::from_utf8_lossy(&name_bytes),
+                      cause);
                 continue;
             }
         };
-        let name = path_simplifier.maybe_make_relative(name);
+        let name = path_simplifier.maybe_make_relative(pathbytes::from_bytes(name_bytes));
         let mut trailing_bytes = 0;
         let mut line_count = 0;
         let mut num_matches = 0;
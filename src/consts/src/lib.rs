@@ -1,2 +1,29 @@
+/// Header shared by every index version, followed by the version number
+pub const MAGIC_PREFIX: &'static str = "csearch index ";
 pub const MAGIC: &'static str = "csearch index 1\n";
+pub const MAGIC_V2: &'static str = "csearch index 2\n";
 pub const TRAILER_MAGIC: &'static str = "\ncsearch trailr\n";
+
+/// Section id of the per-file metadata section
+pub const SECTION_FILE_META: u32 = 1;
+/// Section id of the checksum section
+pub const SECTION_CHECKSUMS: u32 = 2;
+/// Section id of the root directory that names are stored relative to
+pub const SECTION_ROOT: u32 = 3;
+/// Section id of the options the index was built with
+pub const SECTION_OPTIONS: u32 = 4;
+/// Section id of the list of files sharing another file's posting entries
+pub const SECTION_DUPLICATES: u32 = 5;
+
+/// Ids of the required sections, used to refer to them in the checksum section
+pub const SECTION_PATHS: u32 = 0x100;
+pub const SECTION_NAMES: u32 = 0x101;
+pub const SECTION_POSTING_LISTS: u32 = 0x102;
+pub const SECTION_NAME_INDEX: u32 = 0x103;
+pub const SECTION_POSTING_INDEX: u32 = 0x104;
+
+/// Number of file IDs in each block of a version 2 posting list
+pub const POST_BLOCK_SIZE: usize = 128;
+
+/// Every this many names, a front-coded name list restarts with a full name
+pub const NAME_RESTART_INTERVAL: usize = 16;
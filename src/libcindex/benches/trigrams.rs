@@ -0,0 +1,164 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Compares scanning a file's trigrams from chunks with `TrigramScanner`,
+// along with the built-in content filters, to the byte-at-a-time reader it
+// replaced, which did the same checks itself.
+//
+// Run with `cargo bench -p libcindex`.
+
+#[macro_use]
+extern crate bencher;
+extern crate libcindex;
+
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use bencher::{black_box, Bencher};
+use libcindex::writer::{FileFilter, MaxInvalidUtf8, MaxLineLen, SkipBinary, TrigramScanner};
+
+const READ_CHUNK: usize = 64 << 10;
+
+/// About 4MB of source code-like text, mostly ASCII
+fn text() -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut i = 0u32;
+    while data.len() < 4 << 20 {
+        let line = if i % 32 == 0 {
+            format!("    // caf\u{e9} \u{2192} na\u{ef}ve r\u{e9}sum\u{e9} {}\n", i)
+        } else {
+            format!("    let value_{} = compute(\"name {}\", {:x});\n",
+                    i % 97,
+                    i,
+                    i.wrapping_mul(2654435761))
+        };
+        data.extend_from_slice(line.as_bytes());
+        i += 1;
+    }
+    data
+}
+
+fn scanner(b: &mut Bencher) {
+    let data = text();
+    let size = data.len() as u64;
+    let path = Path::new("bench");
+    b.bytes = size;
+    b.iter(|| {
+        let filters: [&FileFilter; 3] = [&SkipBinary, &MaxInvalidUtf8(0.1), &MaxLineLen(2000)];
+        let mut content = filters.iter()
+            .filter_map(|f| f.content_filter(path, size))
+            .collect::<Vec<_>>();
+        let mut scanner = TrigramScanner::new();
+        let mut sum = 0u64;
+        for chunk in data.chunks(READ_CHUNK) {
+            for c in &mut content {
+                c.check(chunk).unwrap();
+            }
+            scanner.scan(chunk, |t| sum = sum.wrapping_add(t as u64));
+        }
+        for c in &mut content {
+            c.finish().unwrap();
+        }
+        black_box(sum)
+    });
+}
+
+fn byte_reader(b: &mut Bencher) {
+    let data = text();
+    b.bytes = data.len() as u64;
+    b.iter(|| {
+        let max_invalid = ((data.len() as f64) * 0.1) as u64;
+        let mut reader = ByteReader::new(&data[..], max_invalid, 2000);
+        let sum = (&mut reader).fold(0u64, |sum, t| sum.wrapping_add(t as u64));
+        assert!(reader.error.is_none());
+        black_box(sum)
+    });
+}
+
+benchmark_group!(benches, scanner, byte_reader);
+benchmark_main!(benches);
+
+/// The trigram reader `TrigramScanner` replaced, trimmed of its error
+/// messages
+struct ByteReader<R: Read> {
+    reader: io::Bytes<BufReader<R>>,
+    current_value: u32,
+    num_read: usize,
+    inv_cnt: u64,
+    max_invalid: u64,
+    line_len: u64,
+    max_line_len: u64,
+    error: Option<&'static str>,
+}
+
+impl<R: Read> ByteReader<R> {
+    fn new(r: R, max_invalid: u64, max_line_len: u64) -> ByteReader<R> {
+        ByteReader {
+            reader: BufReader::with_capacity(16384, r).bytes(),
+            current_value: 0,
+            num_read: 0,
+            inv_cnt: 0,
+            max_invalid: max_invalid,
+            line_len: 0,
+            max_line_len: max_line_len,
+            error: None,
+        }
+    }
+}
+
+impl<R: Read> Iterator for ByteReader<R> {
+    type Item = u32;
+    fn next(&mut self) -> Option<u32> {
+        let c = match self.reader.next() {
+            Some(Ok(c)) => c,
+            Some(Err(_)) => {
+                self.error = Some("read error");
+                return None;
+            }
+            None => return None,
+        };
+        self.num_read += 1;
+        self.current_value = ((1 << 24) - 1) & ((self.current_value << 8) | (c as u32));
+        if self.num_read < 3 {
+            return self.next();
+        }
+        let b1 = ((self.current_value >> 8) & 0xff) as u8;
+        let b2 = (self.current_value & 0xff) as u8;
+        if b1 == 0x00 || b2 == 0x00 {
+            self.error = Some("binary");
+            None
+        } else if !valid_utf8(b1, b2) {
+            self.inv_cnt += 1;
+            if self.inv_cnt > self.max_invalid {
+                self.error = Some("invalid UTF-8");
+                None
+            } else {
+                self.next()
+            }
+        } else if self.line_len > self.max_line_len {
+            self.error = Some("line too long");
+            None
+        } else {
+            if c == b'\n' {
+                self.line_len = 0;
+            } else {
+                self.line_len += 1;
+            }
+            Some(self.current_value)
+        }
+    }
+}
+
+fn valid_utf8(c1: u8, c2: u8) -> bool {
+    if c1 < 0x80 {
+        (c2 < 0x80) || (0xc0 <= c2) && (c2 < 0xf8)
+    } else if c1 < 0xc0 {
+        (c2 < 0xf8)
+    } else if c1 < 0xf8 {
+        (0x80 <= c2) && (c2 < 0xc0)
+    } else {
+        false
+    }
+}
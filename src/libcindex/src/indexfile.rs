@@ -0,0 +1,334 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+//! Replacing an index file while other programs use it.
+//!
+//! Searchers can open the index at any time, and cindex can be run again
+//! (say, from cron) while an earlier run is still going.  So an index is
+//! never written in place: a new version goes into a temporary file next
+//! to it, which is synced to disk and renamed over the index.  Renaming
+//! is atomic, so the index is always either the old version or the new
+//! one, even if cindex crashes, and searchers that opened the old version
+//! keep reading it.  The new version gets the permissions of the old one,
+//! so other users can still search a shared index.
+//!
+//! Runs that update the index take turns, by holding an advisory lock on
+//! `<index>.lock` while they run.  The lock is released when the process
+//! exits, even if it crashes, so the temporary files of an earlier run
+//! that are still around once a run has the lock are left over from a
+//! crash, and are removed.  Locking uses `flock`, so only Unix has it;
+//! elsewhere runs aren't kept from overlapping, and shouldn't be started
+//! while another one might be going.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tempfile::{Builder, NamedTempFile};
+
+/// The lock on an index file, released when it's dropped
+///
+/// ```no_run
+/// # use std::io::Write;
+/// # use libcindex::indexfile::IndexLock;
+/// let lock = IndexLock::acquire("index").unwrap();
+/// let mut tmp = lock.temp_file().unwrap();
+/// tmp.write_all(b"...").unwrap();
+/// lock.replace(tmp).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct IndexLock {
+    index: PathBuf,
+    // the index's directory with symlinks resolved, for `is_index_file`
+    dir: PathBuf,
+    _file: File,
+}
+
+impl IndexLock {
+    /// Takes the lock on the index at `index`, which doesn't have to
+    /// exist, waiting for another process to release it if need be
+    ///
+    /// Only Unix has locking.  Elsewhere this logs a warning and returns
+    /// right away without excluding anyone, so overlapping runs can write
+    /// over each other's changes (but the index is still replaced
+    /// atomically) and can remove each other's temporary files.
+    pub fn acquire<P: AsRef<Path>>(index: P) -> io::Result<IndexLock> {
+        let index = index.as_ref().to_path_buf();
+        let mut lock_path = index.clone().into_os_string();
+        lock_path.push(".lock");
+        let f = try!(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path));
+        if !try!(imp::try_lock(&f)) {
+            info!("waiting for another cindex to finish with {}", index.display());
+            try!(imp::lock(&f));
+        }
+        let dir = try!(fs::canonicalize(parent_dir(&index)));
+        Ok(IndexLock {
+            index: index,
+            dir: dir,
+            _file: f,
+        })
+    }
+
+    /// Returns the path of the locked index
+    pub fn index(&self) -> &Path {
+        &self.index
+    }
+
+    /// Returns true if `path` is the index, its lock file or one of its
+    /// temporary files, under whatever name the directory is reached by
+    pub fn is_index_file(&self, path: &Path) -> bool {
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => return false,
+        };
+        let index_name = match self.index.file_name() {
+            Some(name) => name,
+            None => return false,
+        };
+        let mut lock_name = index_name.to_os_string();
+        lock_name.push(".lock");
+        let prefix = temp_prefix(&self.index);
+        if name != index_name && name != lock_name &&
+           !name.to_string_lossy().starts_with(&*prefix.to_string_lossy()) {
+            return false;
+        }
+        match fs::canonicalize(parent_dir(path)) {
+            Ok(dir) => dir == self.dir,
+            Err(_) => false,
+        }
+    }
+
+    /// Creates a temporary file with a unique name next to the index, for
+    /// writing a new version of it.  It's deleted when it's dropped unless
+    /// it replaces the index.
+    pub fn temp_file(&self) -> io::Result<NamedTempFile> {
+        let prefix = temp_prefix(&self.index);
+        Builder::new()
+            .prefix(&prefix)
+            .tempfile_in(parent_dir(&self.index))
+    }
+
+    /// Syncs `tmp` to disk and renames it over the index, giving it the
+    /// index's permissions.  Temporary files are only readable by their
+    /// owner, so if there's no index yet it gets the usual permissions of
+    /// a new file instead.
+    pub fn replace(&self, tmp: NamedTempFile) -> io::Result<()> {
+        let permissions = match fs::metadata(&self.index) {
+            Ok(meta) => Some(meta.permissions()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => imp::new_file_permissions(),
+            Err(e) => return Err(e),
+        };
+        if let Some(permissions) = permissions {
+            try!(fs::set_permissions(tmp.path(), permissions));
+        }
+        try!(tmp.as_file().sync_all());
+        try!(tmp.persist(&self.index));
+        imp::sync_dir(parent_dir(&self.index))
+    }
+
+    /// Removes the temporary files left next to the index by runs that
+    /// didn't finish, and returns their names
+    pub fn remove_stale_files(&self) -> io::Result<Vec<PathBuf>> {
+        let prefix = temp_prefix(&self.index);
+        let prefix = prefix.to_string_lossy();
+        let mut removed = Vec::new();
+        for entry in try!(fs::read_dir(parent_dir(&self.index))) {
+            let entry = try!(entry);
+            if entry.file_name().to_string_lossy().starts_with(&*prefix) {
+                try!(fs::remove_file(entry.path()));
+                removed.push(entry.path());
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Returns the start of the names of the temporary files for `index`,
+/// which also matches the fixed names older versions used
+fn temp_prefix(index: &Path) -> OsString {
+    let mut prefix = index.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    prefix.push("~");
+    prefix
+}
+
+fn parent_dir(index: &Path) -> &Path {
+    match index.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::fs::{File, Permissions};
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    use libc;
+
+    /// Returns the process's umask, which can only be read by setting it
+    pub fn umask() -> u32 {
+        unsafe {
+            let mask = libc::umask(0o022);
+            libc::umask(mask);
+            mask as u32
+        }
+    }
+
+    /// Returns the permissions `File::create` gives a new file
+    pub fn new_file_permissions() -> Option<Permissions> {
+        Some(Permissions::from_mode(0o644 & !umask()))
+    }
+
+    pub fn try_lock(f: &File) -> io::Result<bool> {
+        if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            Ok(false)
+        } else {
+            Err(err)
+        }
+    }
+
+    pub fn lock(f: &File) -> io::Result<()> {
+        loop {
+            if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX) } == 0 {
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Makes a rename in `dir` durable
+    pub fn sync_dir(dir: &Path) -> io::Result<()> {
+        try!(File::open(dir)).sync_all()
+    }
+}
+
+// Without flock, runs aren't kept from overlapping, but the index is still
+// replaced atomically.  See `IndexLock::acquire`.
+#[cfg(not(unix))]
+mod imp {
+    use std::fs::{File, Permissions};
+    use std::io;
+    use std::path::Path;
+
+    /// Temporary files get the same permissions as other new files
+    pub fn new_file_permissions() -> Option<Permissions> {
+        None
+    }
+
+    pub fn try_lock(_f: &File) -> io::Result<bool> {
+        warn!("index locking isn't supported on this system; don't run cindex twice at once");
+        Ok(true)
+    }
+
+    pub fn lock(_f: &File) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn sync_dir(_dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_replace() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dir = tmp_dir.path();
+        let index = dir.join("index");
+        File::create(&index).unwrap().write_all(b"old").unwrap();
+        File::create(dir.join("index~")).unwrap();
+        File::create(dir.join("index.other")).unwrap();
+
+        let lock = IndexLock::acquire(&index).unwrap();
+        assert_eq!(lock.remove_stale_files().unwrap(), vec![dir.join("index~")]);
+        let mut tmp = lock.temp_file().unwrap();
+        let abandoned = lock.temp_file().unwrap();
+        assert!(tmp.path() != abandoned.path());
+        tmp.write_all(b"new").unwrap();
+        lock.replace(tmp).unwrap();
+        drop(abandoned);
+
+        let mut contents = String::new();
+        File::open(&index).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "new");
+        let mut names = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["index", "index.lock", "index.other"]);
+    }
+
+    #[test]
+    fn test_is_index_file() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dir = tmp_dir.path();
+        fs::create_dir(dir.join("sub")).unwrap();
+        let lock = IndexLock::acquire(dir.join("sub").join("index")).unwrap();
+        let tmp = lock.temp_file().unwrap();
+
+        let other_way = dir.join("sub").join("..").join("sub");
+        assert!(lock.is_index_file(&other_way.join("index")));
+        assert!(lock.is_index_file(&other_way.join("index.lock")));
+        assert!(lock.is_index_file(&other_way.join(tmp.path().file_name().unwrap())));
+        assert!(!lock.is_index_file(&other_way.join("index.other")));
+        assert!(!lock.is_index_file(&dir.join("index")));
+        assert!(!lock.is_index_file(&dir.join("index.lock")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lock_excludes() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dir = tmp_dir.path();
+        let index = dir.join("index");
+        let lock = IndexLock::acquire(&index).unwrap();
+        // flock locks belong to the open file, so a second open conflicts
+        let other = File::open(dir.join("index.lock")).unwrap();
+        assert!(!imp::try_lock(&other).unwrap());
+        drop(lock);
+        assert!(imp::try_lock(&other).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_replace_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = TempDir::new().unwrap();
+        let index = tmp_dir.path().join("index");
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        let lock = IndexLock::acquire(&index).unwrap();
+
+        lock.replace(lock.temp_file().unwrap()).unwrap();
+        assert_eq!(mode(&index), 0o644 & !imp::umask());
+
+        fs::set_permissions(&index, Permissions::from_mode(0o640)).unwrap();
+        lock.replace(lock.temp_file().unwrap()).unwrap();
+        assert_eq!(mode(&index), 0o640);
+    }
+}
@@ -1,8 +1,13 @@
 extern crate byteorder;
+extern crate ignore;
+extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate memchr;
 extern crate memmap;
+extern crate sha2;
 extern crate tempfile;
+extern crate twox_hash;
 
 
 extern crate consts;
@@ -12,3 +17,5 @@ extern crate libvarint;
 
 pub mod writer;
 pub mod merge;
+pub mod indexfile;
+pub mod walk;
@@ -28,20 +28,40 @@
 // During the merge, translate the docid numbers to the new C docid space.
 // Also during the merge, write the posting list index to a temporary file as usual.
 //
-// Copy the name index and posting list index into C's index and write the trailer.
+// Copy the file metadata, name index and posting list index into C's index
+// and write the trailer.  File metadata is carried along with each name; names
+// from an index without metadata get an empty record.  Every section is
+// checksummed as it's written.  Both indexes must have the same root, which
+// is copied along unchanged.  The options section is copied from B, the
+// newer index, or from A if B doesn't have one.
+//
+// The duplicates sections of A and B are translated to C's docids too.  A
+// file whose first copy is discarded from A while one of its duplicates
+// isn't has no posting entries left, so the first surviving duplicate
+// takes over the discarded docid's posting entries and becomes the first
+// copy of the rest.  Files in A aren't compared with files in B.
+//
+// C is a version 1 index, without any optional sections, if A and B both
+// are, so indexes shared with the Go implementation stay readable by it.
 // Rename C's index onto the new index.
+//
+// Removing paths from an index A works the same way, with only one index:
+// the names inside the removed paths are discarded along with the paths,
+// and the rest of A's docids are mapped to C's in ranges as above.
 
-use libcsearch::reader::IndexReader;
-use writer::{get_offset, copy_file};
+use libcsearch::pathbytes;
+use libcsearch::reader::{FileMeta, IndexReader, IndexVersion};
+use writer::{HashWriter, NameWriter, Section, get_offset, copy_file, take_checksum,
+             write_checksums, write_duplicates, write_section_table, write_trailer};
 use libprofiling;
 
 use tempfile::tempfile;
-use byteorder::{BigEndian, WriteBytesExt};
 use consts;
 
 use super::postmapreader::{IdRange, PostMapReader};
 use super::postdatawriter::PostDataWriter;
 
+use std::collections::HashMap;
 use std::io::{self, Write, Seek, SeekFrom, BufReader, BufWriter};
 use std::u32;
 use std::fs::File;
@@ -58,8 +78,25 @@ pub fn merge<P1, P2, P3>(dest: P1, src1: P2, src2: P3) -> io::Result<()>
     let _frame_merge = libprofiling::profile("merge");
     let ix1 = try!(IndexReader::open(src1));
     let ix2 = try!(IndexReader::open(src2));
-    let paths1 = ix1.indexed_paths();
-    let paths2 = ix2.indexed_paths();
+    // Names are merged as stored, so both indexes have to be relative to
+    // the same root (or neither relative to any).
+    let root = ix1.section(consts::SECTION_ROOT).map(|r| r.to_vec());
+    if root.as_ref().map(|r| &r[..]) != ix2.section(consts::SECTION_ROOT) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("merge: indexes have different roots ({:?} and {:?})",
+                                          ix1.root(),
+                                          ix2.root())));
+    }
+    let options = ix2.section(consts::SECTION_OPTIONS)
+        .or_else(|| ix1.section(consts::SECTION_OPTIONS))
+        .map(|o| o.to_vec());
+    let version = if ix1.version() == IndexVersion::V1 && ix2.version() == IndexVersion::V1 {
+        IndexVersion::V1
+    } else {
+        IndexVersion::V2
+    };
+    let paths1 = ix1.stored_paths();
+    let paths2 = ix2.stored_paths();
 
     let mut i1: u32 = 0;
     let mut i2: u32 = 0;
@@ -69,17 +106,11 @@ pub fn merge<P1, P2, P3>(dest: P1, src1: P2, src2: P3) -> io::Result<()>
     for path in &paths2 {
         let _frame = libprofiling::profile("merge: merge indexed paths");
         let old = i1;
-        while (i1 as usize) < ix1.num_name && ix1.name(i1 as u32) < *path {
+        while (i1 as usize) < ix1.num_name && ix1.stored_name(i1 as u32) < *path {
             i1 += 1;
         }
         let mut lo = i1;
-        let limit = {
-            let (l1, l2) = path.split_at(path.len() - 1);
-            assert!(l2.len() == 1);
-            let l2_u = l2.chars().next().unwrap() as u8;
-            l1.to_string() + &((l2_u + 1) as char).to_string()
-        };
-        while (i1 as usize) < ix1.num_name && ix1.name(i1 as u32) < limit {
+        while (i1 as usize) < ix1.num_name && ix1.stored_name(i1 as u32).starts_with(path) {
             i1 += 1;
         }
 
@@ -96,11 +127,13 @@ pub fn merge<P1, P2, P3>(dest: P1, src1: P2, src2: P3) -> io::Result<()>
         // Determine range defined by this path.
         // Because we are iterating over the ix2 paths,
         // there can't be gaps, so it must start at i2.
-        if (i2 as usize) < ix2.num_name && ix2.name(i2) < *path {
-            panic!("merge: inconsistent index ({} < {})", ix2.name(i2), *path);
+        if (i2 as usize) < ix2.num_name && ix2.stored_name(i2) < *path {
+            panic!("merge: inconsistent index ({:?} < {:?})",
+                   pathbytes::from_bytes(ix2.stored_name(i2)),
+                   pathbytes::from_bytes(path.clone()));
         }
         lo = i2;
-        while (i2 as usize) < ix2.num_name && ix2.name(i2) < limit {
+        while (i2 as usize) < ix2.num_name && ix2.stored_name(i2).starts_with(path) {
             i2 += 1;
         }
         let hi = i2;
@@ -120,20 +153,15 @@ pub fn merge<P1, P2, P3>(dest: P1, src1: P2, src2: P3) -> io::Result<()>
             high: ix1.num_name as u32,
             new: new,
         });
-        new += (ix1.num_name as u32) - i1;
     }
     if (i2 as usize) < ix2.num_name {
         panic!("merge: inconsistent index ({} < {})", i2, ix2.num_name);
     }
-    let num_name = new;
-    let mut ix3 = BufWriter::new(try!(File::create(dest)));
-    try!(ix3.write(consts::MAGIC.as_bytes()));
 
-    let path_data = try!(get_offset(&mut ix3));
+    let mut paths = Vec::new();
     let mut mi1 = 0;
     let mut mi2 = 0;
-    let mut last = "\0".to_string(); // not a prefix of anything
-
+    let mut last = b"\0".to_vec(); // not a prefix of anything
     while mi1 < paths1.len() || mi2 < paths2.len() {
         let _frame = libprofiling::profile("merge: merge file_ids");
         let p = if mi2 >= paths2.len() || mi1 < paths1.len() && paths1[mi1] <= paths2[mi2] {
@@ -149,66 +177,239 @@ pub fn merge<P1, P2, P3>(dest: P1, src1: P2, src2: P3) -> io::Result<()>
             continue;
         }
         last = p.clone();
-        try!(ix3.write(&p.as_bytes()));
+        paths.push(p);
+    }
+
+    write_index(dest,
+                vec![(&ix1, map1), (&ix2, map2)],
+                &paths,
+                root,
+                options,
+                version)
+}
+
+/// Writes the index in `src` to `dest` without `paths`, i.e. without the
+/// paths inside them in its list of indexed paths and without the files
+/// inside them.  A path inside one of the indexed paths only removes the
+/// files inside it, so reindexing adds them back.
+///
+/// ```no_run
+/// # use libcindex::merge::remove;
+/// remove("index~", "index", &["/src/old-project"]).unwrap();
+/// ```
+pub fn remove<P1, P2, I>(dest: P1, src: P2, paths: I) -> io::Result<()>
+    where P1: AsRef<Path>,
+          P2: AsRef<Path>,
+          I: IntoIterator,
+          I::Item: AsRef<Path>
+{
+    let _frame_remove = libprofiling::profile("remove");
+    let ix = try!(IndexReader::open(src));
+    let root = ix.section(consts::SECTION_ROOT).map(|r| r.to_vec());
+    let mut removed = Vec::new();
+    for p in paths {
+        let name = pathbytes::to_bytes(p.as_ref().as_os_str()).into_owned();
+        removed.push(match root {
+            Some(ref root) => {
+                try!(pathbytes::relative_to(&name, root).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput,
+                                   format!("remove: {} is outside of the index root {:?}",
+                                           p.as_ref().display(),
+                                           ix.root()))
+                }))
+            }
+            None => name,
+        });
+    }
+    let is_removed = |name: &[u8]| removed.iter().any(|r| pathbytes::is_inside(name, r));
+
+    let paths = ix.stored_paths()
+        .into_iter()
+        .filter(|p| !is_removed(p))
+        .collect::<Vec<_>>();
+    // names inside an indexed path are together, but the paths aren't
+    // necessarily in order, so every name is checked
+    let mut map = Vec::new();
+    let mut new = 0;
+    let mut i = 0;
+    while (i as usize) < ix.num_name {
+        if is_removed(&ix.stored_name(i)) {
+            i += 1;
+            continue;
+        }
+        let lo = i;
+        while (i as usize) < ix.num_name && !is_removed(&ix.stored_name(i)) {
+            i += 1;
+        }
+        map.push(IdRange {
+            low: lo,
+            high: i,
+            new: new,
+        });
+        new += i - lo;
+    }
+
+    let options = ix.section(consts::SECTION_OPTIONS).map(|o| o.to_vec());
+    let version = ix.version();
+    write_index(dest, vec![(&ix, map)], &paths, root, options, version)
+}
+
+/// Writes an index to `dest` with the files of each of `sources` whose
+/// docids are in its map, as mapped, and `paths` as its indexed paths
+fn write_index<P: AsRef<Path>>(dest: P,
+                               sources: Vec<(&IndexReader, Vec<IdRange>)>,
+                               paths: &[Vec<u8>],
+                               root: Option<Vec<u8>>,
+                               options: Option<Vec<u8>>,
+                               version: IndexVersion)
+                               -> io::Result<()> {
+    let num_name = sources.iter()
+        .flat_map(|&(_, ref map)| map.iter())
+        .map(|r| r.high - r.low)
+        .sum::<u32>();
+    let mut ix3 = BufWriter::new(HashWriter::new(try!(File::create(dest))));
+    let magic = match version {
+        IndexVersion::V1 => consts::MAGIC,
+        IndexVersion::V2 => consts::MAGIC_V2,
+    };
+    try!(ix3.write(magic.as_bytes()));
+
+    let mut checksums = Vec::new();
+    let path_data = try!(get_offset(&mut ix3));
+    try!(take_checksum(&mut ix3));
+    for p in paths {
+        try!(ix3.write(p));
         try!(ix3.write("\0".as_bytes()));
     }
     try!(ix3.write("\0".as_bytes()));
+    checksums.push((consts::SECTION_PATHS, try!(take_checksum(&mut ix3))));
 
     // Merged list of names
     let name_data = try!(get_offset(&mut ix3));
     let mut name_index_file = BufWriter::new(try!(tempfile()));
+    let mut file_meta_file = BufWriter::new(try!(tempfile()));
+    let mut names = NameWriter::new(version);
+    let has_file_meta = sources.iter()
+        .any(|&(ix, _)| ix.section(consts::SECTION_FILE_META).is_some());
 
-    new = 0;
-    mi1 = 0;
-    mi2 = 0;
-
+    let mut new = 0;
+    // the next range of each source's map
+    let mut next = vec![0; sources.len()];
     while new < num_name {
         let _frame = libprofiling::profile("merge: Merge list of names");
-        if mi1 < map1.len() && map1[mi1].new == new {
-            for i in map1[mi1].low..map1[mi1].high {
-                let name = ix1.name(i);
-                let new_offset: u32 = try!(get_offset(&mut ix3)) as u32;
-                name_index_file.write_u32::<BigEndian>(new_offset - (name_data as u32)).unwrap();
-                try!(ix3.write(&name.as_bytes()));
-                try!(ix3.write("\0".as_bytes()));
-                new += 1;
-            }
-            mi1 += 1;
-        } else if mi2 < map2.len() && map2[mi2].new == new {
-            for i in map2[mi2].low..map2[mi2].high {
-                let name = ix2.name(i);
-                let new_offset: u32 = try!(get_offset(&mut ix3)) as u32;
-                name_index_file.write_u32::<BigEndian>(new_offset - (name_data as u32)).unwrap();
-                try!(ix3.write(&name.as_bytes()));
-                try!(ix3.write("\0".as_bytes()));
-                new += 1;
+        let k = match (0..sources.len())
+            .find(|&k| next[k] < sources[k].1.len() && sources[k].1[next[k]].new == new) {
+            Some(k) => k,
+            None => panic!("merge: inconsistent index"),
+        };
+        let (ix, ref map) = sources[k];
+        for i in map[next[k]].low..map[next[k]].high {
+            let name = ix.stored_name(i);
+            let new_offset = try!(get_offset(&mut ix3));
+            try!(names.write_index_entry(&mut name_index_file, new_offset - name_data));
+            try!(names.write_name(&mut ix3, &name));
+            if has_file_meta {
+                let meta = ix.file_meta(i).unwrap_or(FileMeta::default());
+                try!(meta.write_to(&mut file_meta_file));
             }
-            mi2 += 1;
-        } else {
-            panic!("merge: inconsistent index");
+            new += 1;
         }
+        next[k] += 1;
     }
-    if ((new * 4) as u64) != try!(get_offset(&mut name_index_file)) {
+    if ((new as usize) * version.offset_size()) as u64 != try!(get_offset(&mut name_index_file)) {
         panic!("merge: inconsistent index");
     }
-    name_index_file.write_u32::<BigEndian>(try!(get_offset(&mut ix3)) as u32).unwrap();
+    let end_offset = try!(get_offset(&mut ix3)) - name_data;
+    try!(names.write_index_entry(&mut name_index_file, end_offset));
+    try!(names.write_name(&mut ix3, b""));
+    checksums.push((consts::SECTION_NAMES, try!(take_checksum(&mut ix3))));
 
     let post_data = try!(get_offset(&mut ix3));
 
-    let post_index_file = try!(merge_list_of_posting_lists(PostMapReader::new(&ix1, map1),
-                                                           PostMapReader::new(&ix2, map2),
-                                                           &mut ix3));
+    let mut duplicates = Vec::new();
+    let mut readers = Vec::new();
+    for (ix, map) in sources {
+        let (dups, promoted) = map_duplicates(ix, &map);
+        duplicates.extend(dups);
+        readers.push(try!(PostMapReader::new(ix, map, promoted)));
+    }
+    duplicates.sort();
+    let post_index_file = try!(merge_list_of_posting_lists(readers, &mut ix3, version));
+    checksums.push((consts::SECTION_POSTING_LISTS, try!(take_checksum(&mut ix3))));
+
+    // Optional sections
+    let mut sections = Vec::new();
+    if has_file_meta && version == IndexVersion::V2 {
+        let meta_offset = try!(get_offset(&mut ix3));
+        copy_file(&mut ix3,
+                  &mut BufReader::new(file_meta_file.into_inner().unwrap()));
+        sections.push(Section {
+            id: consts::SECTION_FILE_META,
+            offset: meta_offset,
+            len: try!(get_offset(&mut ix3)) - meta_offset,
+        });
+        checksums.push((consts::SECTION_FILE_META, try!(take_checksum(&mut ix3))));
+    }
+    if let Some(root) = root {
+        let root_offset = try!(get_offset(&mut ix3));
+        try!(ix3.write_all(&root));
+        sections.push(Section {
+            id: consts::SECTION_ROOT,
+            offset: root_offset,
+            len: root.len() as u64,
+        });
+        checksums.push((consts::SECTION_ROOT, try!(take_checksum(&mut ix3))));
+    }
+    if let Some(options) = options {
+        let options_offset = try!(get_offset(&mut ix3));
+        try!(ix3.write_all(&options));
+        sections.push(Section {
+            id: consts::SECTION_OPTIONS,
+            offset: options_offset,
+            len: options.len() as u64,
+        });
+        checksums.push((consts::SECTION_OPTIONS, try!(take_checksum(&mut ix3))));
+    }
+    if !duplicates.is_empty() && version == IndexVersion::V2 {
+        let dup_offset = try!(get_offset(&mut ix3));
+        try!(write_duplicates(&mut ix3, &duplicates));
+        sections.push(Section {
+            id: consts::SECTION_DUPLICATES,
+            offset: dup_offset,
+            len: try!(get_offset(&mut ix3)) - dup_offset,
+        });
+        checksums.push((consts::SECTION_DUPLICATES, try!(take_checksum(&mut ix3))));
+    }
 
     // Name index
     let name_index = try!(get_offset(&mut ix3));
     name_index_file.seek(SeekFrom::Start(0)).unwrap();
     copy_file(&mut ix3,
               &mut BufReader::new(name_index_file.into_inner().unwrap()));
+    checksums.push((consts::SECTION_NAME_INDEX, try!(take_checksum(&mut ix3))));
 
     // Posting list index
     let post_index = get_offset(&mut ix3).unwrap();
     copy_file(&mut ix3,
               &mut BufReader::new(post_index_file.into_inner().unwrap()));
+    checksums.push((consts::SECTION_POSTING_INDEX, try!(take_checksum(&mut ix3))));
+
+    // Checksums
+    let mut section_table = 0;
+    if version == IndexVersion::V2 {
+        let checksum_offset = try!(get_offset(&mut ix3));
+        try!(write_checksums(&mut ix3, &checksums));
+        sections.push(Section {
+            id: consts::SECTION_CHECKSUMS,
+            offset: checksum_offset,
+            len: try!(get_offset(&mut ix3)) - checksum_offset,
+        });
+
+        // Section table
+        section_table = get_offset(&mut ix3).unwrap();
+        trace!("section_table = {}", section_table);
+        try!(write_section_table(&mut ix3, &sections));
+    }
 
     trace!("path_data  = {}", path_data);
     trace!("name_data  = {}", name_data);
@@ -216,61 +417,72 @@ pub fn merge<P1, P2, P3>(dest: P1, src1: P2, src2: P3) -> io::Result<()>
     trace!("name_index = {}", name_index);
     trace!("post_index = {}", post_index);
 
-
-    ix3.write_u32::<BigEndian>(path_data as u32).unwrap();
-    ix3.write_u32::<BigEndian>(name_data as u32).unwrap();
-    ix3.write_u32::<BigEndian>(post_data as u32).unwrap();
-    ix3.write_u32::<BigEndian>(name_index as u32).unwrap();
-    ix3.write_u32::<BigEndian>(post_index as u32).unwrap();
+    try!(write_trailer(&mut ix3,
+                       version,
+                       &[path_data, name_data, post_data, name_index, post_index, section_table]));
     try!(ix3.write(consts::TRAILER_MAGIC.as_bytes()));
     Ok(())
 }
 
-fn merge_list_of_posting_lists(mut r1: PostMapReader,
-                               mut r2: PostMapReader,
-                               ix3: &mut BufWriter<File>)
+/// Returns the new docid of `id`, or None if it's discarded
+fn map_id(map: &[IdRange], id: u32) -> Option<u32> {
+    map.iter()
+        .find(|r| r.low <= id && id < r.high)
+        .map(|r| r.new + id - r.low)
+}
+
+/// Translates the duplicates section of `ix` to the merged docids.  Also
+/// returns the discarded docids whose posting entries a duplicate takes
+/// over, mapped to the new docid of that duplicate.
+fn map_duplicates(ix: &IndexReader, map: &[IdRange]) -> (Vec<(u32, u32)>, HashMap<u32, u32>) {
+    let pairs = ix.all_duplicates();
+    let mut duplicates = Vec::new();
+    let mut promoted = HashMap::new();
+    let mut i = 0;
+    while i < pairs.len() {
+        let first = pairs[i].0;
+        let n = pairs[i..].iter().take_while(|p| p.0 == first).count();
+        let mut copies = pairs[i..i + n]
+            .iter()
+            .filter_map(|&(_, dup)| map_id(map, dup))
+            .collect::<Vec<_>>();
+        i += n;
+        let new_first = match map_id(map, first) {
+            Some(id) => id,
+            None if !copies.is_empty() => {
+                let id = copies.remove(0);
+                promoted.insert(first, id);
+                id
+            }
+            None => continue,
+        };
+        duplicates.extend(copies.into_iter().map(|dup| (new_first, dup)));
+    }
+    (duplicates, promoted)
+}
+
+fn merge_list_of_posting_lists(mut readers: Vec<PostMapReader>,
+                               ix3: &mut BufWriter<HashWriter<File>>,
+                               version: IndexVersion)
                                -> io::Result<BufWriter<File>> {
-    // Merged list of posting lists.
-    let mut w = try!(PostDataWriter::new(ix3));
+    // Merged list of posting lists.  The sources' docids are mapped to
+    // disjoint ranges, and the writer sorts each list.
+    let mut w = try!(PostDataWriter::new(ix3, version));
 
     loop {
         let _frame = libprofiling::profile("merge: merge list of posting lists");
-        if r1.trigram < r2.trigram {
-            w.trigram(r1.trigram);
-            while r1.next_id() {
-                w.file_id(r1.file_id);
-            }
-            r1.next_trigram();
-            w.end_trigram();
-        } else if r2.trigram < r1.trigram {
-            w.trigram(r2.trigram);
-            while r2.next_id() {
-                w.file_id(r2.file_id);
-            }
-            r2.next_trigram();
-            w.end_trigram();
-        } else {
-            if r1.trigram == u32::MAX {
-                break;
-            }
-            w.trigram(r1.trigram);
-            r1.next_id();
-            r2.next_id();
-            while r1.file_id < u32::MAX || r2.file_id < u32::MAX {
-                if r1.file_id < r2.file_id {
-                    w.file_id(r1.file_id);
-                    r1.next_id();
-                } else if r2.file_id < r1.file_id {
-                    w.file_id(r2.file_id);
-                    r2.next_id();
-                } else {
-                    panic!("merge: inconsistent index");
-                }
+        let trigram = readers.iter().map(|r| r.trigram).min().unwrap_or(u32::MAX);
+        if trigram == u32::MAX {
+            break;
+        }
+        w.trigram(trigram);
+        for r in readers.iter_mut().filter(|r| r.trigram == trigram) {
+            while try!(r.next_id()) {
+                w.file_id(r.file_id);
             }
-            r1.next_trigram();
-            r2.next_trigram();
-            w.end_trigram();
+            try!(r.next_trigram());
         }
+        try!(w.end_trigram());
     }
 
     Ok(w.into_inner())
@@ -1,4 +1,4 @@
-pub use self::merge::merge;
+pub use self::merge::{merge, remove};
 
 mod merge;
 mod postmapreader;
@@ -6,59 +6,59 @@
 
 use std::io::{self, Write, Seek, BufWriter};
 use std::fs::File;
-use std::u32;
 
-use libvarint;
-use writer::{WriteTrigram, get_offset};
+use libcsearch::reader::IndexVersion;
+use writer::{get_offset, write_post_entry, write_post_list};
 
-use byteorder::{BigEndian, WriteBytesExt};
 use tempfile::tempfile;
 
 pub struct PostDataWriter<'a, W: 'a + Write + Seek> {
     out: &'a mut BufWriter<W>,
+    version: IndexVersion,
     post_index_file: BufWriter<File>,
-    base: u32,
+    base: u64,
     count: u32,
-    offset: u32,
-    last: u32,
+    offset: u64,
+    ids: Vec<u32>,
     t: u32,
 }
 
 impl<'a, W: Write + Seek> PostDataWriter<'a, W> {
-    pub fn new(out: &'a mut BufWriter<W>) -> io::Result<Self> {
-        let base = try!(get_offset(out)) as u32;
+    pub fn new(out: &'a mut BufWriter<W>, version: IndexVersion) -> io::Result<Self> {
+        let base = try!(get_offset(out));
         Ok(PostDataWriter {
             out: out,
+            version: version,
             post_index_file: BufWriter::with_capacity(256 << 10, try!(tempfile())),
             base: base,
             count: 0,
             offset: 0,
-            last: 0,
+            ids: Vec::new(),
             t: 0,
         })
     }
     pub fn trigram(&mut self, t: u32) {
-        self.offset = get_offset(self.out).unwrap() as u32;
+        self.offset = get_offset(self.out).unwrap();
         self.count = 0;
         self.t = t;
-        self.last = u32::MAX;
+        self.ids.clear();
     }
     pub fn file_id(&mut self, id: u32) {
-        if self.count == 0 {
-            self.out.write_trigram(self.t).unwrap();
-        }
-        libvarint::write_uvarint(self.out, id.wrapping_sub(self.last)).unwrap();
-        self.last = id;
+        self.ids.push(id);
         self.count += 1;
     }
-    pub fn end_trigram(&mut self) {
+    pub fn end_trigram(&mut self) -> io::Result<()> {
         if self.count == 0 {
-            return;
+            return Ok(());
         }
-        libvarint::write_uvarint(self.out, 0).unwrap();
-        self.post_index_file.write_trigram(self.t).unwrap();
-        self.post_index_file.write_u32::<BigEndian>(self.count).unwrap();
-        self.post_index_file.write_u32::<BigEndian>(self.offset - self.base).unwrap();
+        // promoted duplicates (see merge.rs) can arrive out of order
+        self.ids.sort();
+        try!(write_post_list(self.out, self.version, self.t, &self.ids));
+        write_post_entry(&mut self.post_index_file,
+                         self.version,
+                         self.t,
+                         self.count,
+                         self.offset - self.base)
     }
     pub fn into_inner(self) -> BufWriter<File> {
         self.post_index_file
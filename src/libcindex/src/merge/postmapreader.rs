@@ -4,11 +4,11 @@
 // Use of this source code is governed by a BSD-style
 // license that can be found in the LICENSE file.
 
-use libcsearch::reader::{IndexReader, POST_ENTRY_SIZE};
+use libcsearch::reader::{IndexReader, PostList, ReaderResult};
 
 use libprofiling;
-use libvarint;
 
+use std::collections::HashMap;
 use std::u32;
 
 #[derive(Debug)]
@@ -21,89 +21,88 @@ pub struct IdRange {
 pub struct PostMapReader<'a> {
     index: &'a IndexReader,
     pub id_map: Vec<IdRange>,
+    /// Deleted file IDs whose posting entries are taken over by one of
+    /// their duplicates, and the new ID of that duplicate
+    promoted: HashMap<u32, u32>,
     tri_num: u32,
     pub trigram: u32,
-    count: u32,
-    offset: u32,
-    d: &'a [u8],
-    old_id: u32,
+    list: Option<PostList<'a>>,
     pub file_id: u32,
     i: usize,
 }
 
 impl<'a> PostMapReader<'a> {
-    pub fn new(index: &'a IndexReader, id_map: Vec<IdRange>) -> PostMapReader<'a> {
+    /// Creates a reader mapping file IDs through `id_map`.  IDs in
+    /// `promoted` are mapped through it instead, so they come out of order.
+    pub fn new(index: &'a IndexReader,
+               id_map: Vec<IdRange>,
+               promoted: HashMap<u32, u32>)
+               -> ReaderResult<PostMapReader<'a>> {
         let _frame = libprofiling::profile("PostMapReader::new");
-        let s = unsafe { index.as_slice() };
         let mut p = PostMapReader {
             index: index,
             id_map: id_map,
+            promoted: promoted,
             tri_num: 0,
             trigram: u32::MAX,
-            count: 0,
-            offset: 0,
-            d: s,
-            old_id: u32::MAX,
+            list: None,
             file_id: 0,
             i: 0,
         };
-        p.load();
-        p
+        try!(p.load());
+        Ok(p)
     }
-    pub fn next_trigram(&mut self) {
+    pub fn next_trigram(&mut self) -> ReaderResult<()> {
         let _frame = libprofiling::profile("PostMapReader::next_trigram");
         self.tri_num += 1;
-        self.load();
+        self.load()
     }
-    fn load(&mut self) {
+    fn load(&mut self) -> ReaderResult<()> {
         if self.tri_num >= (self.index.num_post as u32) {
             self.trigram = u32::MAX;
-            self.count = 0;
+            self.list = None;
             self.file_id = u32::MAX;
-            return;
+            return Ok(());
         }
         let (trigram, count, offset) = self.index
-                                           .list_at((self.tri_num as usize) * POST_ENTRY_SIZE);
+                                           .list_at((self.tri_num as usize) *
+                                                    self.index.post_entry_size());
         self.trigram = trigram;
-        self.count = count;
-        self.offset = offset;
         if count == 0 {
+            self.list = None;
             self.file_id = u32::MAX;
-            return;
+            return Ok(());
         }
-        self.d = unsafe {
-            let s = self.index.as_slice();
-            let split_point = self.index.post_data + self.offset + 3;
-            let (_, right_side) = s.split_at(split_point as usize);
-            right_side
-        };
-        self.old_id = u32::MAX;
+        self.list = Some(try!(self.index.post_list_at(trigram, count as usize, offset)));
         self.i = 0;
+        Ok(())
     }
-    pub fn next_id(&mut self) -> bool {
-        while self.count > 0 {
-            self.count -= 1;
-            let (delta, n) = libvarint::read_uvarint(self.d).unwrap();
-            if n <= 0 || delta == 0 {
-                panic!("merge: inconsistent index at trigram {}",
-                       self.trigram);
+    pub fn next_id(&mut self) -> ReaderResult<bool> {
+        while let Some(old_id) = match self.list {
+            Some(ref mut l) => try!(l.next()),
+            None => None,
+        } {
+            if let Some(&new_id) = self.promoted.get(&old_id) {
+                self.file_id = new_id;
+                return Ok(true);
             }
-            self.d = self.d.split_at(n as usize).1;
-            self.old_id = self.old_id.wrapping_add(delta as u32);
-            while self.i < self.id_map.len() && self.id_map[self.i].high <= self.old_id {
+            while self.i < self.id_map.len() && self.id_map[self.i].high <= old_id {
                 self.i += 1;
             }
             if self.i >= self.id_map.len() {
-                self.count = 0;
-                break;
+                if self.promoted.is_empty() {
+                    self.list = None;
+                    break;
+                }
+                continue;
             }
-            if self.old_id < self.id_map[self.i].low {
+            if old_id < self.id_map[self.i].low {
                 continue;
             }
-            self.file_id = self.id_map[self.i].new + self.old_id - self.id_map[self.i].low;
-            return true;
+            self.file_id = self.id_map[self.i].new + old_id - self.id_map[self.i].low;
+            return Ok(true);
         }
         self.file_id = u32::MAX;
-        return false;
+        Ok(false)
     }
 }
@@ -0,0 +1,236 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+//! Deciding which files to index while walking a directory tree.
+//!
+//! Files listed in a directory's ignore files aren't indexed, with the
+//! same rules as `.gitignore`: patterns are relative to the directory the
+//! ignore file is in, a pattern containing a slash is anchored to it, a
+//! trailing slash only matches directories, and `!` re-includes a path an
+//! earlier pattern excluded.  Rules in deeper directories override the ones
+//! above them, and `.csearchignore` overrides `.ignore`, which overrides
+//! `.gitignore` in the same directory, so a project can index files git
+//! ignores (e.g. generated code) without touching its `.gitignore`.
+//!
+//! Ignored directories aren't walked, so as in git a file inside one can't
+//! be re-included.
+//!
+//! Only ignore files inside the tree being walked count, and, if that's in
+//! a git repository, the ones between it and the top of the repository.
+//! Ignore files further up, such as one in the user's home directory,
+//! belong to some other project.
+//!
+//! Links can make a file reachable under several paths.  `SeenFiles` tells
+//! them apart by device and inode numbers, so each file is indexed once.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+/// Names of the files read for ignore rules, lowest precedence first
+pub const IGNORE_FILES: [&'static str; 3] = [".gitignore", ".ignore", ".csearchignore"];
+
+/// The ignore rules of the directories seen so far, read from their ignore
+/// files the first time a path inside them is checked
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use libcindex::walk::IgnoreRules;
+/// let rules = IgnoreRules::new(Path::new("/src/project"));
+/// if !rules.is_ignored(Path::new("/src/project/target"), true) {
+///     // walk it
+/// }
+/// ```
+pub struct IgnoreRules {
+    /// The highest directory whose ignore files are read
+    top: PathBuf,
+    /// Rules of each directory, None if it has no ignore files
+    dirs: RefCell<HashMap<PathBuf, Option<Gitignore>>>,
+}
+
+impl IgnoreRules {
+    /// Returns the rules for walking the tree at `root`, which are read
+    /// from the directories below the top of the git repository `root` is
+    /// in, or below `root` itself if it isn't in one
+    pub fn new(root: &Path) -> IgnoreRules {
+        let top = root.ancestors()
+            .find(|dir| dir.join(".git").exists())
+            .unwrap_or(root);
+        IgnoreRules {
+            top: top.to_path_buf(),
+            dirs: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if `path` is ignored by the rules of the directories
+    /// above it, up to the top directory.  Paths have to start with the
+    /// root the rules were made for.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut dirs = self.dirs.borrow_mut();
+        for dir in path.ancestors().skip(1).take_while(|dir| dir.starts_with(&self.top)) {
+            let rules = dirs.entry(dir.to_path_buf()).or_insert_with(|| read_rules(dir));
+            if let Some(ref rules) = *rules {
+                let m = rules.matched(path, is_dir);
+                if m.is_ignore() {
+                    return true;
+                } else if m.is_whitelist() {
+                    return false;
+                }
+            }
+        }
+        false
+    }
+}
+
+/// The files seen so far, by device and inode number
+///
+/// Only Unix has inode numbers, so elsewhere every file is new.
+#[derive(Debug, Default)]
+pub struct SeenFiles {
+    seen: HashSet<(u64, u64)>,
+}
+
+impl SeenFiles {
+    pub fn new() -> SeenFiles {
+        SeenFiles::default()
+    }
+
+    /// Returns true if the file at `path` hasn't been seen before under
+    /// any path.  Symbolic links are followed.
+    pub fn first_visit(&mut self, path: &Path) -> io::Result<bool> {
+        let meta = try!(fs::metadata(path));
+        Ok(match file_identity(&meta) {
+            Some(id) => self.seen.insert(id),
+            None => true,
+        })
+    }
+}
+
+#[cfg(unix)]
+fn file_identity(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_identity(_meta: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Reads the rules in the ignore files of `dir`, or returns None if it has
+/// none.  Patterns that can't be parsed are skipped with a warning.
+fn read_rules(dir: &Path) -> Option<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+    let mut found = false;
+    for name in &IGNORE_FILES {
+        let path = dir.join(name);
+        if !path.is_file() {
+            continue;
+        }
+        found = true;
+        if let Some(e) = builder.add(&path) {
+            warn!("{}: {}", path.display(), e);
+        }
+    }
+    if !found {
+        return None;
+    }
+    match builder.build() {
+        Ok(rules) => Some(rules),
+        Err(e) => {
+            warn!("{}: {}", dir.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_ignore_rules() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("src/gen")).unwrap();
+        let write = |name: &str, contents: &str| {
+            File::create(dir.join(name)).unwrap().write_all(contents.as_bytes()).unwrap();
+        };
+        write(".gitignore", "target/\n*.o\n/build\ngen/\n");
+        write(".csearchignore", "!gen/\n");
+        write("src/.ignore", "*.log\n!keep.o\n");
+
+        let rules = IgnoreRules::new(dir);
+        let ignored = |name: &str, is_dir: bool| rules.is_ignored(&dir.join(name), is_dir);
+        assert!(ignored("target", true));
+        assert!(!ignored("target", false));
+        assert!(ignored("src/target", true));
+        assert!(ignored("src/main.o", false));
+        assert!(!ignored("src/keep.o", false));
+        assert!(ignored("src/debug.log", false));
+        assert!(!ignored("debug.log", false));
+        assert!(ignored("build", false));
+        assert!(!ignored("src/build", false));
+        assert!(!ignored("src/gen", true));
+        assert!(!ignored("src/main.c", false));
+    }
+
+    #[test]
+    fn test_ignore_rules_top() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("repo/.git")).unwrap();
+        fs::create_dir_all(dir.join("repo/src/lib")).unwrap();
+        let write = |name: &str, contents: &str| {
+            File::create(dir.join(name)).unwrap().write_all(contents.as_bytes()).unwrap();
+        };
+        write(".gitignore", "*.c\n");
+        write("repo/.gitignore", "*.o\n");
+        write("repo/src/.gitignore", "*.h\n");
+
+        // outside a repository, only the walked tree's ignore files count
+        let root = dir.join("repo/src");
+        fs::remove_dir(dir.join("repo/.git")).unwrap();
+        let rules = IgnoreRules::new(&root);
+        assert!(rules.is_ignored(&root.join("lib/a.h"), false));
+        assert!(!rules.is_ignored(&root.join("lib/a.o"), false));
+        assert!(!rules.is_ignored(&root.join("lib/a.c"), false));
+
+        // inside one, so do the ones up to the top of the repository
+        fs::create_dir(dir.join("repo/.git")).unwrap();
+        let rules = IgnoreRules::new(&root);
+        assert!(rules.is_ignored(&root.join("lib/a.h"), false));
+        assert!(rules.is_ignored(&root.join("lib/a.o"), false));
+        assert!(!rules.is_ignored(&root.join("lib/a.c"), false));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_seen_files() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("a")).unwrap();
+        File::create(dir.join("a/file")).unwrap();
+        File::create(dir.join("other")).unwrap();
+        symlink(dir.join("a"), dir.join("b")).unwrap();
+        fs::hard_link(dir.join("a/file"), dir.join("hard")).unwrap();
+
+        let mut seen = SeenFiles::new();
+        assert!(seen.first_visit(&dir.join("a/file")).unwrap());
+        assert!(!seen.first_visit(&dir.join("b/file")).unwrap());
+        assert!(!seen.first_visit(&dir.join("hard")).unwrap());
+        assert!(seen.first_visit(&dir.join("other")).unwrap());
+        assert!(seen.first_visit(&dir.join("missing")).is_err());
+    }
+}
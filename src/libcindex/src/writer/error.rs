@@ -13,11 +13,12 @@ pub struct IndexError {
     error: Box<error::Error + Send + Sync>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum IndexErrorKind {
     /// A read error returned from a std::io function
     IoError(io::ErrorKind),
-    /// Indicates a filename isn't valid utf-8
+    /// Indicates a filename can't be stored in the index, e.g. because
+    /// it's outside of the index root
     FileNameError,
     /// The file is longer than the specified max size
     FileTooLong,
@@ -30,6 +31,15 @@ pub enum IndexErrorKind {
     BinaryDataPresent,
     /// The ratio of invalid utf-8 : valid utf-8 chars is too high
     HighInvalidUtf8Ratio,
+    /// A `FileFilter` skipped the file for a reason not covered by the
+    /// other kinds
+    Filtered,
+    /// An indexing option is out of range, or an index's options section
+    /// can't be read
+    InvalidOptions,
+    /// A thread reading files for `IndexWriter::add_files` panicked or
+    /// stopped
+    WorkerFailed,
 }
 
 
@@ -92,6 +102,9 @@ impl Error for IndexError {
             IndexErrorKind::TooManyTrigrams => "too many trigrams in file",
             IndexErrorKind::BinaryDataPresent => "binary file",
             IndexErrorKind::HighInvalidUtf8Ratio => "Too many invalid utf-8 sequences",
+            IndexErrorKind::Filtered => "skipped by a filter",
+            IndexErrorKind::InvalidOptions => "invalid index options",
+            IndexErrorKind::WorkerFailed => "indexing thread failed",
         }
     }
 }
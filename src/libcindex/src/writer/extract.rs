@@ -0,0 +1,265 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Reading a file and collecting its set of trigrams.  This is the part of
+// indexing a file that doesn't touch the index itself, so it can run on
+// several threads at once; see parallel.rs.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use libcsearch::pathbytes;
+use libcsearch::reader::FileMeta;
+use libprofiling;
+
+use super::error::{IndexError, IndexErrorKind, IndexResult};
+use super::filter::{FileFilter, builtin_filters};
+use super::hashreader::HashReader;
+use super::options::IndexOptions;
+use super::previous::PreviousIndex;
+use super::trigramiter::TrigramScanner;
+use super::trigramset::TrigramSet;
+
+/// Size of the chunks files are read in
+const READ_CHUNK: usize = 64 << 10;
+
+/// The settings of an `IndexWriter` that decide what gets indexed
+#[derive(Clone)]
+pub struct ExtractSettings {
+    pub options: IndexOptions,
+    pub root: Option<PathBuf>,
+    /// Filters run after the built-in ones for `options`
+    pub filters: Vec<Arc<FileFilter>>,
+    /// Files that haven't changed since this index are reused
+    pub previous: Option<Arc<PreviousIndex>>,
+    /// Compute a SHA-256 digest of each file read, for finding copies
+    pub digest: bool,
+}
+
+/// The name, metadata and trigrams of a file, ready to be added to an
+/// index with `IndexWriter::add_trigrams`.
+#[derive(Debug)]
+pub struct FileTrigrams {
+    name: Vec<u8>,
+    meta: FileMeta,
+    trigrams: Vec<u32>,
+    /// SHA-256 digest of the file's contents, if it was read and the
+    /// settings asked for one
+    digest: Option<[u8; 32]>,
+    /// ID of the file in the previous index, if it's reused from there
+    /// instead of having been read
+    previous_id: Option<u32>,
+}
+
+impl FileTrigrams {
+    /// Returns the name of the file as it will be stored in the index
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+    /// Returns the file's metadata
+    pub fn meta(&self) -> &FileMeta {
+        &self.meta
+    }
+    /// Returns the file's trigrams, in no particular order
+    pub fn trigrams(&self) -> &[u32] {
+        &self.trigrams
+    }
+    /// Returns true if the file hasn't changed since the previous index
+    /// (see `IndexWriter::set_previous`), so it wasn't read and its
+    /// trigrams will be copied from there
+    pub fn is_reused(&self) -> bool {
+        self.previous_id.is_some()
+    }
+    pub fn into_parts(self) -> (Vec<u8>, FileMeta, Vec<u32>) {
+        (self.name, self.meta, self.trigrams)
+    }
+    pub(super) fn previous_id(&self) -> Option<u32> {
+        self.previous_id
+    }
+    pub(super) fn digest(&self) -> Option<[u8; 32]> {
+        self.digest
+    }
+}
+
+/// Reads files and collects their trigrams, skipping the files an
+/// `IndexWriter` would skip.  Get one from `IndexWriter::extractor`.
+pub struct TrigramExtractor {
+    settings: ExtractSettings,
+    builtin: Vec<Box<FileFilter>>,
+    trigram: TrigramSet,
+    buf: Vec<u8>,
+}
+
+impl TrigramExtractor {
+    pub fn new(settings: ExtractSettings) -> TrigramExtractor {
+        TrigramExtractor {
+            builtin: builtin_filters(&settings.options),
+            settings: settings,
+            trigram: TrigramSet::new(),
+            buf: vec![0; READ_CHUNK],
+        }
+    }
+
+    /// Replaces the settings used for files extracted from now on
+    pub fn set_settings(&mut self, settings: ExtractSettings) {
+        if settings.options != self.settings.options {
+            self.builtin = builtin_filters(&settings.options);
+        }
+        self.settings = settings;
+    }
+
+    /// Opens a file and collects its trigrams, unless it hasn't changed
+    /// since the previous index
+    pub fn extract_file<P: AsRef<Path>>(&mut self, filename: P) -> IndexResult<FileTrigrams> {
+        if let Some(t) = try!(self.unchanged(filename.as_ref())) {
+            return Ok(t);
+        }
+        let f = try!(File::open(filename.as_ref()));
+        let metadata = try!(f.metadata());
+        self.extract(filename, f, metadata.len(), metadata.modified().ok())
+    }
+
+    /// Returns the file as it was in the previous index, if its
+    /// modification time and size are the same as they were there
+    fn unchanged(&self, path: &Path) -> IndexResult<Option<FileTrigrams>> {
+        let previous = match self.settings.previous {
+            Some(ref p) => p,
+            None => return Ok(None),
+        };
+        let metadata = try!(fs::metadata(path));
+        let mtime = match metadata.modified() {
+            Ok(t) => t,
+            Err(_) => return Ok(None),
+        };
+        let name = try!(stored_name(self.settings.root.as_ref().map(|r| r.as_path()), path));
+        let meta = FileMeta::new(Some(mtime), metadata.len(), 0);
+        Ok(previous.unchanged(&name, &meta).map(|(id, meta)| {
+            FileTrigrams {
+                name: name,
+                meta: meta,
+                trigrams: Vec::new(),
+                digest: None,
+                previous_id: Some(id),
+            }
+        }))
+    }
+
+    /// Collects the trigrams of the file named `filename`, read from `f`.
+    /// `size` is the size of the file and `mtime` its modification time,
+    /// if known.
+    pub fn extract<P, R>(&mut self,
+                         filename: P,
+                         f: R,
+                         size: u64,
+                         mtime: Option<SystemTime>)
+                         -> IndexResult<FileTrigrams>
+        where P: AsRef<Path>,
+              R: Read
+    {
+        let path = filename.as_ref();
+        let name = try!(stored_name(self.settings.root.as_ref().map(|r| r.as_path()), path));
+        self.extract_named(name, path, f, size, mtime)
+    }
+
+    /// Collects the trigrams of a document that isn't a file, identified
+    /// by `id`, read from `f`.  See `IndexWriter::add_document`.
+    pub fn extract_document<R: Read>(&mut self,
+                                     id: &str,
+                                     f: R,
+                                     size: u64)
+                                     -> IndexResult<FileTrigrams> {
+        let name = id.as_bytes();
+        if name.is_empty() || name.contains(&0) {
+            return Err(IndexError::new(IndexErrorKind::FileNameError,
+                                       format!("invalid document id {:?}", id)));
+        }
+        if self.settings.root.is_some() && pathbytes::is_relative(name) {
+            return Err(IndexError::new(IndexErrorKind::FileNameError,
+                                       format!("document id {:?} would be read as a path \
+                                                relative to the index root",
+                                               id)));
+        }
+        self.extract_named(name.to_vec(), Path::new(id), f, size, None)
+    }
+
+    /// Collects the trigrams of a file or document stored as `name`.  The
+    /// filters see it as `path`.
+    fn extract_named<R: Read>(&mut self,
+                              name: Vec<u8>,
+                              path: &Path,
+                              f: R,
+                              size: u64,
+                              mtime: Option<SystemTime>)
+                              -> IndexResult<FileTrigrams> {
+        let _frame = libprofiling::profile("TrigramExtractor::extract");
+        let (builtin, custom) = (&self.builtin, &self.settings.filters);
+        let filters = || builtin.iter().map(|f| &**f).chain(custom.iter().map(|f| &**f));
+        for filter in filters() {
+            try!(filter.check_file(path, size));
+        }
+        let mut content = filters()
+            .filter_map(|filter| filter.content_filter(path, size))
+            .collect::<Vec<_>>();
+
+        self.trigram.clear();
+        let mut f = HashReader::new(f, self.settings.digest);
+        {
+            let mut scanner = TrigramScanner::new();
+            let _trigram_insert_frame = libprofiling::profile("TrigramExtractor::extract: \
+                                                               Insert Trigrams");
+            loop {
+                let n = match f.read(&mut self.buf) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                let chunk = &self.buf[..n];
+                for c in &mut content {
+                    try!(c.check(chunk));
+                }
+                let trigram = &mut self.trigram;
+                scanner.scan(chunk, |t| trigram.insert(t));
+            }
+        }
+        for c in &mut content {
+            try!(c.finish());
+        }
+        let trigrams = self.trigram.take_dense();
+        for filter in filters() {
+            try!(filter.check_trigrams(path, &trigrams));
+        }
+        debug!("{} {} {:?}", size, trigrams.len(), path);
+        Ok(FileTrigrams {
+            name: name,
+            meta: FileMeta::new(mtime, f.bytes_read(), f.hash()),
+            trigrams: trigrams,
+            digest: f.digest(),
+            previous_id: None,
+        })
+    }
+}
+
+/// Returns `filename` the way it's stored in the index, relative to
+/// `root` if there is one.
+pub fn stored_name(root: Option<&Path>, filename: &Path) -> IndexResult<Vec<u8>> {
+    let name = pathbytes::to_bytes(filename.as_os_str());
+    let root = match root {
+        Some(root) => root,
+        None => return Ok(name.into_owned()),
+    };
+    match pathbytes::relative_to(&name, &pathbytes::to_bytes(root.as_os_str())) {
+        Some(name) => Ok(name),
+        None => {
+            Err(IndexError::new(IndexErrorKind::FileNameError,
+                                format!("{} is outside of the index root {}",
+                                        filename.display(),
+                                        root.display())))
+        }
+    }
+}
@@ -0,0 +1,454 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Deciding which files to skip.
+//
+// Each filter gets three chances to skip a file: before it's read, from its
+// name and size; while it's read, from each chunk of its contents; and after
+// it's read, from its set of trigrams.  The limits in `IndexOptions` are
+// enforced by the built-in filters below, which every writer runs before
+// the ones added with `IndexWriter::add_filter`.
+
+use std::cmp;
+use std::path::Path;
+
+use memchr::memchr;
+
+use super::error::{IndexError, IndexErrorKind, IndexResult};
+use super::options::IndexOptions;
+use super::trigramiter::valid_utf8;
+
+/// Decides whether a file gets indexed.  A filter that returns an error
+/// skips the file, and the error is passed on to the caller of
+/// `IndexWriter::add_file` or the `skipped` callback of
+/// `IndexWriter::add_files`; use `IndexErrorKind::Filtered` for reasons
+/// that don't fit another kind.
+///
+/// Filters are shared between the threads reading files, so per-file state
+/// belongs in the `ContentFilter` returned by `content_filter`.
+///
+/// ```
+/// # use std::path::Path;
+/// # use libcindex::writer::{FileFilter, IndexError, IndexErrorKind, IndexResult};
+/// /// Skips minified JavaScript
+/// struct NoMinifiedJs;
+///
+/// impl FileFilter for NoMinifiedJs {
+///     fn check_file(&self, path: &Path, _size: u64) -> IndexResult<()> {
+///         if path.to_string_lossy().ends_with(".min.js") {
+///             return Err(IndexError::new(IndexErrorKind::Filtered, "minified JavaScript"));
+///         }
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait FileFilter: Send + Sync {
+    /// Called with the name of a file and its size in bytes before it's
+    /// read
+    fn check_file(&self, _path: &Path, _size: u64) -> IndexResult<()> {
+        Ok(())
+    }
+
+    /// Returns a filter to check the contents of a file that passed
+    /// `check_file`, if this filter looks at contents
+    fn content_filter(&self, _path: &Path, _size: u64) -> Option<Box<ContentFilter>> {
+        None
+    }
+
+    /// Called with the distinct trigrams of a file, in no particular order,
+    /// once it's been read
+    fn check_trigrams(&self, _path: &Path, _trigrams: &[u32]) -> IndexResult<()> {
+        Ok(())
+    }
+}
+
+/// Checks the contents of one file as it's read
+pub trait ContentFilter {
+    /// Called with each chunk of the file in order.  Chunks can be any
+    /// size, so anything spanning chunks has to be remembered.
+    fn check(&mut self, chunk: &[u8]) -> IndexResult<()>;
+
+    /// Called once the whole file has been read
+    fn finish(&mut self) -> IndexResult<()> {
+        Ok(())
+    }
+}
+
+/// Returns the built-in filters enforcing `options`
+pub fn builtin_filters(options: &IndexOptions) -> Vec<Box<FileFilter>> {
+    vec![Box::new(MaxFileLen(options.max_file_len())),
+         Box::new(SkipBinary),
+         Box::new(MaxInvalidUtf8(options.max_utf8_invalid())),
+         Box::new(MaxLineLen(options.max_line_len())),
+         Box::new(MaxTrigramCount(options.max_trigram_count()))]
+}
+
+/// Skips files longer than this many bytes
+#[derive(Debug, Clone, Copy)]
+pub struct MaxFileLen(pub u64);
+
+impl FileFilter for MaxFileLen {
+    fn check_file(&self, _path: &Path, size: u64) -> IndexResult<()> {
+        if size > self.0 {
+            return Err(IndexError::new(IndexErrorKind::FileTooLong,
+                                       format!("file too long, ignoring ({} > {})", size, self.0)));
+        }
+        Ok(())
+    }
+}
+
+/// Skips files with a NUL byte, other than as their first byte, that are
+/// long enough to have a trigram
+#[derive(Debug, Clone, Copy)]
+pub struct SkipBinary;
+
+impl FileFilter for SkipBinary {
+    fn content_filter(&self, _path: &Path, _size: u64) -> Option<Box<ContentFilter>> {
+        Some(Box::new(BinaryCheck { pairs: Pairs::new() }))
+    }
+}
+
+struct BinaryCheck {
+    pairs: Pairs,
+}
+
+impl ContentFilter for BinaryCheck {
+    fn check(&mut self, chunk: &[u8]) -> IndexResult<()> {
+        let (prev, rest, offset) = self.pairs.next(chunk);
+        let (b1, i) = match (prev, memchr(0, rest)) {
+            (0, _) if !rest.is_empty() => (0, 0),
+            (_, Some(0)) => (prev, 0),
+            (_, Some(i)) => (rest[i - 1], i),
+            (_, None) => return Ok(()),
+        };
+        Err(IndexError::new(IndexErrorKind::BinaryDataPresent,
+                            format!("Binary File. Bytes {:02x}{:02x} at offset {}",
+                                    b1,
+                                    rest[i],
+                                    offset + i as u64 + 1)))
+    }
+}
+
+/// Skips files where more than this ratio of the pairs of adjacent bytes
+/// aren't valid UTF-8.  The trigrams containing them are left out of the
+/// index either way.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxInvalidUtf8(pub f64);
+
+impl FileFilter for MaxInvalidUtf8 {
+    fn content_filter(&self, _path: &Path, size: u64) -> Option<Box<ContentFilter>> {
+        Some(Box::new(Utf8Check {
+            pairs: Pairs::new(),
+            max_invalid: ((size as f64) * self.0) as u64,
+            invalid: 0,
+        }))
+    }
+}
+
+struct Utf8Check {
+    pairs: Pairs,
+    max_invalid: u64,
+    invalid: u64,
+}
+
+impl ContentFilter for Utf8Check {
+    fn check(&mut self, chunk: &[u8]) -> IndexResult<()> {
+        let (mut b1, rest, mut offset) = self.pairs.next(chunk);
+        for block in rest.chunks(64) {
+            // ASCII bytes are always valid after one another
+            if b1 >= 0x80 || !block.is_ascii() {
+                try!(self.check_block(b1, block, offset));
+            }
+            b1 = block[block.len() - 1];
+            offset += block.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Utf8Check {
+    fn check_block(&mut self, mut b1: u8, block: &[u8], offset: u64) -> IndexResult<()> {
+        for (i, &b2) in block.iter().enumerate() {
+            if b1 != 0 && b2 != 0 && !valid_utf8(b1, b2) {
+                self.invalid += 1;
+                if self.invalid > self.max_invalid {
+                    let total = offset + i as u64 + 1;
+                    return Err(IndexError::new(IndexErrorKind::HighInvalidUtf8Ratio,
+                                               format!("High invalid UTF-8 ratio. total {} \
+                                                        invalid: {} ratio: {}",
+                                                       total,
+                                                       self.invalid,
+                                                       (self.invalid as f64) / (total as f64))));
+                }
+            }
+            b1 = b2;
+        }
+        Ok(())
+    }
+}
+
+/// Skips files with a line longer than this many bytes that's followed by
+/// more of the file.  The first two bytes of the file aren't counted, and
+/// nor are bytes that aren't valid UTF-8 after the one before them.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxLineLen(pub u64);
+
+impl FileFilter for MaxLineLen {
+    fn content_filter(&self, _path: &Path, _size: u64) -> Option<Box<ContentFilter>> {
+        Some(Box::new(LineCheck {
+            pairs: Pairs::new(),
+            max_line_len: self.0,
+            line_len: 0,
+        }))
+    }
+}
+
+struct LineCheck {
+    pairs: Pairs,
+    max_line_len: u64,
+    line_len: u64,
+}
+
+impl ContentFilter for LineCheck {
+    fn check(&mut self, chunk: &[u8]) -> IndexResult<()> {
+        let (mut b1, mut rest, _) = self.pairs.next(chunk);
+        while !rest.is_empty() {
+            let end = memchr(b'\n', rest).map_or(rest.len(), |i| i + 1);
+            let (part, next) = rest.split_at(end);
+            let newline = part[end - 1] == b'\n';
+            let line = if newline { &part[..end - 1] } else { part };
+            // every byte of a line of ASCII without NULs counts, so there's
+            // no need to look at each pair
+            if is_plain(b1) && line.iter().all(|&b| is_plain(b)) {
+                let n = line.len() as u64;
+                if (newline && self.line_len + n > self.max_line_len) ||
+                   (n > 0 && self.line_len + n - 1 > self.max_line_len) {
+                    return Err(self.too_long());
+                }
+                self.line_len = if newline { 0 } else { self.line_len + n };
+            } else {
+                for &b2 in part {
+                    try!(self.add(b1, b2));
+                    b1 = b2;
+                }
+            }
+            b1 = part[end - 1];
+            rest = next;
+        }
+        Ok(())
+    }
+}
+
+impl LineCheck {
+    fn add(&mut self, b1: u8, b2: u8) -> IndexResult<()> {
+        if b1 != 0 && b2 != 0 && valid_utf8(b1, b2) {
+            if self.line_len > self.max_line_len {
+                return Err(self.too_long());
+            }
+            if b2 == b'\n' {
+                self.line_len = 0;
+            } else {
+                self.line_len += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// The error for a line that's gotten too long, which happens as soon
+    /// as its length is one past the limit
+    fn too_long(&self) -> IndexError {
+        IndexError::new(IndexErrorKind::LineTooLong,
+                        format!("Line too long ({} > {})",
+                                self.max_line_len.saturating_add(1),
+                                self.max_line_len))
+    }
+}
+
+/// Returns true if `b` is ASCII but not NUL, so any pair of them is valid
+fn is_plain(b: u8) -> bool {
+    b.wrapping_sub(1) < 0x7f
+}
+
+/// Splits the chunks of a file into the pairs of adjacent bytes the file's
+/// trigrams end in, which is every pair but the first
+struct Pairs {
+    num_read: u64,
+    last: u8,
+}
+
+impl Pairs {
+    fn new() -> Pairs {
+        Pairs {
+            num_read: 0,
+            last: 0,
+        }
+    }
+
+    /// Returns the part of `chunk` holding the second bytes of pairs, the
+    /// byte before it, and the offset of its start in the file
+    fn next<'a>(&mut self, chunk: &'a [u8]) -> (u8, &'a [u8], u64) {
+        let skip = cmp::min(chunk.len() as u64, 2 - cmp::min(self.num_read, 2)) as usize;
+        let (head, rest) = chunk.split_at(skip);
+        self.num_read += head.len() as u64;
+        let prev = *head.last().unwrap_or(&self.last);
+        let offset = self.num_read;
+        self.last = *rest.last().unwrap_or(&prev);
+        self.num_read += rest.len() as u64;
+        (prev, rest, offset)
+    }
+}
+
+/// Skips files with more than this many distinct trigrams
+#[derive(Debug, Clone, Copy)]
+pub struct MaxTrigramCount(pub u64);
+
+impl FileFilter for MaxTrigramCount {
+    fn check_trigrams(&self, _path: &Path, trigrams: &[u32]) -> IndexResult<()> {
+        if trigrams.len() as u64 > self.0 {
+            return Err(IndexError::new(IndexErrorKind::TooManyTrigrams,
+                                       format!("Too many trigrams ({} > {})",
+                                               trigrams.len(),
+                                               self.0)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use writer::trigramiter::valid_utf8;
+    use writer::error::{IndexErrorKind, IndexResult};
+
+    fn check<F: FileFilter>(filter: F, chunks: &[&[u8]]) -> IndexResult<()> {
+        let size = chunks.iter().map(|c| c.len() as u64).sum();
+        let mut c = filter.content_filter(Path::new("f"), size).unwrap();
+        for chunk in chunks {
+            try!(c.check(chunk));
+        }
+        c.finish()
+    }
+
+    fn kind(r: IndexResult<()>) -> IndexErrorKind {
+        r.unwrap_err().kind()
+    }
+
+    #[test]
+    fn test_skip_binary() {
+        check(SkipBinary, &[b"text\n", b"more"]).unwrap();
+        assert_eq!(kind(check(SkipBinary, &[b"text\n", b"mo\x00re"])),
+                   IndexErrorKind::BinaryDataPresent);
+        assert_eq!(kind(check(SkipBinary, &[b"a", b"\x00", b"", b"b"])),
+                   IndexErrorKind::BinaryDataPresent);
+        assert_eq!(kind(check(SkipBinary, &[b"ab\x00"])),
+                   IndexErrorKind::BinaryDataPresent);
+        // a NUL that's the first byte or in a file too short for a trigram
+        check(SkipBinary, &[b"\x00", b"ab"]).unwrap();
+        check(SkipBinary, &[b"a\x00"]).unwrap();
+    }
+
+    #[test]
+    fn test_max_invalid_utf8() {
+        // the pair of the first two bytes isn't counted
+        check(MaxInvalidUtf8(0.7), &[b"a\xff", b"b\xff"]).unwrap();
+        assert_eq!(kind(check(MaxInvalidUtf8(0.3), &[b"a\xff", b"b\xff"])),
+                   IndexErrorKind::HighInvalidUtf8Ratio);
+        check(MaxInvalidUtf8(0.0), &[b"\xffab"]).unwrap();
+        check(MaxInvalidUtf8(0.0), &["d\u{e9}j\u{e0} vu".as_bytes()]).unwrap();
+        assert_eq!(kind(check(MaxInvalidUtf8(0.0), &[b"ab\xc3", b"b"])),
+                   IndexErrorKind::HighInvalidUtf8Ratio);
+    }
+
+    #[test]
+    fn test_max_line_len() {
+        check(MaxLineLen(3), &[b"abc\nab", b"c\n", b"", b"abc"]).unwrap();
+        assert_eq!(kind(check(MaxLineLen(3), &[b"abc\nab", b"cd\n"])),
+                   IndexErrorKind::LineTooLong);
+        assert_eq!(kind(check(MaxLineLen(3), &[b"ab", b"", b"cdef", b"g"])),
+                   IndexErrorKind::LineTooLong);
+        // the first two bytes, bytes after the line, and invalid UTF-8
+        // aren't counted
+        check(MaxLineLen(3), &[b"abcde"]).unwrap();
+        check(MaxLineLen(3), &[b"\nabcd"]).unwrap();
+        check(MaxLineLen(3), &[b"\nab\xff\xffc\n"]).unwrap();
+    }
+
+    /// Returns true if `TrigramReader`, which did these checks before the
+    /// filters did, would have skipped `data` with only the given checks
+    fn reader_skips(data: &[u8],
+                    binary: bool,
+                    max_invalid: Option<u64>,
+                    max_line_len: Option<u64>)
+                    -> bool {
+        let (mut invalid, mut line_len) = (0, 0);
+        for (i, &c) in data.iter().enumerate().skip(2) {
+            let b1 = data[i - 1];
+            if b1 == 0 || c == 0 {
+                if binary {
+                    return true;
+                }
+            } else if !valid_utf8(b1, c) {
+                invalid += 1;
+                if max_invalid.map_or(false, |max| invalid > max) {
+                    return true;
+                }
+            } else if max_line_len.map_or(false, |max| line_len > max) {
+                return true;
+            } else if c == b'\n' {
+                line_len = 0;
+            } else {
+                line_len += 1;
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn test_same_as_reader() {
+        const BYTES: &[u8] = b"aaaaab\n\n\x00\xc3\xa9\xff";
+        let mut seed = 1u32;
+        let mut rand = |n: usize| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as usize % n
+        };
+        for _ in 0..5000 {
+            let len = rand(16);
+            let data = (0..len).map(|_| BYTES[rand(BYTES.len())]).collect::<Vec<_>>();
+            let (mut i, mut j) = (rand(len + 1), rand(len + 1));
+            if i > j {
+                ::std::mem::swap(&mut i, &mut j);
+            }
+            let chunks: &[&[u8]] = &[&data[..i], &data[i..j], &data[j..]];
+            let max_invalid = (len as f64 * 0.2) as u64;
+            let skips = |filter: &FileFilter| {
+                let mut c = filter.content_filter(Path::new("f"), len as u64).unwrap();
+                chunks.iter().any(|chunk| c.check(chunk).is_err())
+            };
+            assert_eq!(skips(&SkipBinary),
+                       reader_skips(&data, true, None, None),
+                       "{:?}",
+                       data);
+            assert_eq!(skips(&MaxInvalidUtf8(0.2)),
+                       reader_skips(&data, false, Some(max_invalid), None),
+                       "{:?}",
+                       data);
+            assert_eq!(skips(&MaxLineLen(3)),
+                       reader_skips(&data, false, None, Some(3)),
+                       "{:?}",
+                       data);
+        }
+    }
+
+    #[test]
+    fn test_file_checks() {
+        let p = Path::new("f");
+        MaxFileLen(10).check_file(p, 10).unwrap();
+        assert_eq!(kind(MaxFileLen(10).check_file(p, 11)), IndexErrorKind::FileTooLong);
+        MaxTrigramCount(2).check_trigrams(p, &[1, 2]).unwrap();
+        assert_eq!(kind(MaxTrigramCount(2).check_trigrams(p, &[1, 2, 3])),
+                   IndexErrorKind::TooManyTrigrams);
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// The parts of the on-disk format that differ between index versions.
+// See libcsearch's reader/read.rs for a description of both formats.
+//
+// Version 1 is written exactly the way the Go implementation writes it, so
+// an index built here can be used by Go's csearch and vice versa.
+
+use std::io::{self, Write};
+use std::u32;
+
+use byteorder::{BigEndian, WriteBytesExt};
+use libcsearch::reader::IndexVersion;
+use libvarint;
+
+use super::{FrontCoder, WriteTrigram, write_post_blocks};
+use super::postinglist::to_diffs;
+
+/// Writes `offset` in the size used by `version`, failing if it doesn't fit
+fn write_offset<W: Write>(out: &mut W, version: IndexVersion, offset: u64) -> io::Result<()> {
+    match version {
+        IndexVersion::V1 => {
+            if offset > u32::MAX as u64 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("offset {} is too large for a version 1 \
+                                                   index",
+                                                  offset)));
+            }
+            out.write_u32::<BigEndian>(offset as u32)
+        }
+        IndexVersion::V2 => out.write_u64::<BigEndian>(offset),
+    }
+}
+
+/// Writes the list of names, front-coded in version 2 and plain in version 1.
+pub struct NameWriter {
+    version: IndexVersion,
+    coder: FrontCoder,
+}
+
+impl NameWriter {
+    pub fn new(version: IndexVersion) -> NameWriter {
+        NameWriter {
+            version: version,
+            coder: FrontCoder::new(),
+        }
+    }
+
+    /// Writes the next name in the list to `out`
+    pub fn write_name<W: Write>(&mut self, out: &mut W, name: &[u8]) -> io::Result<()> {
+        match self.version {
+            IndexVersion::V1 => {
+                try!(out.write_all(name));
+                out.write_u8(0)
+            }
+            IndexVersion::V2 => self.coder.write_name(out, name),
+        }
+    }
+
+    /// Writes the name index entry for a name at `offset` in the name list
+    pub fn write_index_entry<W: Write>(&self, out: &mut W, offset: u64) -> io::Result<()> {
+        write_offset(out, self.version, offset)
+    }
+}
+
+/// Writes a posting list for `trigram`.  An empty list of `ids` writes the
+/// end of the list of posting lists.
+pub fn write_post_list<W: Write>(out: &mut W,
+                                 version: IndexVersion,
+                                 trigram: u32,
+                                 ids: &[u32])
+                                 -> io::Result<()> {
+    try!(out.write_trigram(trigram));
+    match version {
+        IndexVersion::V1 => {
+            for delta in to_diffs(ids.iter().cloned()) {
+                try!(libvarint::write_uvarint(out, delta));
+            }
+            Ok(())
+        }
+        IndexVersion::V2 => write_post_blocks(out, ids),
+    }
+}
+
+/// Writes a posting list index entry
+pub fn write_post_entry<W: Write>(out: &mut W,
+                                  version: IndexVersion,
+                                  trigram: u32,
+                                  count: u32,
+                                  offset: u64)
+                                  -> io::Result<()> {
+    try!(out.write_trigram(trigram));
+    try!(out.write_u32::<BigEndian>(count));
+    write_offset(out, version, offset)
+}
+
+/// Writes the trailer.  `offsets` has the offsets of the path list, name
+/// list, posting lists, name index, posting list index and section table;
+/// a version 1 index has no section table, so the last one is left out.
+pub fn write_trailer<W: Write>(out: &mut W,
+                               version: IndexVersion,
+                               offsets: &[u64; 6])
+                               -> io::Result<()> {
+    let n = match version {
+        IndexVersion::V1 => 5,
+        IndexVersion::V2 => 6,
+    };
+    for &off in &offsets[..n] {
+        try!(write_offset(out, version, off));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libcsearch::reader::IndexVersion;
+
+    #[test]
+    fn test_write_post_list_v1() {
+        let mut out = Vec::new();
+        write_post_list(&mut out, IndexVersion::V1, 0x616263, &[1, 6, 7, 8]).unwrap();
+        assert_eq!(out, b"abc\x02\x05\x01\x01\x00".to_vec());
+
+        let mut out = Vec::new();
+        write_post_list(&mut out, IndexVersion::V1, 0xffffff, &[]).unwrap();
+        assert_eq!(out, b"\xff\xff\xff\x00".to_vec());
+    }
+
+    #[test]
+    fn test_v1_offset_overflow() {
+        let mut out = Vec::new();
+        write_trailer(&mut out, IndexVersion::V1, &[0, 1, 2, 3, 1 << 32, 5]).unwrap_err();
+        write_trailer(&mut out, IndexVersion::V2, &[0, 1, 2, 3, 1 << 32, 5]).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Front-coded name lists, used by version 2 indexes.
+// See libcsearch's reader/read.rs for a description of the format.
+
+use std::io::{self, Write};
+
+use byteorder::WriteBytesExt;
+use consts::NAME_RESTART_INTERVAL;
+use libvarint;
+
+/// Writes a list of names, each one front-coded against the one before it.
+pub struct FrontCoder {
+    last: Vec<u8>,
+    count: usize,
+}
+
+impl FrontCoder {
+    pub fn new() -> FrontCoder {
+        FrontCoder {
+            last: Vec::new(),
+            count: 0,
+        }
+    }
+
+    /// Writes the next name in the list to `out`
+    pub fn write_name<W: Write>(&mut self, out: &mut W, name: &[u8]) -> io::Result<()> {
+        let shared = if self.count % NAME_RESTART_INTERVAL == 0 {
+            0
+        } else {
+            self.last.iter().zip(name.iter()).take_while(|&(a, b)| a == b).count()
+        };
+        try!(libvarint::write_uvarint(out, shared as u32));
+        try!(out.write_all(&name[shared..]));
+        try!(out.write_u8(0));
+        self.last.clear();
+        self.last.extend_from_slice(name);
+        self.count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_name() {
+        let mut out = Vec::new();
+        let mut f = FrontCoder::new();
+        for name in &["f0", "file1", "file3", "thefile2"] {
+            f.write_name(&mut out, name.as_bytes()).unwrap();
+        }
+        assert_eq!(out, b"\x00f0\x00\x01ile1\x00\x043\x00\x00thefile2\x00".to_vec());
+    }
+
+    #[test]
+    fn test_restart() {
+        let mut out = Vec::new();
+        let mut f = FrontCoder::new();
+        for _ in 0..NAME_RESTART_INTERVAL + 1 {
+            f.write_name(&mut out, b"ab").unwrap();
+        }
+        let mut want = b"\x00ab\x00".to_vec();
+        for _ in 1..NAME_RESTART_INTERVAL {
+            want.extend_from_slice(b"\x02\x00");
+        }
+        want.extend_from_slice(b"\x00ab\x00");
+        assert_eq!(out, want);
+    }
+}
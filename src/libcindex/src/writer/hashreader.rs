@@ -0,0 +1,74 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use std::hash::Hasher;
+use std::io::{self, Read};
+
+use sha2::{Digest, Sha256};
+use twox_hash::XxHash64;
+
+
+/// Wraps a reader, hashing the bytes that pass through it
+pub struct HashReader<R: Read> {
+    inner: R,
+    hasher: XxHash64,
+    sha: Option<Sha256>,
+    bytes_read: u64,
+}
+
+impl<R: Read> HashReader<R> {
+    /// Wraps `inner`.  If `digest` is set, a SHA-256 digest of the bytes
+    /// is computed too, which takes longer.
+    pub fn new(inner: R, digest: bool) -> HashReader<R> {
+        HashReader {
+            inner: inner,
+            hasher: XxHash64::with_seed(0),
+            sha: if digest { Some(Sha256::new()) } else { None },
+            bytes_read: 0,
+        }
+    }
+    /// Returns the hash of the bytes read so far
+    pub fn hash(&self) -> u64 {
+        self.hasher.finish()
+    }
+    /// Returns the SHA-256 digest of the bytes read so far, if it's being
+    /// computed
+    pub fn digest(&self) -> Option<[u8; 32]> {
+        self.sha.as_ref().map(|sha| {
+            let mut d = [0; 32];
+            d.copy_from_slice(&sha.clone().finalize());
+            d
+        })
+    }
+    /// Returns the number of bytes read so far
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.hasher.write(&buf[..n]);
+        if let Some(ref mut sha) = self.sha {
+            sha.update(&buf[..n]);
+        }
+        self.bytes_read += n as u64;
+        Ok(n)
+    }
+}
+
+#[test]
+fn test_hash_matches_contents() {
+    let mut r = HashReader::new("hello world".as_bytes(), true);
+    let mut v = Vec::new();
+    r.read_to_end(&mut v).unwrap();
+    let mut h = XxHash64::with_seed(0);
+    h.write(b"hello world");
+    assert_eq!(r.hash(), h.finish());
+    assert_eq!(r.digest().unwrap()[..4], [0xb9, 0x4d, 0x27, 0xb9]);
+    assert_eq!(r.bytes_read(), 11);
+    assert_eq!(HashReader::new("".as_bytes(), false).digest(), None);
+}
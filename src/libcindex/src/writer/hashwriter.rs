@@ -0,0 +1,68 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use std::hash::Hasher;
+use std::io::{self, Seek, SeekFrom, Write};
+
+use twox_hash::XxHash64;
+
+
+/// Wraps a writer, hashing the bytes written through it
+///
+/// Used to checksum each section of an index as it is written out.
+pub struct HashWriter<W: Write> {
+    inner: W,
+    hasher: XxHash64,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W) -> HashWriter<W> {
+        HashWriter {
+            inner: inner,
+            hasher: XxHash64::with_seed(0),
+        }
+    }
+    /// Returns the hash of the bytes written since the last call,
+    /// and starts a new one
+    pub fn take_hash(&mut self) -> u64 {
+        let h = self.hasher.finish();
+        self.hasher = XxHash64::with_seed(0);
+        h
+    }
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.hasher.write(&buf[..n]);
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for HashWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[test]
+fn test_take_hash() {
+    let mut w = HashWriter::new(Vec::new());
+    w.write_all(b"hello").unwrap();
+    let h1 = w.take_hash();
+    w.write_all(b"hello").unwrap();
+    assert_eq!(w.take_hash(), h1);
+    assert!(w.take_hash() != h1);
+    assert_eq!(w.get_mut().len(), 10);
+}
@@ -5,21 +5,46 @@
 
 use std::io::{self, BufReader, BufWriter, SeekFrom, BufRead, Read, Seek, Write};
 
+use byteorder::{BigEndian, WriteBytesExt};
+
 pub use self::write::IndexWriter;
 pub use self::error::{IndexResult, IndexError, IndexErrorKind};
+pub use self::hashwriter::HashWriter;
+pub use self::postblock::write_post_blocks;
+pub use self::frontcode::FrontCoder;
+pub use self::extract::{FileTrigrams, TrigramExtractor};
+pub use self::filter::{ContentFilter, FileFilter, MaxFileLen, MaxInvalidUtf8, MaxLineLen,
+                       MaxTrigramCount, SkipBinary};
+pub use self::stats::{IndexStats, LARGEST_POSTING_LISTS};
+pub use self::options::{IndexOptions, IndexOptionsBuilder, StoredOptions, TOOL_VERSION};
+pub use self::format::{NameWriter, write_post_entry, write_post_list, write_trailer};
+pub use self::trigramiter::TrigramScanner;
 
 
 mod write;
+mod extract;
+mod filter;
+mod parallel;
+mod options;
+mod previous;
+mod scratch;
+mod stats;
 mod error;
-mod sparseset;
+mod trigramset;
 
 mod postinglist;
+mod postblock;
+mod frontcode;
+mod format;
 mod postentry;
 mod postheap;
 mod trigramiter;
 mod sort_post;
+mod hashreader;
+mod hashwriter;
 
-const NPOST: usize = (64 << 20) / 8; // 64 MB worth of post entries
+/// Default memory budget for post entries, see `IndexWriter::post_mem_budget`
+const POST_MEM_BUDGET: usize = 64 << 20;
 
 /// Returns the offset in a seekable object.
 pub fn get_offset<S: Seek>(seekable: &mut S) -> io::Result<u64> {
@@ -45,6 +70,51 @@ pub fn copy_file<R: Read + Seek, W: Write>(dest: &mut BufWriter<W>, src: &mut R)
 }
 
 
+/// Location of an optional section written into an index
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub id: u32,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Writes the section table describing `sections`
+pub fn write_section_table<W: Write>(out: &mut W, sections: &[Section]) -> io::Result<()> {
+    for s in sections {
+        try!(out.write_u32::<BigEndian>(s.id));
+        try!(out.write_u64::<BigEndian>(s.offset));
+        try!(out.write_u64::<BigEndian>(s.len));
+    }
+    Ok(())
+}
+
+
+/// Returns the checksum of everything written to `out` since the last call
+pub fn take_checksum<W: Write + Seek>(out: &mut BufWriter<HashWriter<W>>) -> io::Result<u64> {
+    try!(out.flush());
+    Ok(out.get_mut().take_hash())
+}
+
+/// Writes the checksum section from a list of (section id, checksum) pairs
+pub fn write_checksums<W: Write>(out: &mut W, checksums: &[(u32, u64)]) -> io::Result<()> {
+    for &(id, sum) in checksums {
+        try!(out.write_u32::<BigEndian>(id));
+        try!(out.write_u64::<BigEndian>(sum));
+    }
+    Ok(())
+}
+
+/// Writes the duplicates section from a sorted list of (file ID in the
+/// posting lists, file ID of a duplicate) pairs
+pub fn write_duplicates<W: Write>(out: &mut W, duplicates: &[(u32, u32)]) -> io::Result<()> {
+    for &(first, dup) in duplicates {
+        try!(out.write_u32::<BigEndian>(first));
+        try!(out.write_u32::<BigEndian>(dup));
+    }
+    Ok(())
+}
+
+
 /// Used for writing trigrams
 pub trait WriteTrigram: Write {
     /// Write a trigram to a stream
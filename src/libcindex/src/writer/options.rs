@@ -0,0 +1,267 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Options that decide which files get indexed.
+//
+// A version 2 index records the options it was built with in its options
+// section, so adding to it or reindexing it later can use the same ones.
+// The section is text, one "name=value\n" line per option, followed by
+//
+// 	tool_version=<version of the library that wrote the index>
+// 	created=<seconds since the UNIX epoch>
+//
+// Readers skip names they don't recognize and use the default for options
+// that are missing, so options can be added without changing the format.
+
+use std::io::{self, Write};
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use consts::SECTION_OPTIONS;
+use libcsearch::reader::IndexReader;
+
+use super::error::{IndexError, IndexErrorKind, IndexResult};
+
+/// Version of this library, recorded in the indexes it writes
+pub const TOOL_VERSION: &'static str = concat!("libcindex ", env!("CARGO_PKG_VERSION"));
+
+const MAX_FILE_LEN: u64 = 1 << 30;
+const MAX_TEXT_TRIGRAMS: u64 = 30000;
+const MAX_INVALID_UTF8_RATIO: f64 = 0.1;
+const MAX_LINE_LEN: u64 = 2000;
+
+/// Limits on the files that get indexed.  Files that exceed them are
+/// skipped.  Build one with `IndexOptions::builder`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexOptions {
+    max_trigram_count: u64,
+    max_utf8_invalid: f64,
+    max_file_len: u64,
+    max_line_len: u64,
+}
+
+impl Default for IndexOptions {
+    fn default() -> IndexOptions {
+        IndexOptions {
+            max_trigram_count: MAX_TEXT_TRIGRAMS,
+            max_utf8_invalid: MAX_INVALID_UTF8_RATIO,
+            max_file_len: MAX_FILE_LEN,
+            max_line_len: MAX_LINE_LEN,
+        }
+    }
+}
+
+impl IndexOptions {
+    /// Returns a builder starting from the default options
+    ///
+    /// ```
+    /// # use libcindex::writer::IndexOptions;
+    /// let options = IndexOptions::builder()
+    ///     .max_file_len(1 << 20)
+    ///     .max_utf8_invalid(0.0)
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(options.max_file_len(), 1 << 20);
+    /// ```
+    pub fn builder() -> IndexOptionsBuilder {
+        IndexOptionsBuilder { options: IndexOptions::default() }
+    }
+    /// Returns a builder starting from these options, to change some of them
+    pub fn to_builder(&self) -> IndexOptionsBuilder {
+        IndexOptionsBuilder { options: self.clone() }
+    }
+    /// Max number of distinct trigrams in a file
+    pub fn max_trigram_count(&self) -> u64 {
+        self.max_trigram_count
+    }
+    /// Max ratio of invalid UTF-8 sequences to bytes in a file
+    pub fn max_utf8_invalid(&self) -> f64 {
+        self.max_utf8_invalid
+    }
+    /// Max size of a file in bytes
+    pub fn max_file_len(&self) -> u64 {
+        self.max_file_len
+    }
+    /// Max length of a line in bytes
+    pub fn max_line_len(&self) -> u64 {
+        self.max_line_len
+    }
+}
+
+/// Builds `IndexOptions`, checking that each one is in range
+#[derive(Debug, Clone)]
+pub struct IndexOptionsBuilder {
+    options: IndexOptions,
+}
+
+impl IndexOptionsBuilder {
+    pub fn max_trigram_count(mut self, n: u64) -> IndexOptionsBuilder {
+        self.options.max_trigram_count = n;
+        self
+    }
+    pub fn max_utf8_invalid(mut self, ratio: f64) -> IndexOptionsBuilder {
+        self.options.max_utf8_invalid = ratio;
+        self
+    }
+    pub fn max_file_len(mut self, len: u64) -> IndexOptionsBuilder {
+        self.options.max_file_len = len;
+        self
+    }
+    pub fn max_line_len(mut self, len: u64) -> IndexOptionsBuilder {
+        self.options.max_line_len = len;
+        self
+    }
+
+    /// Returns the options, or an `InvalidOptions` error if one is out of
+    /// range
+    pub fn build(self) -> IndexResult<IndexOptions> {
+        let o = self.options;
+        let ratio = o.max_utf8_invalid;
+        if !(ratio >= 0.0 && ratio <= 1.0) {
+            return Err(invalid(format!("max_utf8_invalid must be between 0 and 1, not {}",
+                                       ratio)));
+        }
+        for &(name, value) in &[("max_trigram_count", o.max_trigram_count),
+                                ("max_file_len", o.max_file_len),
+                                ("max_line_len", o.max_line_len)] {
+            if value == 0 {
+                return Err(invalid(format!("{} must be at least 1", name)));
+            }
+        }
+        Ok(o)
+    }
+}
+
+/// The contents of an index's options section
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredOptions {
+    /// The options the index was built with
+    pub options: IndexOptions,
+    /// `TOOL_VERSION` of the library that wrote the index
+    pub tool_version: String,
+    /// When the index was written, to the second
+    pub created: SystemTime,
+}
+
+impl StoredOptions {
+    /// Reads the options section of `ix`.  Returns `None` for an index
+    /// without one, e.g. a version 1 index or one written before options
+    /// were recorded.
+    pub fn read(ix: &IndexReader) -> IndexResult<Option<StoredOptions>> {
+        match ix.section(SECTION_OPTIONS) {
+            Some(data) => StoredOptions::from_bytes(data).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Decodes an options section
+    pub fn from_bytes(data: &[u8]) -> IndexResult<StoredOptions> {
+        let text = try!(str::from_utf8(data)
+            .map_err(|_| invalid("options section isn't valid UTF-8")));
+        let mut builder = IndexOptions::builder();
+        let mut tool_version = String::new();
+        let mut created = UNIX_EPOCH;
+        for line in text.lines() {
+            let mut kv = line.splitn(2, '=');
+            let (name, value) = match (kv.next(), kv.next()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => return Err(invalid(format!("bad line in options section: {:?}", line))),
+            };
+            builder = match name {
+                "max_trigram_count" => builder.max_trigram_count(try!(parse(name, value))),
+                "max_utf8_invalid" => builder.max_utf8_invalid(try!(parse(name, value))),
+                "max_file_len" => builder.max_file_len(try!(parse(name, value))),
+                "max_line_len" => builder.max_line_len(try!(parse(name, value))),
+                "tool_version" => {
+                    tool_version = value.to_string();
+                    builder
+                }
+                "created" => {
+                    created = UNIX_EPOCH + Duration::from_secs(try!(parse(name, value)));
+                    builder
+                }
+                _ => builder,
+            };
+        }
+        Ok(StoredOptions {
+            options: try!(builder.build()),
+            tool_version: tool_version,
+            created: created,
+        })
+    }
+
+    /// Encodes the options section into `w`
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let o = &self.options;
+        try!(write!(w, "max_trigram_count={}\n", o.max_trigram_count));
+        try!(write!(w, "max_utf8_invalid={}\n", o.max_utf8_invalid));
+        try!(write!(w, "max_file_len={}\n", o.max_file_len));
+        try!(write!(w, "max_line_len={}\n", o.max_line_len));
+        try!(write!(w, "tool_version={}\n", self.tool_version));
+        let created = self.created.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        write!(w, "created={}\n", created)
+    }
+}
+
+fn parse<T: str::FromStr>(name: &str, value: &str) -> IndexResult<T> {
+    value.parse().map_err(|_| invalid(format!("bad value for {} in options section: {:?}",
+                                              name,
+                                              value)))
+}
+
+fn invalid<S: Into<String>>(msg: S) -> IndexError {
+    IndexError::new(IndexErrorKind::InvalidOptions, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+    use writer::error::IndexErrorKind;
+
+    #[test]
+    fn test_build_invalid() {
+        let err = |b: IndexOptionsBuilder| b.build().unwrap_err().kind();
+        assert_eq!(err(IndexOptions::builder().max_utf8_invalid(1.5)),
+                   IndexErrorKind::InvalidOptions);
+        assert_eq!(err(IndexOptions::builder().max_utf8_invalid(-0.1)),
+                   IndexErrorKind::InvalidOptions);
+        assert_eq!(err(IndexOptions::builder().max_utf8_invalid(0.0 / 0.0)),
+                   IndexErrorKind::InvalidOptions);
+        assert_eq!(err(IndexOptions::builder().max_line_len(0)),
+                   IndexErrorKind::InvalidOptions);
+        assert_eq!(IndexOptions::builder().build().unwrap(), IndexOptions::default());
+    }
+
+    #[test]
+    fn test_stored_roundtrip() {
+        let stored = StoredOptions {
+            options: IndexOptions::builder()
+                .max_trigram_count(100)
+                .max_utf8_invalid(0.333)
+                .build()
+                .unwrap(),
+            tool_version: TOOL_VERSION.to_string(),
+            created: UNIX_EPOCH + Duration::from_secs(1500000000),
+        };
+        let mut data = Vec::new();
+        stored.write_to(&mut data).unwrap();
+        assert_eq!(StoredOptions::from_bytes(&data).unwrap(), stored);
+    }
+
+    #[test]
+    fn test_stored_unknown_and_missing() {
+        let stored = StoredOptions::from_bytes(b"max_line_len=10\nsome_new_option=x\n").unwrap();
+        assert_eq!(stored.options,
+                   IndexOptions::builder().max_line_len(10).build().unwrap());
+        assert_eq!(stored.tool_version, "");
+        assert_eq!(stored.created, UNIX_EPOCH);
+
+        for bad in &[&b"max_line_len=ten\n"[..], b"max_line_len\n", b"max_line_len=0\n"] {
+            assert_eq!(StoredOptions::from_bytes(bad).unwrap_err().kind(),
+                       IndexErrorKind::InvalidOptions);
+        }
+    }
+}
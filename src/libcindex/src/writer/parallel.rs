@@ -0,0 +1,197 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Indexing files on several threads.
+//
+// Reading files and collecting their trigrams is most of the work of
+// indexing, and each file can be done on its own, so worker threads each
+// take the next file name off a shared queue and send back its trigrams.
+// File IDs are assigned in the order files are added, so the writer holds
+// on to results that arrive early and adds them in the order the files were
+// queued.  That way the index is the same no matter how many threads built
+// it.
+//
+// A worker that panics sends that back instead of the file's trigrams and
+// stops, and indexing stops with an error naming the file.
+
+use std::any::Any;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Seek, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+
+use libprofiling;
+
+use super::error::{IndexError, IndexErrorKind, IndexResult};
+use super::extract::FileTrigrams;
+use super::write::IndexWriter;
+
+/// Number of files queued or waiting to be added, per worker thread
+const QUEUE_PER_JOB: usize = 4;
+
+type Job = (usize, PathBuf);
+type JobResult = (usize, PathBuf, thread::Result<IndexResult<FileTrigrams>>);
+
+impl<W: Write + Seek> IndexWriter<W> {
+    /// Opens and indexes each file in `files`, reading them on `jobs`
+    /// threads.
+    ///
+    /// The result is the same as calling `add_file` on each file in order.
+    /// Files that can't be indexed, for any of the reasons `add_file`
+    /// returns an error, are passed to `skipped` along with the error.
+    /// An error writing the index stops indexing and is returned, and so
+    /// does a thread panicking while it reads a file, as an error of kind
+    /// `WorkerFailed`.
+    ///
+    /// ```no_run
+    /// # use libcindex::writer::IndexWriter;
+    /// let mut index = IndexWriter::new("index").unwrap();
+    /// let files = vec!["/path/to/file1", "/path/to/file2"];
+    /// index.add_files(files, 4, |f, e| println!("{}: {}", f.display(), e)).unwrap();
+    /// index.flush().unwrap();
+    /// ```
+    pub fn add_files<I, F>(&mut self, files: I, jobs: usize, mut skipped: F) -> IndexResult<()>
+        where I: IntoIterator,
+              I::Item: Into<PathBuf>,
+              F: FnMut(&PathBuf, IndexError)
+    {
+        let _frame = libprofiling::profile("IndexWriter::add_files");
+        if jobs <= 1 {
+            let mut extractor = self.extractor();
+            for f in files {
+                let f = f.into();
+                match extractor.extract_file(&f) {
+                    Ok(t) => try!(self.add_trigrams(t)),
+                    Err(e) => {
+                        self.record_skip(&e);
+                        skipped(&f, e)
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        let (job_tx, job_rx) = mpsc::channel::<Job>();
+        let (result_tx, result_rx) = mpsc::channel::<JobResult>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let workers = (0..jobs)
+            .map(|_| {
+                let job_rx = job_rx.clone();
+                let result_tx = result_tx.clone();
+                let mut extractor = self.extractor();
+                thread::spawn(move || loop {
+                    let job = job_rx.lock().unwrap().recv();
+                    let (seq, f) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let t = panic::catch_unwind(AssertUnwindSafe(|| extractor.extract_file(&f)));
+                    let panicked = t.is_err();
+                    if result_tx.send((seq, f, t)).is_err() || panicked {
+                        break;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(result_tx);
+
+        let max_queued = jobs * QUEUE_PER_JOB;
+        let mut result = self.add_in_order(files, job_tx, result_rx, max_queued, &mut skipped);
+        for w in workers {
+            if let Err(e) = w.join() {
+                if result.is_ok() {
+                    result = Err(worker_failed(None, Some(e)));
+                }
+            }
+        }
+        result
+    }
+
+    /// Queues `files` for the workers and adds their results in order
+    fn add_in_order<I, F>(&mut self,
+                          files: I,
+                          job_tx: mpsc::Sender<Job>,
+                          result_rx: mpsc::Receiver<JobResult>,
+                          max_queued: usize,
+                          skipped: &mut F)
+                          -> IndexResult<()>
+        where I: IntoIterator,
+              I::Item: Into<PathBuf>,
+              F: FnMut(&PathBuf, IndexError)
+    {
+        let mut files = files.into_iter();
+        let mut queued = 0;
+        let mut added = 0;
+        // the files queued but not added yet, to name if the workers stop
+        let mut waiting = VecDeque::new();
+        let mut early = BTreeMap::new();
+        let mut job_tx = Some(job_tx);
+        loop {
+            while job_tx.is_some() && queued - added < max_queued {
+                match files.next() {
+                    Some(f) => {
+                        let f = f.into();
+                        waiting.push_back(f.clone());
+                        if let Err(mpsc::SendError((_, f))) = job_tx.as_ref()
+                            .unwrap()
+                            .send((queued, f)) {
+                            return Err(worker_failed(Some(&f), None));
+                        }
+                        queued += 1;
+                    }
+                    None => {
+                        // lets the workers stop once the queue is empty
+                        job_tx = None;
+                        break;
+                    }
+                }
+            }
+            if added == queued {
+                return Ok(());
+            }
+            let (seq, f, t) = match result_rx.recv() {
+                Ok(r) => r,
+                Err(_) => return Err(worker_failed(waiting.front().map(|f| f.as_path()), None)),
+            };
+            early.insert(seq, (f, t));
+            while let Some((f, t)) = early.remove(&added) {
+                added += 1;
+                waiting.pop_front();
+                match t {
+                    Ok(Ok(t)) => try!(self.add_trigrams(t)),
+                    Ok(Err(e)) => {
+                        self.record_skip(&e);
+                        skipped(&f, e)
+                    }
+                    Err(e) => return Err(worker_failed(Some(&f), Some(e))),
+                }
+            }
+        }
+    }
+}
+
+/// Returns the error for a worker that stopped while reading `file`, or
+/// that panicked with `panic`
+fn worker_failed(file: Option<&Path>, panic: Option<Box<Any + Send>>) -> IndexError {
+    let mut msg = match file {
+        Some(f) => format!("{}: indexing thread ", f.display()),
+        None => "indexing thread ".to_string(),
+    };
+    match panic {
+        Some(p) => {
+            let reason = p.downcast_ref::<&str>()
+                .cloned()
+                .or_else(|| p.downcast_ref::<String>().map(|s| &s[..]))
+                .unwrap_or("unknown reason");
+            msg.push_str("panicked: ");
+            msg.push_str(reason);
+        }
+        None => msg.push_str("stopped"),
+    }
+    IndexError::new(IndexErrorKind::WorkerFailed, msg)
+}
@@ -0,0 +1,89 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Block-compressed posting lists, the encoding used by version 2 indexes.
+// See libcsearch's reader/postlist.rs for a description of the format.
+
+use std::io::{self, Write};
+
+use byteorder::{BigEndian, WriteBytesExt};
+use consts::POST_BLOCK_SIZE;
+
+/// Writes the sorted file IDs in `ids` as a block-compressed posting list,
+/// not including its trigram.
+pub fn write_post_blocks<W: Write>(out: &mut W, ids: &[u32]) -> io::Result<()> {
+    let mut blocks = Vec::new();
+    let mut prev = None;
+    for block in ids.chunks(POST_BLOCK_SIZE) {
+        let last = block[block.len() - 1];
+        try!(out.write_u32::<BigEndian>(last));
+        try!(out.write_u32::<BigEndian>(blocks.len() as u32));
+        encode_block(&mut blocks, block, prev);
+        prev = Some(last);
+    }
+    out.write_all(&blocks)
+}
+
+/// Appends one block of file IDs to `out`. `prev` is the last file ID of
+/// the previous block, if there is one.
+fn encode_block(out: &mut Vec<u8>, block: &[u32], prev: Option<u32>) {
+    let mut deltas = Vec::with_capacity(block.len());
+    let mut last = prev;
+    for &id in block {
+        deltas.push(match last {
+            Some(l) => id - l,
+            None => id,
+        });
+        last = Some(id);
+    }
+    let width = 32 - deltas.iter().fold(0, |a, &d| a | d).leading_zeros() as usize;
+    out.push(width as u8);
+
+    let mut acc = 0u64;
+    let mut bits = 0;
+    for d in deltas {
+        acc |= (d as u64) << bits;
+        bits += width;
+        while bits >= 8 {
+            out.push(acc as u8);
+            acc >>= 8;
+            bits -= 8;
+        }
+    }
+    if bits > 0 {
+        out.push(acc as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_block() {
+        let mut out = Vec::new();
+        encode_block(&mut out, &[1, 6, 7, 8], None);
+        // deltas 1, 5, 1, 1 in 3 bits each
+        assert_eq!(out, vec![3, 0b01_101_001, 0b0000_001_0]);
+
+        let mut out = Vec::new();
+        encode_block(&mut out, &[9], Some(8));
+        assert_eq!(out, vec![1, 1]);
+    }
+
+    #[test]
+    fn test_write_post_blocks() {
+        let ids = (0..300).map(|i| i * 2).collect::<Vec<u32>>();
+        let mut out = Vec::new();
+        write_post_blocks(&mut out, &ids).unwrap();
+        // 3 skip entries, then blocks of 128, 128 and 44 deltas
+        let block0 = 1 + (128 * 2 + 7) / 8;
+        let block1 = 1 + (128 * 2 + 7) / 8;
+        let block2 = 1 + (44 * 2 + 7) / 8;
+        assert_eq!(out.len(), 3 * 8 + block0 + block1 + block2);
+        assert_eq!(&out[..8], &[0, 0, 0, 254, 0, 0, 0, 0]);
+        assert_eq!(&out[8..16], &[0, 0, 1, 254, 0, 0, 0, block0 as u8]);
+    }
+}
@@ -1,22 +1,59 @@
+// Merging sorted runs of post entries.
+//
+// IndexWriter sorts its post entries and spills them to a temporary file
+// whenever it reaches its memory budget, so at the end there are a number of
+// sorted runs on disk plus one in memory.  PostHeap merges them into a single
+// sorted stream, keeping the runs in a min-heap ordered by their next entry.
+
 use std::vec;
 
+use byteorder::{BigEndian, ByteOrder};
 use libprofiling;
 
 use super::postentry::PostEntry;
 
-struct PostChunk {
+/// Size of a post entry spilled to disk
+pub const POST_ENTRY_DISK_SIZE: usize = 8;
+
+/// The remaining entries of a sorted run
+enum Run<'a> {
+    Mem(vec::IntoIter<PostEntry>),
+    /// Big-endian entries from a spill file
+    Disk(&'a [u8]),
+}
+
+impl<'a> Iterator for Run<'a> {
+    type Item = PostEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        match *self {
+            Run::Mem(ref mut it) => it.next(),
+            Run::Disk(ref mut d) => {
+                if d.len() < POST_ENTRY_DISK_SIZE {
+                    return None;
+                }
+                let e = PostEntry(BigEndian::read_u64(d));
+                *d = &d[POST_ENTRY_DISK_SIZE..];
+                Some(e)
+            }
+        }
+    }
+}
+
+struct PostChunk<'a> {
     e: PostEntry,
-    m: vec::IntoIter<PostEntry>,
+    m: Run<'a>,
     size: usize,
 }
 
-impl PostChunk {
-    pub fn new(v: Vec<PostEntry>) -> Option<PostChunk> {
+impl<'a> PostChunk<'a> {
+    pub fn new(v: Vec<PostEntry>) -> Option<PostChunk<'a>> {
         let size = v.len();
+        PostChunk::from_run(Run::Mem(v.into_iter()), size)
+    }
+    fn from_run(mut m: Run<'a>, size: usize) -> Option<PostChunk<'a>> {
         if size == 0 {
             None
         } else {
-            let mut m = v.into_iter();
             let e = m.next().unwrap();
             Some(PostChunk {
                 e: e,
@@ -34,7 +71,7 @@ impl PostChunk {
     }
 }
 
-impl Iterator for PostChunk {
+impl<'a> Iterator for PostChunk<'a> {
     type Item = PostEntry;
     fn next(&mut self) -> Option<Self::Item> {
         if self.is_empty() {
@@ -52,12 +89,12 @@ impl Iterator for PostChunk {
     }
 }
 
-pub struct PostHeap {
-    ch: Vec<PostChunk>,
+pub struct PostHeap<'a> {
+    ch: Vec<PostChunk<'a>>,
 }
 
-impl PostHeap {
-    pub fn new() -> PostHeap {
+impl<'a> PostHeap<'a> {
+    pub fn new() -> PostHeap<'a> {
         PostHeap { ch: Vec::new() }
     }
     pub fn add_mem(&mut self, v: Vec<PostEntry>) {
@@ -66,12 +103,19 @@ impl PostHeap {
             self.add(p);
         }
     }
-    fn add(&mut self, ch: PostChunk) {
+    /// Adds a sorted run spilled to disk, `d` holding its big-endian entries
+    pub fn add_disk(&mut self, d: &'a [u8]) {
+        let size = d.len() / POST_ENTRY_DISK_SIZE;
+        if let Some(p) = PostChunk::from_run(Run::Disk(d), size) {
+            self.add(p);
+        }
+    }
+    fn add(&mut self, ch: PostChunk<'a>) {
         if !ch.is_empty() {
             self.push(ch);
         }
     }
-    fn push(&mut self, ch: PostChunk) {
+    fn push(&mut self, ch: PostChunk<'a>) {
         let n = self.ch.len();
         self.ch.push(ch);
         if self.ch.len() >= 2 {
@@ -111,42 +155,39 @@ impl PostHeap {
     }
 }
 
-impl IntoIterator for PostHeap {
+impl<'a> IntoIterator for PostHeap<'a> {
     type Item = PostEntry;
-    type IntoIter = IntoIter;
+    type IntoIter = IntoIter<'a>;
     fn into_iter(self) -> Self::IntoIter {
         IntoIter::new(self)
     }
 }
 
-pub struct IntoIter {
-    inner: PostHeap,
-    place: usize,
+pub struct IntoIter<'a> {
+    inner: PostHeap<'a>,
 }
 
-impl IntoIter {
-    pub fn new(inner: PostHeap) -> Self {
-        IntoIter {
-            inner: inner,
-            place: 0,
-        }
+impl<'a> IntoIter<'a> {
+    pub fn new(inner: PostHeap<'a>) -> Self {
+        IntoIter { inner: inner }
     }
 }
 
-impl Iterator for IntoIter {
+impl<'a> Iterator for IntoIter<'a> {
     type Item = PostEntry;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.place < self.inner.ch.len() {
-            let e = self.inner.ch[self.place].next();
-            if self.inner.ch[self.place].is_empty() {
-                self.place += 1;
-            } else {
-                self.inner.sift_down(self.place);
-            }
-            e
-        } else {
-            None
+        if self.inner.ch.is_empty() {
+            return None;
         }
+        let e = self.inner.ch[0].next();
+        if self.inner.ch[0].is_empty() {
+            // pop the exhausted run, moving the last one to the top
+            self.inner.ch.swap_remove(0);
+        }
+        if !self.inner.ch.is_empty() {
+            self.inner.sift_down(0);
+        }
+        e
     }
 }
 
@@ -217,4 +258,37 @@ mod tests {
         p.add_mem(v2.clone());
         assert!(p.into_iter().collect::<Vec<_>>() == v_comb);
     }
+
+    #[test]
+    fn test_postheap_many_runs() {
+        // more runs than fit in the first two levels of the heap
+        let runs = (0..7u32)
+            .map(|r| (0..20u32).map(|i| PostEntry::new(i % 5, i * 7 + r)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut want = runs.iter().flat_map(|r| r.iter().cloned()).collect::<Vec<_>>();
+        want.sort();
+        let mut p = PostHeap::new();
+        for mut r in runs {
+            r.sort();
+            p.add_mem(r);
+        }
+        assert_eq!(p.into_iter().collect::<Vec<_>>(), want);
+    }
+
+    #[test]
+    fn test_postheap_disk_runs() {
+        use byteorder::{BigEndian, WriteBytesExt};
+        let mut d = Vec::new();
+        for e in &[PostEntry::new(1, 2), PostEntry::new(3, 0)] {
+            d.write_u64::<BigEndian>(e.value()).unwrap();
+        }
+        let mut p = PostHeap::new();
+        p.add_disk(&d);
+        p.add_mem(vec![PostEntry::new(1, 1), PostEntry::new(2, 0)]);
+        assert_eq!(p.into_iter().collect::<Vec<_>>(),
+                   vec![PostEntry::new(1, 1),
+                        PostEntry::new(1, 2),
+                        PostEntry::new(2, 0),
+                        PostEntry::new(3, 0)]);
+    }
 }
@@ -46,6 +46,8 @@ impl<'a, I: 'a + Iterator<Item = PostEntry>> Iterator for TakeWhilePeek<'a, I> {
     }
 }
 
+// Deltas in the version 1 varint encoding; version 2 lists are written
+// by write_post_blocks instead.
 pub type DiffIter<I> = Chain<Scan<I, u32, fn(&mut u32, u32) -> Option<u32>>, Once<u32>>;
 
 pub fn to_diffs<'a, I: 'a + Iterator<Item = u32>>(it: I) -> DiffIter<I> {
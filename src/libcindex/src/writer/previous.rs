@@ -0,0 +1,104 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Reusing the files of a previous version of an index.
+//
+// Reindexing mostly finds the same files as last time, so rather than read
+// each of them again, a file whose modification time and size match the
+// ones recorded in the previous index is added without being opened: its
+// name and metadata are copied as it's added, and its posting entries are
+// copied from the previous index's posting lists in one pass when the new
+// index is flushed.  They go into runs of their own that are merged with
+// the rest, so reused and newly read files can be added in any order.
+//
+// Files are only reused if the previous index was built with the same
+// options and root, since they decide which files get indexed and how
+// their names are stored.
+//
+// A file can be rewritten without its modification time changing, if it's
+// within the filesystem's timestamp granularity of the last write, so a
+// file modified after the previous index was started (or in the same
+// second, which is all the index records) is read again even if its
+// modification time and size are the same.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use consts::SECTION_ROOT;
+use libcsearch::pathbytes;
+use libcsearch::reader::{FileMeta, IndexReader};
+
+use super::options::{IndexOptions, StoredOptions};
+
+/// A previous version of an index, see `IndexWriter::set_previous`
+pub struct PreviousIndex {
+    ix: IndexReader,
+    /// ID of each file with metadata, by its stored name
+    files: HashMap<Vec<u8>, u32>,
+    /// The file whose posting entries each duplicate shares
+    first_copies: HashMap<u32, u32>,
+    options: Option<IndexOptions>,
+    /// When the index was started
+    created: SystemTime,
+}
+
+impl PreviousIndex {
+    pub fn new(ix: IndexReader) -> PreviousIndex {
+        let files = (0..ix.num_name as u32)
+            .filter(|&id| ix.file_meta(id).is_some())
+            .map(|id| (ix.stored_name(id), id))
+            .collect();
+        let first_copies = ix.all_duplicates()
+            .into_iter()
+            .map(|(first, dup)| (dup, first))
+            .collect();
+        let (options, created) = match StoredOptions::read(&ix) {
+            Ok(Some(stored)) => (Some(stored.options), stored.created),
+            _ => (None, UNIX_EPOCH),
+        };
+        PreviousIndex {
+            ix: ix,
+            files: files,
+            first_copies: first_copies,
+            options: options,
+            created: created,
+        }
+    }
+
+    pub fn index(&self) -> &IndexReader {
+        &self.ix
+    }
+
+    /// Returns true if files added with `options` and `root` would be
+    /// indexed the same way they were in this index
+    pub fn matches(&self, options: &IndexOptions, root: Option<&Path>) -> bool {
+        let root = root.map(|r| pathbytes::to_bytes(r.as_os_str()).into_owned());
+        self.options.as_ref() == Some(options) &&
+        self.ix.section(SECTION_ROOT).map(|r| r.to_vec()) == root
+    }
+
+    /// Returns the ID and metadata of the file stored as `name`, if its
+    /// modification time and size are the same as in `meta`, and it was
+    /// last modified before this index was started
+    pub fn unchanged(&self, name: &[u8], meta: &FileMeta) -> Option<(u32, FileMeta)> {
+        let id = match self.files.get(name) {
+            Some(&id) => id,
+            None => return None,
+        };
+        match self.ix.file_meta(id) {
+            Some(old) if old.mtime_sec == meta.mtime_sec && old.mtime_nsec == meta.mtime_nsec &&
+                         old.size == meta.size &&
+                         old.modified() < self.created => Some((id, old)),
+            _ => None,
+        }
+    }
+
+    /// Returns the file whose posting lists `id` is in: `id`, or the file
+    /// it's a duplicate of
+    pub fn postings_of(&self, id: u32) -> u32 {
+        self.first_copies.get(&id).cloned().unwrap_or(id)
+    }
+}
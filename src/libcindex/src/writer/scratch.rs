@@ -0,0 +1,90 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Scratch space for the parts of an index that are built up while files
+// are added and copied into the index when it's flushed: the names, file
+// metadata, name index, posting list index and spilled post entries.
+//
+// It's an anonymous temporary file, or memory for an index built without
+// touching the filesystem (see `IndexWriter::in_memory`).
+
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+
+use memmap::Mmap;
+use tempfile::tempfile;
+
+pub enum Scratch {
+    File(File),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Scratch {
+    pub fn new(in_memory: bool) -> io::Result<Scratch> {
+        if in_memory {
+            Ok(Scratch::Memory(Cursor::new(Vec::new())))
+        } else {
+            Ok(Scratch::File(try!(tempfile())))
+        }
+    }
+
+    /// Returns everything written, to be read back in place
+    pub fn into_map(self) -> io::Result<ScratchMap> {
+        match self {
+            Scratch::File(f) => Ok(ScratchMap::Mapped(try!(unsafe { Mmap::map(&f) }))),
+            Scratch::Memory(c) => Ok(ScratchMap::Memory(c.into_inner())),
+        }
+    }
+}
+
+impl Read for Scratch {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Scratch::File(ref mut f) => f.read(buf),
+            Scratch::Memory(ref mut c) => c.read(buf),
+        }
+    }
+}
+
+impl Write for Scratch {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Scratch::File(ref mut f) => f.write(buf),
+            Scratch::Memory(ref mut c) => c.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Scratch::File(ref mut f) => f.flush(),
+            Scratch::Memory(ref mut c) => c.flush(),
+        }
+    }
+}
+
+impl Seek for Scratch {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match *self {
+            Scratch::File(ref mut f) => f.seek(pos),
+            Scratch::Memory(ref mut c) => c.seek(pos),
+        }
+    }
+}
+
+/// The contents of a `Scratch`, mapped into memory if they're in a file
+pub enum ScratchMap {
+    Mapped(Mmap),
+    Memory(Vec<u8>),
+}
+
+impl Deref for ScratchMap {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match *self {
+            ScratchMap::Mapped(ref m) => m,
+            ScratchMap::Memory(ref v) => v,
+        }
+    }
+}
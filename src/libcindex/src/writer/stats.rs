@@ -0,0 +1,207 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fmt;
+use std::time::Duration;
+
+use super::error::IndexErrorKind;
+
+/// Number of posting lists kept in `IndexStats::largest_posting_lists`
+pub const LARGEST_POSTING_LISTS: usize = 10;
+
+/// What happened while an `IndexWriter` wrote an index, returned by
+/// `IndexWriter::flush`.
+///
+/// Only files given to the writer are counted; files a caller never added
+/// (e.g. excluded while walking a directory) don't show up as skipped.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexStats {
+    /// Number of files added to the index
+    pub files_indexed: u64,
+    /// Number of the files added that weren't read because they hadn't
+    /// changed since the previous index, see `IndexWriter::set_previous`
+    pub files_reused: u64,
+    /// Number of the files added that were identical to one added before
+    /// them, and so share its posting entries
+    pub duplicate_files: u64,
+    /// Number of files that weren't added, by the reason why
+    pub files_skipped: BTreeMap<IndexErrorKind, u64>,
+    /// Total size of the files read
+    pub bytes_read: u64,
+    /// Number of distinct trigrams, i.e. of posting lists
+    pub distinct_trigrams: u64,
+    /// Size of the index file
+    pub index_bytes: u64,
+    /// The trigrams with the longest posting lists and the number of files
+    /// in each, longest first
+    pub largest_posting_lists: Vec<(u32, u32)>,
+    /// Time from creating the writer until `flush` was called, which is
+    /// mostly spent adding files
+    pub add_time: Duration,
+    /// Time spent sorting posting entries and spilling them to disk, while
+    /// adding files
+    pub spill_time: Duration,
+    /// Time spent merging the spilled entries and writing the posting
+    /// lists, during `flush`
+    pub merge_time: Duration,
+    /// Total time spent in `flush`
+    pub flush_time: Duration,
+}
+
+impl IndexStats {
+    /// Returns the number of files that weren't added, for any reason
+    pub fn total_skipped(&self) -> u64 {
+        self.files_skipped.values().sum()
+    }
+
+    /// Counts a file that wasn't added because of an error of kind `kind`
+    pub fn record_skip(&mut self, kind: IndexErrorKind) {
+        *self.files_skipped.entry(kind).or_insert(0) += 1;
+    }
+
+    /// Formats the statistics as a JSON object
+    pub fn to_json(&self) -> String {
+        let skipped = self.files_skipped
+            .iter()
+            .map(|(kind, n)| format!("{}: {}", json_string(format!("{:?}", kind).as_bytes()), n))
+            .collect::<Vec<_>>();
+        let largest = self.largest_posting_lists
+            .iter()
+            .map(|&(t, n)| format!("{{\"trigram\": {}, \"files\": {}}}",
+                                   json_string(&trigram_bytes(t)),
+                                   n))
+            .collect::<Vec<_>>();
+        format!("{{\"files_indexed\": {}, \"files_reused\": {}, \"duplicate_files\": {}, \
+                 \"files_skipped\": {{{}}}, \"bytes_read\": {}, \"distinct_trigrams\": {}, \"index_bytes\": {}, \
+                 \"largest_posting_lists\": [{}], \"add_seconds\": {}, \"spill_seconds\": {}, \
+                 \"merge_seconds\": {}, \"flush_seconds\": {}}}",
+                self.files_indexed,
+                self.files_reused,
+                self.duplicate_files,
+                skipped.join(", "),
+                self.bytes_read,
+                self.distinct_trigrams,
+                self.index_bytes,
+                largest.join(", "),
+                secs(self.add_time),
+                secs(self.spill_time),
+                secs(self.merge_time),
+                secs(self.flush_time))
+    }
+}
+
+impl fmt::Display for IndexStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "files indexed:       {}", self.files_indexed));
+        try!(writeln!(f, "  reused:            {}", self.files_reused));
+        try!(writeln!(f, "  duplicates:        {}", self.duplicate_files));
+        try!(writeln!(f, "files skipped:       {}", self.total_skipped()));
+        for (kind, n) in &self.files_skipped {
+            try!(writeln!(f, "  {:?}: {}", kind, n));
+        }
+        try!(writeln!(f, "bytes read:          {}", self.bytes_read));
+        try!(writeln!(f, "distinct trigrams:   {}", self.distinct_trigrams));
+        try!(writeln!(f, "index bytes:         {}", self.index_bytes));
+        try!(writeln!(f, "largest posting lists:"));
+        for &(t, n) in &self.largest_posting_lists {
+            try!(writeln!(f, "  {} {} files", json_string(&trigram_bytes(t)), n));
+        }
+        try!(writeln!(f, "adding files:        {:.3}s", secs(self.add_time)));
+        try!(writeln!(f, "  spilling runs:     {:.3}s", secs(self.spill_time)));
+        try!(writeln!(f, "flushing:            {:.3}s", secs(self.flush_time)));
+        write!(f, "  merging runs:      {:.3}s", secs(self.merge_time))
+    }
+}
+
+/// Keeps the `LARGEST_POSTING_LISTS` longest posting lists seen, preferring
+/// the lowest trigrams among lists of the same length
+pub struct LargestLists {
+    /// (count, trigram), with the list to drop next on top
+    heap: BinaryHeap<Reverse<(u32, Reverse<u32>)>>,
+}
+
+impl LargestLists {
+    pub fn new() -> LargestLists {
+        LargestLists { heap: BinaryHeap::with_capacity(LARGEST_POSTING_LISTS + 1) }
+    }
+
+    pub fn add(&mut self, trigram: u32, count: u32) {
+        self.heap.push(Reverse((count, Reverse(trigram))));
+        if self.heap.len() > LARGEST_POSTING_LISTS {
+            self.heap.pop();
+        }
+    }
+
+    /// Returns (trigram, count) pairs, longest first
+    pub fn into_vec(self) -> Vec<(u32, u32)> {
+        let mut v = self.heap
+            .into_iter()
+            .map(|Reverse((n, Reverse(t)))| (t, n))
+            .collect::<Vec<_>>();
+        v.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        v
+    }
+}
+
+fn trigram_bytes(t: u32) -> [u8; 3] {
+    [(t >> 16) as u8, (t >> 8) as u8, t as u8]
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+/// Quotes `s` as a JSON string.  Bytes that aren't printable ASCII are
+/// written as the code point with the same value.
+fn json_string(s: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in s {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            0x20..=0x7e => out.push(b as char),
+            _ => out.push_str(&format!("\\u{:04x}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use writer::error::IndexErrorKind;
+
+    #[test]
+    fn test_largest_lists() {
+        let mut l = LargestLists::new();
+        for t in 0..100 {
+            l.add(t, (t * 37) % 101);
+        }
+        let v = l.into_vec();
+        assert_eq!(v.len(), LARGEST_POSTING_LISTS);
+        assert_eq!(v[0].1, 100);
+        assert!(v.windows(2).all(|w| w[0].1 >= w[1].1));
+    }
+
+    #[test]
+    fn test_json() {
+        let mut stats = IndexStats::default();
+        stats.files_indexed = 2;
+        stats.record_skip(IndexErrorKind::BinaryDataPresent);
+        stats.record_skip(IndexErrorKind::BinaryDataPresent);
+        stats.largest_posting_lists = vec![(0x0a2211, 2)];
+        assert_eq!(stats.total_skipped(), 2);
+        assert_eq!(stats.to_json(),
+                   "{\"files_indexed\": 2, \"files_reused\": 0, \"duplicate_files\": 0, \
+                    \"files_skipped\": {\"BinaryDataPresent\": 2}, \"bytes_read\": 0, \
+                    \"distinct_trigrams\": 0, \"index_bytes\": 0, \
+                    \"largest_posting_lists\": [{\"trigram\": \"\\u000a\\\"\\u0011\", \
+                    \"files\": 2}], \"add_seconds\": 0, \"spill_seconds\": 0, \
+                    \"merge_seconds\": 0, \"flush_seconds\": 0}");
+    }
+}
@@ -1,121 +1,59 @@
-use std::io::{self, Read, BufReader};
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
 
-use super::error::{IndexResult, IndexError, IndexErrorKind};
-
-
-/// Yields 24-bit trigrams of characters from a reader
+/// Collects the 24-bit trigrams of characters from a file, given to it as a
+/// series of byte slices
 ///
-/// The Iterator interface is implemented to allow iteration over the trigrams.
-/// The `.next()` method has a slight quirk, in that if an error occurs, iteration
-/// stops immediately and the error is stored in an internal variable that can be
-/// queried by the `.error()` method. This is because this iteration is a fairly
-/// tight loop, and flattening the return of the `.next()` method from
-/// `Option<Result<u32>>` to `Option<u32>` resulted in a respectable speedup.
-pub struct TrigramReader<R: Read> {
-    reader: io::Bytes<BufReader<R>>,
+/// The file can be read in chunks of any size, or mapped into memory and
+/// scanned all at once; either way the trigrams are the same.  Trigrams
+/// ending in a pair of bytes that isn't valid UTF-8 are left out.  Whether
+/// to skip the file altogether is up to the filters, see filter.rs.
+#[derive(Default)]
+pub struct TrigramScanner {
     current_value: u32,
     num_read: usize,
-
-    inv_cnt: u64,
-    max_invalid: u64,
-
-    line_len: u64,
-    max_line_len: u64,
-
-    error: Option<IndexResult<()>>,
 }
 
-impl<R: Read> TrigramReader<R> {
-    /// If an error occurred during reading, extracts it into an option
-    pub fn take_error(&mut self) -> Option<IndexResult<()>> {
-        self.error.take()
-    }
-    pub fn new(r: R, max_invalid: u64, max_line_len: u64) -> TrigramReader<R> {
-        TrigramReader {
-            reader: BufReader::with_capacity(16384, r).bytes(),
-            current_value: 0,
-            num_read: 0,
-            inv_cnt: 0,
-            max_invalid: max_invalid,
-            line_len: 0,
-            max_line_len: max_line_len,
-            error: None,
-        }
+impl TrigramScanner {
+    pub fn new() -> TrigramScanner {
+        TrigramScanner::default()
     }
-    fn next_char(&mut self) -> Option<u8> {
-        match self.reader.next() {
-            Some(Err(e)) => {
-                self.error = Some(Err(e.into()));
-                None
-            }
-            Some(Ok(c)) => {
-                self.num_read += 1;
-                Some(c)
-            }
-            None => None,
-        }
-    }
-}
 
-impl<R: Read> Iterator for TrigramReader<R> {
-    type Item = u32;
-    fn next(&mut self) -> Option<Self::Item> {
-        let c = match self.next_char() {
-            Some(c) => c,
-            _ => return None,
-        };
-        self.current_value = ((1 << 24) - 1) & ((self.current_value << 8) | (c as u32));
-        if self.num_read < 3 {
-            return self.next();
+    /// Calls `f` with each trigram in `buf`, the next chunk of the file.
+    /// Trigrams that span chunks are found too, since the last two bytes
+    /// of each chunk are carried over to the next.
+    pub fn scan<F: FnMut(u32)>(&mut self, buf: &[u8], mut f: F) {
+        let mut buf = buf;
+        // the first two bytes of the file don't make a trigram yet
+        while self.num_read < 2 {
+            match buf.split_first() {
+                Some((&c, rest)) => {
+                    self.current_value = (self.current_value << 8) | (c as u32);
+                    self.num_read += 1;
+                    buf = rest;
+                }
+                None => return,
+            }
         }
 
-        let b1 = ((self.current_value >> 8) & 0xff) as u8;
-        let b2 = (self.current_value & 0xff) as u8;
-        if b1 == 0x00 || b2 == 0x00 {
-            // Binary file. Skip
-            self.error = Some(Err(IndexError::new(IndexErrorKind::BinaryDataPresent,
-                                                  format!("Binary File. Bytes {:02x}{:02x} at \
-                                                           offset {}",
-                                                          b1,
-                                                          b2,
-                                                          self.num_read))));
-            None
-        } else if !valid_utf8(b1, b2) {
-            // invalid utf8 data
-            self.inv_cnt += 1;
-            if self.inv_cnt > self.max_invalid {
-                let e = IndexError::new(IndexErrorKind::HighInvalidUtf8Ratio,
-                                        format!("High invalid UTF-8 ratio. total {} invalid: {} \
-                                                 ratio: {}",
-                                                self.num_read,
-                                                self.inv_cnt,
-                                                (self.inv_cnt as f64) / (self.num_read as f64)));
-                self.error = Some(Err(e));
-                None
-            } else {
-                // skip invalid character
-                self.next()
-            }
-        } else if self.line_len > self.max_line_len {
-            let e = IndexError::new(IndexErrorKind::LineTooLong,
-                                    format!("Line too long ({} > {})",
-                                            self.line_len,
-                                            self.max_line_len));
-            self.error = Some(Err(e));
-            None
-        } else {
-            if c == ('\n' as u8) {
-                self.line_len = 0;
-            } else {
-                self.line_len += 1;
+        let mut tv = self.current_value;
+        for &c in buf {
+            tv = ((1 << 24) - 1) & ((tv << 8) | (c as u32));
+            let b1 = ((tv >> 8) & 0xff) as u8;
+            // ASCII is by far the most common case
+            if (b1 | c) < 0x80 || valid_utf8(b1, c) {
+                f(tv);
             }
-            Some(self.current_value)
         }
-
+        self.current_value = tv;
+        self.num_read += buf.len();
     }
 }
 
-fn valid_utf8(c1: u8, c2: u8) -> bool {
+/// Returns true if `c2` can follow `c1` in UTF-8 text
+pub fn valid_utf8(c1: u8, c2: u8) -> bool {
     if c1 < 0x80 {
         // 1-byte, must be followed by 1-byte or first of multi-byte
         (c2 < 0x80) || (0xc0 <= c2) && (c2 < 0xf8)
@@ -130,18 +68,55 @@ fn valid_utf8(c1: u8, c2: u8) -> bool {
     }
 }
 
-#[test]
-fn test_trigram_iter_once() {
-    let c = TrigramReader::new("hello".as_bytes(), 0, 100).next().unwrap();
-    let hel = ('h' as u32) << 16 | ('e' as u32) << 8 | ('l' as u32);
-    assert_eq!(c, hel);
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tri(s: &[u8]) -> u32 {
+        (s[0] as u32) << 16 | (s[1] as u32) << 8 | (s[2] as u32)
+    }
+
+    fn scan_chunks(chunks: &[&[u8]]) -> Vec<u32> {
+        let mut s = TrigramScanner::new();
+        let mut trigrams = Vec::new();
+        for c in chunks {
+            s.scan(c, |t| trigrams.push(t));
+        }
+        trigrams
+    }
+
+    #[test]
+    fn test_trigram_scan() {
+        let trigrams = scan_chunks(&[b"hello"]);
+        assert_eq!(trigrams, vec![tri(b"hel"), tri(b"ell"), tri(b"llo")]);
+        assert_eq!(scan_chunks(&[b"he"]), vec![]);
+        assert_eq!(scan_chunks(&[]), vec![]);
+    }
 
-#[test]
-pub fn test_trigram_iter() {
-    let trigrams: Vec<u32> = TrigramReader::new("hello".as_bytes(), 0, 100).collect();
-    let hel = ('h' as u32) << 16 | ('e' as u32) << 8 | ('l' as u32);
-    let ell = ('e' as u32) << 16 | ('l' as u32) << 8 | ('l' as u32);
-    let llo = ('l' as u32) << 16 | ('l' as u32) << 8 | ('o' as u32);
-    assert_eq!(trigrams, vec![hel, ell, llo]);
+    #[test]
+    fn test_trigram_scan_chunks() {
+        let data = "line one\nligne d\u{e9}ux\n\u{20ac}3\n".as_bytes();
+        let whole = scan_chunks(&[data]);
+        for i in 0..data.len() + 1 {
+            for j in i..data.len() + 1 {
+                let split = scan_chunks(&[&data[..i], &data[i..j], &data[j..]]);
+                assert_eq!(whole, split, "split at {} and {}", i, j);
+            }
+        }
+    }
+
+    #[test]
+    fn test_trigram_scan_invalid_utf8() {
+        // both trigrams with the byte in the middle are left out
+        assert_eq!(scan_chunks(&[b"ab\xffcd"]), vec![tri(b"\xffcd")]);
+    }
+
+    #[test]
+    fn test_trigram_scan_long_invalid_run() {
+        // each invalid byte used to be skipped with a recursive call
+        let mut data = b"ab".to_vec();
+        data.extend(vec![0xff; 1 << 22]);
+        data.extend_from_slice(b"cd");
+        assert_eq!(scan_chunks(&[&data]), vec![tri(b"\xffcd")]);
+    }
 }
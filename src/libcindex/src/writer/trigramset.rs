@@ -0,0 +1,136 @@
+// Copyright 2016 Vernon Jones. All rights reserved.
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+#![allow(dead_code)]
+use std::mem;
+
+/// Number of distinct trigrams
+const MAX_SIZE: usize = 1 << 24;
+const STARTING_DENSE_SIZE: usize = 10000;
+
+/// A set of trigrams, for collecting the distinct trigrams of a file
+///
+/// Membership is kept in a bitmap with a bit for every possible trigram,
+/// 2 MB in all, which isn't allocated until the first insert.  The members
+/// are also kept in a list, in the order they were inserted, so clearing the
+/// set only touches the bits that were set.
+pub struct TrigramSet {
+    bits: Vec<u64>,
+    dense: Vec<u32>,
+}
+
+impl TrigramSet {
+    pub fn new() -> TrigramSet {
+        TrigramSet {
+            bits: Vec::new(),
+            dense: Vec::new(),
+        }
+    }
+    pub fn insert(&mut self, x: u32) {
+        if self.bits.is_empty() {
+            self.bits = vec![0; MAX_SIZE / 64];
+            self.dense.reserve(STARTING_DENSE_SIZE);
+        }
+        let (word, bit) = ((x >> 6) as usize, 1 << (x & 63));
+        if self.bits[word] & bit == 0 {
+            self.bits[word] |= bit;
+            self.dense.push(x);
+        }
+    }
+    pub fn contains(&self, x: u32) -> bool {
+        let word = (x >> 6) as usize;
+        word < self.bits.len() && self.bits[word] & (1 << (x & 63)) != 0
+    }
+    pub fn clear(&mut self) {
+        self.clear_bits();
+        self.dense.clear();
+    }
+    pub fn len(&self) -> usize {
+        self.dense.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.dense.is_empty()
+    }
+    /// Returns the members in the order they were inserted, leaving the
+    /// set empty
+    pub fn take_dense(&mut self) -> Vec<u32> {
+        self.clear_bits();
+        mem::replace(&mut self.dense, Vec::with_capacity(STARTING_DENSE_SIZE))
+    }
+    fn clear_bits(&mut self) {
+        for &x in &self.dense {
+            self.bits[(x >> 6) as usize] = 0;
+        }
+    }
+}
+
+#[test]
+fn test_init() {
+    let s = TrigramSet::new();
+    assert!(s.len() == 0);
+    assert!(s.is_empty());
+    assert!(!s.contains(5));
+}
+
+#[test]
+fn test_insert() {
+    let mut s = TrigramSet::new();
+    s.insert(5);
+    assert!(s.len() == 1);
+}
+
+#[test]
+fn test_insert_mult_unique() {
+    let mut s = TrigramSet::new();
+    for each in 0..10 {
+        s.insert(each);
+    }
+    assert!(s.len() == 10);
+}
+
+#[test]
+fn test_insert_overlapping() {
+    let mut s = TrigramSet::new();
+    s.insert(1);
+    s.insert(1);
+    s.insert((1 << 24) - 1);
+    s.insert((1 << 24) - 1);
+    assert!(s.len() == 2);
+}
+
+#[test]
+fn test_contains() {
+    let mut s = TrigramSet::new();
+    s.insert(1);
+    assert!(s.contains(1));
+    assert!(!s.contains(2));
+}
+
+#[test]
+fn test_take_dense() {
+    let mut s = TrigramSet::new();
+    s.insert(5);
+    s.insert(10);
+    s.insert(0);
+    s.insert(10);
+    assert!(s.take_dense() == vec![5, 10, 0]);
+    assert!(s.is_empty());
+    assert!(!s.contains(5));
+    s.insert(10);
+    s.insert(11);
+    assert!(s.take_dense() == vec![10, 11]);
+}
+
+#[test]
+fn test_clear() {
+    let mut s = TrigramSet::new();
+    s.insert(70);
+    s.insert(71);
+    s.clear();
+    assert!(s.is_empty());
+    assert!(!s.contains(70) && !s.contains(71));
+    s.insert(71);
+    assert!(s.take_dense() == vec![71]);
+}
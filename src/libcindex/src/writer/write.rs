@@ -8,27 +8,40 @@
 
 #![allow(dead_code)]
 use std::fs::File;
-use std::path::Path;
-use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::io::{self, BufWriter, Cursor, Read, Seek, Write};
 use std::ffi::OsString;
+use std::cmp;
+use std::collections::HashMap;
 use std::mem;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 
-use libvarint;
-use tempfile::tempfile;
 use byteorder::{BigEndian, WriteBytesExt};
 use libprofiling;
 
-use consts::{MAGIC, TRAILER_MAGIC};
+use consts::{MAGIC, MAGIC_V2, SECTION_FILE_META, SECTION_CHECKSUMS, SECTION_ROOT,
+             SECTION_OPTIONS, SECTION_DUPLICATES, TRAILER_MAGIC};
+use consts::{SECTION_PATHS, SECTION_NAMES, SECTION_POSTING_LISTS, SECTION_NAME_INDEX,
+             SECTION_POSTING_INDEX};
+use libcsearch::pathbytes;
+use libcsearch::reader::{FileMeta, IndexReader, IndexVersion};
 
-use super::sparseset::SparseSet;
+use super::extract::{ExtractSettings, FileTrigrams, TrigramExtractor, stored_name};
 use super::error::{IndexError, IndexErrorKind, IndexResult};
-use super::{WriteTrigram, copy_file, get_offset};
-use super::postinglist::{to_diffs, TakeWhilePeek};
+use super::filter::FileFilter;
+use super::scratch::Scratch;
+use super::stats::{IndexStats, LargestLists};
+use super::options::{IndexOptions, StoredOptions, TOOL_VERSION};
+use super::previous::PreviousIndex;
+use super::{HashWriter, Section, copy_file, get_offset, take_checksum, write_checksums,
+            write_duplicates, write_section_table};
+use super::format::{NameWriter, write_post_entry, write_post_list, write_trailer};
+use super::postinglist::TakeWhilePeek;
 use super::postentry::PostEntry;
-use super::postheap::PostHeap;
-use super::trigramiter::TrigramReader;
+use super::postheap::{PostHeap, POST_ENTRY_DISK_SIZE};
 use super::sort_post::sort_post;
-use super::NPOST;
+use super::POST_MEM_BUDGET;
 
 // Index writing.  See read.rs for details of on-disk format.
 //
@@ -36,38 +49,67 @@ use super::NPOST;
 // while processing the files one at a time, sort that list by trigram,
 // and then create the posting lists from subsequences of the list.
 // However, we do not assume that the entire index fits in memory.
-// Instead, we sort and flush the list to a new temporary file each time
-// it reaches its maximum in-memory size, and then at the end we
-// create the final posting lists by merging the temporary files as we
-// read them back in.
+// Instead, we sort the list and append it to a temporary file as a run
+// each time it reaches its memory budget (post_mem_budget), and then at
+// the end we create the final posting lists by merging the runs as we
+// read them back in through a memory map.
 //
-// It would also be useful to be able to create an index for a subset
-// of the files and then merge that index into an existing one.  This would
-// allow incremental updating of an existing index when a directory changes.
-// But we have not implemented that.
-
-const MAX_FILE_LEN: u64 = 1 << 30;
-const MAX_TEXT_TRIGRAMS: u64 = 30000;
-const MAX_INVALID_UTF8_RATION: f64 = 0.1;
-const MAX_LINE_LEN: u64 = 2000;
-
-
-pub struct IndexWriter {
-    /// Max number of allowed trigrams in a file
-    pub max_trigram_count: u64,
-    /// Max percentage of invalid utf-8 sequences allowed
-    pub max_utf8_invalid: f64,
-    /// Don't index a file if its size in bytes is larger than this
-    pub max_file_len: u64,
-    /// Stop indexing a file if it has a line longer than this
-    pub max_line_len: u64,
-
+// Reindexing a directory doesn't have to read every file again: given the
+// previous version of the index (see `IndexWriter::set_previous`), files
+// that haven't changed since are copied from it instead, see previous.rs.
+
+/// Writes an index into a file, or any other `Write + Seek` such as an
+/// in-memory buffer, see `IndexWriter::from_writer`.
+pub struct IndexWriter<W: Write + Seek = File> {
+    /// If set, paths and names are stored relative to this directory so
+    /// the index can be moved elsewhere, see `IndexReader::map_root`.
+    /// Files outside of it can't be added.
+    pub root: Option<PathBuf>,
+    /// Format to write, version 2 unless set before adding any files.
+    /// `IndexVersion::V1` writes the same bytes as the Go implementation,
+    /// without file metadata, checksums or a root.
+    pub version: IndexVersion,
+    /// Max bytes of (trigram, file ID) pairs kept in memory before they're
+    /// sorted and spilled to a temporary file.  Sorting needs as much again
+    /// while it runs.
+    pub post_mem_budget: usize,
+    /// Recorded in the options section as the time the index was written.
+    /// Defaults to when the writer was created; set it to get the same
+    /// bytes from the same files.
+    pub created: SystemTime,
+    /// If set, a file with the same contents as one added before it gets
+    /// no posting entries of its own; the index lists it as a duplicate of
+    /// the first one instead, and queries return both.  Files are compared
+    /// by the SHA-256 digests of their contents, which take time to compute,
+    /// so this is off by default.  A file reused from the previous index
+    /// (see `set_previous`) isn't read, so it's only found to be a copy of
+    /// another reused file that it shared posting entries with there.
+    /// Ignored for `IndexVersion::V1`.
+    pub deduplicate: bool,
+
+    options: IndexOptions,
+    filters: Vec<Arc<FileFilter>>,
     paths: Vec<OsString>,
 
-    name_data: BufWriter<File>,
-    name_index: BufWriter<File>,
-
-    trigram: SparseSet,
+    /// Keep the scratch space in memory instead of temporary files
+    in_memory: bool,
+    name_data: BufWriter<Scratch>,
+    names: NameWriter,
+    name_index: BufWriter<Scratch>,
+    file_meta: BufWriter<Scratch>,
+    /// The first file ID added with each SHA-256 digest
+    first_copies: HashMap<[u8; 32], u32>,
+    /// The first reused file ID that had each file's posting entries in
+    /// the previous index
+    first_reused: HashMap<u32, u32>,
+    /// (first copy, duplicate) file IDs, in the order they were added
+    duplicates: Vec<(u32, u32)>,
+    previous: Option<Arc<PreviousIndex>>,
+    /// (ID in the previous index whose posting entries to copy, file ID)
+    /// of each reused file with posting entries of its own
+    reused: Vec<(u32, u32)>,
+
+    extractor: TrigramExtractor,
 
     /// Tracks the number of names written to disk (used to assign file IDs)
     pub number_of_names_written: usize,
@@ -75,10 +117,15 @@ pub struct IndexWriter {
     pub bytes_written: usize,
 
     post: Vec<PostEntry>,
-    post_files: Vec<Vec<PostEntry>>,
-    post_index: BufWriter<File>,
+    /// Sorted runs spilled to `post_file`, as (offset, number of entries)
+    post_runs: Vec<(u64, usize)>,
+    post_file: Option<BufWriter<Scratch>>,
+    post_index: BufWriter<Scratch>,
 
-    index: BufWriter<File>,
+    index: BufWriter<HashWriter<W>>,
+
+    stats: IndexStats,
+    started: Instant,
 }
 
 impl IndexWriter {
@@ -91,24 +138,140 @@ impl IndexWriter {
     pub fn new<P: AsRef<Path>>(filename: P) -> io::Result<IndexWriter> {
         let _frame = libprofiling::profile("IndexWriter::new");
         let f = try!(File::create(filename));
+        IndexWriter::with_scratch(f, false)
+    }
+}
+
+impl IndexWriter<Cursor<Vec<u8>>> {
+    /// Creates an index in memory, without touching the filesystem.  Get
+    /// it back from `finish` and read it with `IndexReader::from_bytes`.
+    ///
+    /// ```
+    /// # extern crate libcindex;
+    /// # extern crate libcsearch;
+    /// # use libcindex::writer::IndexWriter;
+    /// # use libcsearch::reader::IndexReader;
+    /// # fn main() {
+    /// let mut index = IndexWriter::in_memory();
+    /// index.add_document("greeting", &b"hello world"[..], 11).unwrap();
+    /// let (buf, _stats) = index.finish().unwrap();
+    /// let ix = IndexReader::from_bytes(buf.into_inner()).unwrap();
+    /// assert_eq!(ix.name(0), "greeting");
+    /// # }
+    /// ```
+    ///
+    /// Everything is kept in memory, including the post entries other
+    /// writers spill to temporary files, so this is meant for small
+    /// indexes.
+    pub fn in_memory() -> IndexWriter<Cursor<Vec<u8>>> {
+        IndexWriter::with_scratch(Cursor::new(Vec::new()), true)
+            .expect("writing to memory can't fail")
+    }
+}
+
+impl<W: Write + Seek> IndexWriter<W> {
+    /// Creates an index written into `w`, which has to be at its start.
+    /// Temporary files are used for scratch space as usual.
+    pub fn from_writer(w: W) -> io::Result<IndexWriter<W>> {
+        IndexWriter::with_scratch(w, false)
+    }
+
+    fn with_scratch(mut w: W, in_memory: bool) -> io::Result<IndexWriter<W>> {
+        if try!(get_offset(&mut w)) != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "an index has to be written from the start"));
+        }
         Ok(IndexWriter {
-            max_trigram_count: MAX_TEXT_TRIGRAMS,
-            max_utf8_invalid: MAX_INVALID_UTF8_RATION,
-            max_file_len: MAX_FILE_LEN,
-            max_line_len: MAX_LINE_LEN,
+            root: None,
+            version: IndexVersion::V2,
+            post_mem_budget: POST_MEM_BUDGET,
+            created: SystemTime::now(),
+            deduplicate: false,
+            options: IndexOptions::default(),
+            filters: Vec::new(),
             paths: Vec::new(),
-            name_data: try!(make_temp_buf()),
-            name_index: try!(make_temp_buf()),
-            trigram: SparseSet::new(),
+            in_memory: in_memory,
+            name_data: try!(make_scratch(in_memory)),
+            names: NameWriter::new(IndexVersion::V2),
+            name_index: try!(make_scratch(in_memory)),
+            file_meta: try!(make_scratch(in_memory)),
+            first_copies: HashMap::new(),
+            first_reused: HashMap::new(),
+            duplicates: Vec::new(),
+            previous: None,
+            reused: Vec::new(),
+            extractor: TrigramExtractor::new(ExtractSettings {
+                options: IndexOptions::default(),
+                root: None,
+                filters: Vec::new(),
+                previous: None,
+                digest: false,
+            }),
             number_of_names_written: 0,
             bytes_written: 0,
-            post: Vec::with_capacity(NPOST),
-            post_files: Vec::new(),
-            post_index: try!(make_temp_buf()),
-            index: BufWriter::with_capacity(256 << 10, f),
+            post: Vec::new(),
+            post_runs: Vec::new(),
+            post_file: None,
+            post_index: try!(make_scratch(in_memory)),
+            index: BufWriter::with_capacity(256 << 10, HashWriter::new(w)),
+            stats: IndexStats::default(),
+            started: Instant::now(),
         })
     }
 
+    /// Returns the options deciding which files get indexed
+    pub fn options(&self) -> &IndexOptions {
+        &self.options
+    }
+
+    /// Replaces the options deciding which files get indexed.  They're
+    /// recorded in the index, see `StoredOptions`.
+    ///
+    /// ```no_run
+    /// # use libcindex::writer::{IndexOptions, IndexWriter};
+    /// let mut index = IndexWriter::new("index").unwrap();
+    /// index.set_options(IndexOptions::builder().max_line_len(500).build().unwrap());
+    /// ```
+    pub fn set_options(&mut self, options: IndexOptions) {
+        self.options = options;
+    }
+
+    /// Adds a filter that can skip files, run after the built-in ones that
+    /// enforce the options.  See `FileFilter`.  Files aren't reused from
+    /// the previous index once a filter is added.
+    pub fn add_filter<F: FileFilter + 'static>(&mut self, filter: F) {
+        self.filters.push(Arc::new(filter));
+    }
+
+    /// Reuses files from `ix`, the previous version of this index, when
+    /// reindexing.  A file added with `add_file` whose modification time and
+    /// size are the same as they were in `ix` isn't read again; its posting
+    /// entries are copied from `ix` when the index is flushed.
+    ///
+    /// Nothing is reused unless `ix` was written with the same options and
+    /// root as this index, or if filters were added with `add_filter`,
+    /// since they might skip files `ix` has.  Files added with `add`, `add_document` or from
+    /// a `TrigramExtractor` made before this is called are always read.
+    /// Since `ix` is read until the index is flushed, it mustn't be the
+    /// file being written.
+    ///
+    /// ```no_run
+    /// # extern crate libcindex;
+    /// # extern crate libcsearch;
+    /// # use libcindex::writer::IndexWriter;
+    /// # use libcsearch::reader::IndexReader;
+    /// # fn main() {
+    /// let mut index = IndexWriter::new("index.new").unwrap();
+    /// index.set_previous(IndexReader::open("index").unwrap());
+    /// index.add_file("/path/to/file").unwrap();
+    /// let stats = index.flush().unwrap();
+    /// println!("{} of {} files reused", stats.files_reused, stats.files_indexed);
+    /// # }
+    /// ```
+    pub fn set_previous(&mut self, ix: IndexReader) {
+        self.previous = Some(Arc::new(PreviousIndex::new(ix)));
+    }
+
     /// Add the specified paths to the index.
     /// Note that this only writes the names of the paths into
     /// the index, it doesn't actually walk those directories.
@@ -119,6 +282,9 @@ impl IndexWriter {
 
     /// Open a file and index it
     ///
+    /// The file's modification time, size and content hash are recorded
+    /// in the index, see `IndexReader::file_meta`.
+    ///
     /// ```no_run
     /// # use libcindex::writer::IndexWriter;
     /// let mut index = IndexWriter::new("index").unwrap();
@@ -127,78 +293,260 @@ impl IndexWriter {
     /// ```
     pub fn add_file<P: AsRef<Path>>(&mut self, filename: P) -> IndexResult<()> {
         let _frame = libprofiling::profile("IndexWriter::add_file");
-        let f = try!(File::open(filename.as_ref()));
-        let metadata = try!(f.metadata());
-        self.add(filename, f, metadata.len())
+        let settings = self.extract_settings();
+        self.extractor.set_settings(settings);
+        let t = self.extractor.extract_file(filename);
+        self.add_extracted(t)
     }
 
     /// Indexes a file
     ///
     /// `filename` is the name of the opened file referred to by `f`.
     /// `size` is the size of the file referred to by `f`.
+    ///
+    /// The size and content hash are recorded in the index, but since
+    /// there's no file to ask, the modification time is left as zero.
     pub fn add<P, R>(&mut self, filename: P, f: R, size: u64) -> IndexResult<()>
         where P: AsRef<Path>,
               R: Read
+    {
+        self.add_with_mtime(filename, f, size, None)
+    }
+
+    /// Indexes a document that isn't a file, such as a database row or
+    /// generated content, read from `content`
+    ///
+    /// `id` is stored in the index in place of a file name, exactly as
+    /// given, and is what a search returns for the document.  Searchers
+    /// can't open it as a file, so they need a `ContentSource` that knows
+    /// where to fetch it from (see `libcsearch::source`).  IDs must be
+    /// added in sorted order along with any file names, and can't be
+    /// empty.  The filters see `id` as the file's path.
+    ///
+    /// ```no_run
+    /// # use libcindex::writer::IndexWriter;
+    /// let mut index = IndexWriter::new("index").unwrap();
+    /// index.add_paths(vec!["db:".into()]);
+    /// let row = "SELECT * FROM users";
+    /// index.add_document("db:queries/1", row.as_bytes(), row.len() as u64).unwrap();
+    /// index.flush().unwrap();
+    /// ```
+    pub fn add_document<R: Read>(&mut self, id: &str, content: R, size: u64) -> IndexResult<()> {
+        let _frame = libprofiling::profile("IndexWriter::add_document");
+        let settings = self.extract_settings();
+        self.extractor.set_settings(settings);
+        let t = self.extractor.extract_document(id, content, size);
+        self.add_extracted(t)
+    }
+
+    fn add_with_mtime<P, R>(&mut self,
+                            filename: P,
+                            f: R,
+                            size: u64,
+                            mtime: Option<SystemTime>)
+                            -> IndexResult<()>
+        where P: AsRef<Path>,
+              R: Read
     {
         let _frame = libprofiling::profile("IndexWriter::add");
-        if size > self.max_file_len {
-            return Err(IndexError::new(IndexErrorKind::FileTooLong,
-                                       format!("file too long, ignoring ({} > {})",
-                                               size,
-                                               self.max_file_len)));
-        }
-        self.trigram.clear();
-        let max_utf8_invalid = ((size as f64) * self.max_utf8_invalid) as u64;
-        {
-            let mut trigrams = TrigramReader::new(f, max_utf8_invalid, self.max_line_len);
-            let _trigram_insert_frame = libprofiling::profile("IndexWriter::add: Insert Trigrams");
-            while let Some(each_trigram) = trigrams.next() {
-                self.trigram.insert(each_trigram);
+        let settings = self.extract_settings();
+        self.extractor.set_settings(settings);
+        let t = self.extractor.extract(filename, f, size, mtime);
+        self.add_extracted(t)
+    }
+
+    /// Adds a file if its trigrams could be collected, or counts it as
+    /// skipped
+    fn add_extracted(&mut self, t: IndexResult<FileTrigrams>) -> IndexResult<()> {
+        match t {
+            Ok(t) => self.add_trigrams(t),
+            Err(e) => {
+                self.record_skip(&e);
+                Err(e)
             }
-            if let Some(e) = trigrams.take_error() {
-                return e;
+        }
+    }
+
+    /// Counts a file that was skipped because of `e` in the stats
+    pub(super) fn record_skip(&mut self, e: &IndexError) {
+        self.stats.record_skip(e.kind());
+    }
+
+    /// Returns the statistics collected so far.  `flush` returns the final
+    /// ones.
+    pub fn stats(&self) -> &IndexStats {
+        &self.stats
+    }
+
+    /// Returns a `TrigramExtractor` that skips the same files this writer
+    /// would, for collecting trigrams on another thread.
+    pub fn extractor(&self) -> TrigramExtractor {
+        TrigramExtractor::new(self.extract_settings())
+    }
+
+    fn extract_settings(&self) -> ExtractSettings {
+        let root = self.root.as_ref().map(|r| r.as_path());
+        ExtractSettings {
+            options: self.options.clone(),
+            root: self.root.clone(),
+            filters: self.filters.clone(),
+            previous: self.previous
+                .as_ref()
+                .filter(|p| self.filters.is_empty() && p.matches(&self.options, root))
+                .cloned(),
+            digest: self.deduplicates(),
+        }
+    }
+
+    /// Adds a file whose trigrams were collected by a `TrigramExtractor`.
+    /// Files get IDs in the order they're added.
+    pub fn add_trigrams(&mut self, t: FileTrigrams) -> IndexResult<()> {
+        let previous_id = t.previous_id();
+        let digest = t.digest();
+        let (name, meta, trigrams) = t.into_parts();
+        self.bytes_written += meta.size as usize;
+        self.stats.files_indexed += 1;
+        let file_id = try!(self.add_name(&name));
+        try!(meta.write_to(&mut self.file_meta));
+        if let Some(old) = previous_id {
+            self.stats.files_reused += 1;
+            let source = self.previous.as_ref().unwrap().postings_of(old);
+            if !self.is_reused_duplicate(file_id, source) {
+                self.reused.push((source, file_id));
             }
+            return Ok(());
         }
-        if (self.trigram.len() as u64) > self.max_trigram_count {
-            return Err(IndexError::new(IndexErrorKind::TooManyTrigrams,
-                                       format!("Too many trigrams ({} > {})",
-                                               self.trigram.len(),
-                                               self.max_trigram_count)));
+        self.stats.bytes_read += meta.size;
+        // files without trigrams have no posting entries to share
+        if self.is_duplicate(file_id, &meta, digest, !trigrams.is_empty()) {
+            return Ok(());
+        }
+        self.push_trigrams_to_post(file_id, trigrams)
+    }
+
+    fn deduplicates(&self) -> bool {
+        self.deduplicate && self.version == IndexVersion::V2
+    }
+
+    /// Returns true if file `file_id`, whose contents have the SHA-256
+    /// `digest`, has the same contents as a file added before it, and
+    /// records it as a duplicate of that file
+    fn is_duplicate(&mut self,
+                    file_id: u32,
+                    meta: &FileMeta,
+                    digest: Option<[u8; 32]>,
+                    has_trigrams: bool)
+                    -> bool {
+        let digest = match digest {
+            Some(d) if self.deduplicates() && !meta.is_empty() && has_trigrams => d,
+            _ => return false,
+        };
+        let first = *self.first_copies.entry(digest).or_insert(file_id);
+        self.record_duplicate(first, file_id)
+    }
 
+    /// Returns true if reused file `file_id`, which had the posting entries
+    /// of file `source` in the previous index, shared them with a reused
+    /// file added before it, and records it as a duplicate of that file
+    fn is_reused_duplicate(&mut self, file_id: u32, source: u32) -> bool {
+        if !self.deduplicates() {
+            return false;
         }
-        debug!("{} {} {:?}", size, self.trigram.len(), filename.as_ref());
-        self.bytes_written += size as usize;
+        let first = *self.first_reused.entry(source).or_insert(file_id);
+        self.record_duplicate(first, file_id)
+    }
 
-        let file_id = try!(self.add_name(filename));
-        let v = self.trigram.take_dense();
-        self.push_trigrams_to_post(file_id, v)
+    fn record_duplicate(&mut self, first: u32, file_id: u32) -> bool {
+        if first == file_id {
+            return false;
+        }
+        self.duplicates.push((first, file_id));
+        self.stats.duplicate_files += 1;
+        true
     }
 
     /// Take trigrams in `trigams` and push them to the post list,
     /// possibly flushing them to file.
     fn push_trigrams_to_post(&mut self, file_id: u32, trigrams: Vec<u32>) -> IndexResult<()> {
         let _frame = libprofiling::profile("IndexWriter::push_trigrams_to_post");
+        let max_post = self.max_post();
         for each_trigram in trigrams {
-            if self.post.len() >= NPOST {
-                try!(self.flush_post());
+            try!(self.push_post(max_post, PostEntry::new(each_trigram, file_id)));
+        }
+        Ok(())
+    }
+
+    /// Returns the number of post entries kept in memory before spilling
+    fn max_post(&mut self) -> usize {
+        let max_post = cmp::max(1, self.post_mem_budget / mem::size_of::<PostEntry>());
+        if self.post.capacity() == 0 {
+            self.post.reserve_exact(max_post);
+        }
+        max_post
+    }
+
+    fn push_post(&mut self, max_post: usize, p: PostEntry) -> io::Result<()> {
+        if self.post.len() >= max_post {
+            try!(self.flush_post());
+        }
+        self.post.push(p);
+        Ok(())
+    }
+
+    /// Copies the posting entries of the reused files from the previous
+    /// index, reading each of its posting lists once
+    ///
+    /// `sort_post` expects the file IDs of each trigram to be in order, as
+    /// they are when files are added one by one, so the entries added so
+    /// far are spilled first and the copied ones are pushed already sorted.
+    fn copy_reused_posts(&mut self) -> io::Result<()> {
+        let _frame = libprofiling::profile("IndexWriter::copy_reused_posts");
+        let previous = match self.previous.take() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        if self.reused.is_empty() {
+            return Ok(());
+        }
+        let ix = previous.index();
+        // new file IDs by the ID their entries are copied from
+        let mut targets = vec![Vec::new(); ix.num_name];
+        for &(source, file_id) in &self.reused {
+            targets[source as usize].push(file_id);
+        }
+        try!(self.flush_post());
+        let max_post = self.max_post();
+        let mut file_ids = Vec::new();
+        for i in 0..ix.num_post {
+            let (trigram, count, offset) = ix.list_at(i * ix.post_entry_size());
+            if count == 0 {
+                continue;
+            }
+            let mut list = try!(ix.post_list_at(trigram, count as usize, offset));
+            file_ids.clear();
+            while let Some(id) = try!(list.next()) {
+                if let Some(ids) = targets.get(id as usize) {
+                    file_ids.extend_from_slice(ids);
+                }
+            }
+            file_ids.sort();
+            for &file_id in &file_ids {
+                try!(self.push_post(max_post, PostEntry::new(trigram, file_id)));
             }
-            self.post.push(PostEntry::new(each_trigram, file_id));
         }
         Ok(())
     }
 
-    /// Add `filename` to the nameData section of the index
-    fn add_name<P: AsRef<Path>>(&mut self, filename: P) -> IndexResult<u32> {
+    /// Add `name` to the nameData section of the index
+    fn add_name(&mut self, name: &[u8]) -> IndexResult<u32> {
         let _frame = libprofiling::profile("IndexWriter::add_name");
+        if self.number_of_names_written == 0 {
+            self.names = NameWriter::new(self.version);
+        }
         let offset = try!(get_offset(&mut self.name_data));
-        try!(self.name_index.write_u32::<BigEndian>(offset as u32));
+        try!(self.names.write_index_entry(&mut self.name_index, offset));
 
-        let s = try!(filename.as_ref()
-                             .to_str()
-                             .ok_or(IndexError::new(IndexErrorKind::FileNameError,
-                                                    "UTF-8 Conversion error")));
-        try!(self.name_data.write(s.as_bytes()));
-        try!(self.name_data.write_u8(0));
+        try!(self.names.write_name(&mut self.name_data, name));
 
         let id = self.number_of_names_written;
         self.number_of_names_written += 1;
@@ -206,56 +554,165 @@ impl IndexWriter {
     }
 
     /// Finalize the index, collecting all data and writing it out.
-    pub fn flush(mut self) -> IndexResult<()> {
-        let _frame = libprofiling::profile("IndexWriter::flush");
-        try!(self.add_name(""));
-        try!(self.index.write(MAGIC.as_bytes()));
+    /// Returns statistics about the files added and the index written.
+    pub fn flush(self) -> IndexResult<IndexStats> {
+        self.finish().map(|(_, stats)| stats)
+    }
 
-        let mut off = [0; 5];
+    /// Like `flush`, but also returns the writer the index was written to
+    pub fn finish(mut self) -> IndexResult<(W, IndexStats)> {
+        let _frame = libprofiling::profile("IndexWriter::flush");
+        let flush_start = Instant::now();
+        self.stats.add_time = flush_start - self.started;
+        if self.version == IndexVersion::V1 && self.root.is_some() {
+            return Err(IndexError::new(IndexErrorKind::FileNameError,
+                                       "a version 1 index can't store names relative to a root"));
+        }
+        try!(self.copy_reused_posts());
+        try!(self.add_name(b""));
+        let paths = try!(self.paths
+            .iter()
+            .map(|p| stored_name(self.root.as_ref().map(|r| r.as_path()), Path::new(p)))
+            .collect::<IndexResult<Vec<_>>>());
+        let magic = match self.version {
+            IndexVersion::V1 => MAGIC,
+            IndexVersion::V2 => MAGIC_V2,
+        };
+        try!(self.index.write(magic.as_bytes()));
+
+        let mut off = [0; 6];
+        let mut checksums = Vec::new();
         off[0] = try!(get_offset(&mut self.index));
+        try!(take_checksum(&mut self.index));
 
-        for p in &self.paths {
-            let path_as_bytes = try!(p.to_str()
-                                      .map(str::as_bytes)
-                                      .ok_or(IndexError::new(IndexErrorKind::FileNameError,
-                                                             "UTF-8 Conversion error")));
-            try!(self.index.write(path_as_bytes));
+        for p in &paths {
+            try!(self.index.write(p));
             try!(self.index.write_u8(0));
         }
         try!(self.index.write_u8(0));
         off[1] = try!(get_offset(&mut self.index));
+        checksums.push((SECTION_PATHS, try!(take_checksum(&mut self.index))));
 
         try!(self.name_data.flush());
         copy_file(&mut self.index, &mut self.name_data.get_mut());
         off[2] = try!(get_offset(&mut self.index));
+        checksums.push((SECTION_NAMES, try!(take_checksum(&mut self.index))));
 
+        let merge_start = Instant::now();
         try!(self.merge_post());
+        self.stats.merge_time = merge_start.elapsed();
+        checksums.push((SECTION_POSTING_LISTS, try!(take_checksum(&mut self.index))));
+
+        let mut sections = Vec::new();
+        if self.version == IndexVersion::V2 {
+            try!(self.write_sections(&mut sections, &mut checksums));
+        }
         off[3] = try!(get_offset(&mut self.index));
 
         try!(self.name_index.flush());
         copy_file(&mut self.index, &mut self.name_index.get_mut());
         off[4] = try!(get_offset(&mut self.index));
+        checksums.push((SECTION_NAME_INDEX, try!(take_checksum(&mut self.index))));
 
         try!(self.post_index.flush());
         copy_file(&mut self.index, &mut self.post_index.get_mut());
-
-        for v in off.iter() {
-            try!(self.index.write_u32::<BigEndian>(*v as u32));
+        checksums.push((SECTION_POSTING_INDEX, try!(take_checksum(&mut self.index))));
+
+        if self.version == IndexVersion::V2 {
+            let checksum_offset = try!(get_offset(&mut self.index));
+            try!(write_checksums(&mut self.index, &checksums));
+            sections.push(Section {
+                id: SECTION_CHECKSUMS,
+                offset: checksum_offset,
+                len: try!(get_offset(&mut self.index)) - checksum_offset,
+            });
+
+            off[5] = try!(get_offset(&mut self.index));
+            try!(write_section_table(&mut self.index, &sections));
         }
+
+        try!(write_trailer(&mut self.index, self.version, &off));
         try!(self.index.write(TRAILER_MAGIC.as_bytes()));
+        try!(self.index.flush());
+        self.stats.index_bytes = try!(get_offset(&mut self.index));
+        self.stats.flush_time = flush_start.elapsed();
         info!("{} data bytes, {} index bytes",
               self.bytes_written,
-              try!(get_offset(&mut self.index)));
+              self.stats.index_bytes);
+        let w = try!(self.index.into_inner().map_err(|e| e.into_error()));
+        Ok((w.into_inner(), self.stats))
+    }
+
+    /// Writes the optional sections of a version 2 index
+    fn write_sections(&mut self,
+                      sections: &mut Vec<Section>,
+                      checksums: &mut Vec<(u32, u64)>)
+                      -> io::Result<()> {
+        try!(self.file_meta.flush());
+        let meta_offset = try!(get_offset(&mut self.index));
+        copy_file(&mut self.index, &mut self.file_meta.get_mut());
+        sections.push(Section {
+            id: SECTION_FILE_META,
+            offset: meta_offset,
+            len: try!(get_offset(&mut self.index)) - meta_offset,
+        });
+        checksums.push((SECTION_FILE_META, try!(take_checksum(&mut self.index))));
+        if let Some(root) = self.root.take() {
+            let root = pathbytes::to_bytes(root.as_os_str()).into_owned();
+            let root_offset = try!(get_offset(&mut self.index));
+            try!(self.index.write_all(&root));
+            sections.push(Section {
+                id: SECTION_ROOT,
+                offset: root_offset,
+                len: root.len() as u64,
+            });
+            checksums.push((SECTION_ROOT, try!(take_checksum(&mut self.index))));
+        }
+        let options_offset = try!(get_offset(&mut self.index));
+        try!(StoredOptions {
+                options: self.options.clone(),
+                tool_version: TOOL_VERSION.to_string(),
+                created: self.created,
+            }
+            .write_to(&mut self.index));
+        sections.push(Section {
+            id: SECTION_OPTIONS,
+            offset: options_offset,
+            len: try!(get_offset(&mut self.index)) - options_offset,
+        });
+        checksums.push((SECTION_OPTIONS, try!(take_checksum(&mut self.index))));
+        if !self.duplicates.is_empty() {
+            self.duplicates.sort();
+            let dup_offset = try!(get_offset(&mut self.index));
+            try!(write_duplicates(&mut self.index, &self.duplicates));
+            sections.push(Section {
+                id: SECTION_DUPLICATES,
+                offset: dup_offset,
+                len: try!(get_offset(&mut self.index)) - dup_offset,
+            });
+            checksums.push((SECTION_DUPLICATES, try!(take_checksum(&mut self.index))));
+        }
         Ok(())
     }
+
     /// Merge the posting lists together
     fn merge_post(&mut self) -> io::Result<()> {
         let _frame = libprofiling::profile("IndexWriter::merge_post");
+        let spilled = match self.post_file.take() {
+            Some(f) => {
+                let f = try!(f.into_inner().map_err(|e| e.into_error()));
+                Some(try!(f.into_map()))
+            }
+            None => None,
+        };
         let mut heap = PostHeap::new();
-        info!("merge {} files + mem", self.post_files.len());
+        info!("merge {} files + mem", self.post_runs.len());
 
-        for p in self.post_files.drain(..) {
-            heap.add_mem(p);
+        if let Some(ref m) = spilled {
+            for &(offset, n) in &self.post_runs {
+                let start = offset as usize;
+                heap.add_disk(&m[start..start + n * POST_ENTRY_DISK_SIZE]);
+            }
         }
         sort_post(&mut self.post);
         let mut v = Vec::new();
@@ -267,6 +724,8 @@ impl IndexWriter {
 
         let _frame_write = libprofiling::profile("IndexWriter::merge_post: Generate/Write post \
                                                   index");
+        let mut file_ids = Vec::new();
+        let mut largest = LargestLists::new();
         while let Some(plist) = TakeWhilePeek::new(&mut h) {
             let _fname_write_to_index = libprofiling::profile("IndexWriter::merge_post: Write \
                                                                post index");
@@ -274,43 +733,53 @@ impl IndexWriter {
 
             // posting list
             let plist_trigram = plist.trigram();
-            try!(self.index.write_trigram(plist_trigram));
-            let mut written = 0;
             let _fname_diffs = libprofiling::profile("IndexWriter::merge_post: Write file diffs");
-            for each_file in to_diffs(plist.map(|p| p.file_id())) {
-                try!(libvarint::write_uvarint(&mut self.index, each_file));
-                written += 1;
-            }
+            file_ids.clear();
+            file_ids.extend(plist.map(|p| p.file_id()));
+            try!(write_post_list(&mut self.index, self.version, plist_trigram, &file_ids));
             drop(_fname_diffs);
 
-            let _fname_diffs = libprofiling::profile("IndexWriter::merge_post: Write file diffs");
-            try!(self.post_index.write_trigram(plist_trigram));
-            try!(self.post_index.write_u32::<BigEndian>(written - 1));
-            try!(self.post_index.write_u32::<BigEndian>(offset as u32));
+            try!(write_post_entry(&mut self.post_index,
+                                  self.version,
+                                  plist_trigram,
+                                  file_ids.len() as u32,
+                                  offset));
+            self.stats.distinct_trigrams += 1;
+            largest.add(plist_trigram, file_ids.len() as u32);
         }
+        self.stats.largest_posting_lists = largest.into_vec();
         // NOTE: write last entry like how the go version works
         let offset = try!(get_offset(&mut self.index)) - offset0;
-        try!(self.index.write_trigram(0xffffff));           // END trigram
-        try!(libvarint::write_uvarint(&mut self.index, 0));    // NUL byte for END postlist
-        try!(self.post_index.write_trigram(0xffffff));      // END trigram
-        try!(self.post_index.write_u32::<BigEndian>(0));    // nothing written
-        try!(self.post_index.write_u32::<BigEndian>(offset as u32));
+        try!(write_post_list(&mut self.index, self.version, 0xffffff, &[]));
+        try!(write_post_entry(&mut self.post_index, self.version, 0xffffff, 0, offset));
 
         Ok(())
     }
 
-    /// Flush the post data to a temporary file
+    /// Sort the post data and spill it to a temporary file as a new run
     pub fn flush_post(&mut self) -> io::Result<()> {
         let _frame = libprofiling::profile("IndexWriter::flush_post");
+        if self.post.is_empty() {
+            return Ok(());
+        }
+        let start = Instant::now();
         sort_post(&mut self.post);
-        let mut v = Vec::with_capacity(NPOST);
-        mem::swap(&mut v, &mut self.post);
-        self.post_files.push(v);
+        if self.post_file.is_none() {
+            self.post_file = Some(try!(make_scratch(self.in_memory)));
+        }
+        let out = self.post_file.as_mut().unwrap();
+        let offset = try!(get_offset(out));
+        for p in &self.post {
+            try!(out.write_u64::<BigEndian>(p.value()));
+        }
+        self.post_runs.push((offset, self.post.len()));
+        self.post.clear();
+        self.stats.spill_time += start.elapsed();
         Ok(())
     }
 }
 
-fn make_temp_buf() -> io::Result<BufWriter<File>> {
-    let w = try!(tempfile());
+fn make_scratch(in_memory: bool) -> io::Result<BufWriter<Scratch>> {
+    let w = try!(Scratch::new(in_memory));
     Ok(BufWriter::with_capacity(256 << 10, w))
 }
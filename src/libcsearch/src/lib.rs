@@ -3,12 +3,15 @@ extern crate memmap;
 extern crate num;
 extern crate regex;
 extern crate regex_syntax;
+extern crate twox_hash;
 
 extern crate consts;
 extern crate libvarint;
 
+pub mod pathbytes;
 pub mod reader;
 pub mod regexp;
+pub mod source;
 
 use std::env;
 
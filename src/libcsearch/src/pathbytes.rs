@@ -0,0 +1,169 @@
+// Copyright 2016 Vernon Jones.
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+//! Conversions between file names and the bytes stored in an index.
+//!
+//! On Unix file names are stored exactly as the OS hands them to us, so
+//! names that aren't valid UTF-8 survive a round trip through the index.
+//! Elsewhere names are stored as UTF-8.
+//!
+//! An index can also store names relative to a root directory, so it can
+//! be moved to another machine.  A relative name is the part of the path
+//! after the root, prefixed with ".", e.g. "./src/main.rs"; the root itself
+//! is ".".
+
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+
+/// Returns the bytes stored in an index for `s`
+#[cfg(unix)]
+pub fn to_bytes(s: &OsStr) -> Cow<[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(s.as_bytes())
+}
+
+/// Returns the bytes stored in an index for `s`
+#[cfg(not(unix))]
+pub fn to_bytes(s: &OsStr) -> Cow<[u8]> {
+    match s.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+/// Returns the file name for bytes read from an index
+#[cfg(unix)]
+pub fn from_bytes(b: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(b)
+}
+
+/// Returns the file name for bytes read from an index
+#[cfg(not(unix))]
+pub fn from_bytes(b: Vec<u8>) -> OsString {
+    match String::from_utf8(b) {
+        Ok(s) => OsString::from(s),
+        Err(e) => OsString::from(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+    }
+}
+
+fn is_separator(b: u8) -> bool {
+    b == b'/' || (cfg!(windows) && b == b'\\')
+}
+
+fn trim_separator(dir: &[u8]) -> &[u8] {
+    match dir.split_last() {
+        Some((&b, rest)) if is_separator(b) => rest,
+        _ => dir,
+    }
+}
+
+/// If `name` is `dir` or inside it, returns the rest of `name` after `dir`,
+/// starting with a separator (or empty if `name` is `dir`).
+fn strip_dir<'a>(name: &'a [u8], dir: &[u8]) -> Option<&'a [u8]> {
+    let dir = trim_separator(dir);
+    if !name.starts_with(dir) {
+        return None;
+    }
+    let rest = &name[dir.len()..];
+    if rest.is_empty() || is_separator(rest[0]) {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Returns `name` relative to `root`, as stored in a relocatable index,
+/// or None if it isn't inside `root`.
+pub fn relative_to(name: &[u8], root: &[u8]) -> Option<Vec<u8>> {
+    strip_dir(name, root).map(|rest| {
+        let mut v = b".".to_vec();
+        v.extend_from_slice(rest);
+        v
+    })
+}
+
+/// Returns true if `name` is `dir` or inside it
+pub fn is_inside(name: &[u8], dir: &[u8]) -> bool {
+    strip_dir(name, dir).is_some()
+}
+
+/// Returns true if `stored` is a name relative to the root, which a reader
+/// resolves against it
+pub fn is_relative(stored: &[u8]) -> bool {
+    strip_dir(stored, b".").is_some()
+}
+
+/// Turns a name stored relative to `root` back into a full path.
+/// Names that aren't relative are returned unchanged.
+pub fn resolve(stored: &[u8], root: &[u8]) -> Vec<u8> {
+    match strip_dir(stored, b".") {
+        Some(rest) => {
+            let root = if rest.is_empty() {
+                root
+            } else {
+                trim_separator(root)
+            };
+            let mut v = root.to_vec();
+            v.extend_from_slice(rest);
+            v
+        }
+        None => stored.to_vec(),
+    }
+}
+
+/// If `name` is `old` or inside it, returns it with `old` replaced by `new`
+pub fn replace_dir(name: &[u8], old: &[u8], new: &[u8]) -> Option<Vec<u8>> {
+    strip_dir(name, old).map(|rest| {
+        let new = if rest.is_empty() {
+            new
+        } else {
+            trim_separator(new)
+        };
+        let mut v = new.to_vec();
+        v.extend_from_slice(rest);
+        v
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_names() {
+        assert_eq!(relative_to(b"/build/src/a.rs", b"/build/src"), Some(b"./a.rs".to_vec()));
+        assert_eq!(relative_to(b"/build/src/a.rs", b"/build/src/"), Some(b"./a.rs".to_vec()));
+        assert_eq!(relative_to(b"/build/src", b"/build/src"), Some(b".".to_vec()));
+        assert_eq!(relative_to(b"/build/srcx/a.rs", b"/build/src"), None);
+        assert_eq!(relative_to(b"/a.rs", b"/"), Some(b"./a.rs".to_vec()));
+
+        assert_eq!(resolve(b"./a.rs", b"/home/me/src"), b"/home/me/src/a.rs".to_vec());
+        assert_eq!(resolve(b".", b"/home/me/src"), b"/home/me/src".to_vec());
+        assert_eq!(resolve(b"./a.rs", b"/"), b"/a.rs".to_vec());
+        assert_eq!(resolve(b"/abs/a.rs", b"/home/me/src"), b"/abs/a.rs".to_vec());
+        assert!(is_relative(b"./a.rs") && is_relative(b"."));
+        assert!(!is_relative(b".a.rs") && !is_relative(b"/abs/a.rs"));
+        assert!(is_inside(b"/src/a.rs", b"/src") && is_inside(b"/src", b"/src/"));
+        assert!(!is_inside(b"/srcs/a.rs", b"/src"));
+
+        assert_eq!(replace_dir(b"/build/src/a.rs", b"/build/src", b"/home/me/src/"),
+                   Some(b"/home/me/src/a.rs".to_vec()));
+        assert_eq!(replace_dir(b"/build/src", b"/build/src", b"/home/me/src"),
+                   Some(b"/home/me/src".to_vec()));
+        assert_eq!(replace_dir(b"/build/srcx", b"/build/src", b"/home/me/src"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_round_trip() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        for name in &[&b"plain"[..], "ü.rs".as_bytes(), &b"latin1-\xfc.rs"[..]] {
+            let s = OsStr::from_bytes(name);
+            assert_eq!(&*to_bytes(s), *name);
+            assert_eq!(from_bytes(name.to_vec()), s);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::error::{self, Error};
+use std::fmt;
+use std::io;
+
+
+/// The Error type for opening and reading an index.
+///
+/// Errors can come from std::io::Error, or from
+/// finding that the index file isn't well-formed.
+#[derive(Debug)]
+pub struct ReaderError {
+    kind: ReaderErrorKind,
+    error: Box<error::Error + Send + Sync>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReaderErrorKind {
+    /// A read error returned from a std::io function
+    IoError(io::ErrorKind),
+    /// The header or trailer magic is missing; probably not an index file
+    BadMagic,
+    /// The file is too short to hold the sections it describes
+    Truncated,
+    /// An offset in the trailer or section table points outside the file,
+    /// or the sections are out of order
+    OffsetOutOfRange,
+    /// The index was written in a format version this reader doesn't know
+    UnsupportedVersion,
+    /// A section doesn't match the checksum recorded for it
+    ChecksumMismatch,
+    /// A posting list couldn't be decoded
+    CorruptPostingList,
+}
+
+
+impl ReaderError {
+    /// Creates a new ReaderError. Works the same as std::io::Error.
+    ///
+    /// ```
+    /// # use libcsearch::reader::{ReaderError, ReaderErrorKind};
+    /// let e = ReaderError::new(ReaderErrorKind::Truncated, "index is 3 bytes long");
+    /// assert_eq!(e.kind(), ReaderErrorKind::Truncated);
+    /// ```
+    pub fn new<E>(kind: ReaderErrorKind, error: E) -> ReaderError
+        where E: Into<Box<error::Error + Send + Sync>>
+    {
+        ReaderError {
+            kind: kind,
+            error: error.into(),
+        }
+    }
+    /// Returns the type of the error
+    pub fn kind(&self) -> ReaderErrorKind {
+        self.kind.clone()
+    }
+}
+
+impl From<io::Error> for ReaderError {
+    fn from(e: io::Error) -> Self {
+        ReaderError {
+            kind: ReaderErrorKind::IoError(e.kind()),
+            error: Box::new(e),
+        }
+    }
+}
+
+
+impl From<ReaderError> for io::Error {
+    fn from(e: ReaderError) -> Self {
+        match e.kind() {
+            ReaderErrorKind::IoError(ekind) => io::Error::new(ekind, e),
+            _ => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+impl Error for ReaderError {
+    fn description(&self) -> &str {
+        match self.kind {
+            ReaderErrorKind::IoError(_) => self.error.description(),
+            ReaderErrorKind::BadMagic => "not a csearch index",
+            ReaderErrorKind::Truncated => "index is truncated",
+            ReaderErrorKind::OffsetOutOfRange => "index offset out of range",
+            ReaderErrorKind::UnsupportedVersion => "unsupported index version",
+            ReaderErrorKind::ChecksumMismatch => "index checksum mismatch",
+            ReaderErrorKind::CorruptPostingList => "corrupt posting list",
+        }
+    }
+}
+
+impl fmt::Display for ReaderError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(fmt)
+    }
+}
+
+
+/// A specialized result type for reading an index.
+pub type ReaderResult<T> = Result<T, ReaderError>;
@@ -0,0 +1,107 @@
+// Copyright 2016 Vernon Jones.
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// File metadata section.
+//
+// The optional file metadata section holds one fixed-size record per
+// file ID, in file ID order, so the record for a file can be found
+// without an index.  Each record has the form:
+//
+// 	modification time, seconds since the UNIX epoch [8]
+// 	modification time, nanoseconds [4]
+// 	size in bytes [8]
+// 	XXH64 hash of the contents [8]
+//
+// A record with a zero hash means nothing is known about the file
+// (e.g. it was merged in from an index without a metadata section).
+
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// Size of a record in the file metadata section
+pub const FILE_META_SIZE: usize = 8 + 4 + 8 + 8;
+
+/// Metadata recorded about an indexed file at the time it was indexed
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct FileMeta {
+    /// Seconds part of the modification time, relative to the UNIX epoch
+    pub mtime_sec: i64,
+    /// Nanoseconds part of the modification time
+    pub mtime_nsec: u32,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// XXH64 hash of the file contents
+    pub hash: u64,
+}
+
+impl FileMeta {
+    /// Creates a metadata record for a file with the given modification time
+    pub fn new(mtime: Option<SystemTime>, size: u64, hash: u64) -> FileMeta {
+        let (mtime_sec, mtime_nsec) = match mtime.map(|t| t.duration_since(UNIX_EPOCH)) {
+            Some(Ok(d)) => (d.as_secs() as i64, d.subsec_nanos()),
+            Some(Err(e)) => {
+                // before the epoch; keep the nanoseconds part positive
+                let d = e.duration();
+                if d.subsec_nanos() == 0 {
+                    (-(d.as_secs() as i64), 0)
+                } else {
+                    (-(d.as_secs() as i64) - 1, 1_000_000_000 - d.subsec_nanos())
+                }
+            }
+            None => (0, 0),
+        };
+        FileMeta {
+            mtime_sec: mtime_sec,
+            mtime_nsec: mtime_nsec,
+            size: size,
+            hash: hash,
+        }
+    }
+
+    /// Returns the modification time of the file
+    pub fn modified(&self) -> SystemTime {
+        let nsec = Duration::new(0, self.mtime_nsec);
+        if self.mtime_sec >= 0 {
+            UNIX_EPOCH + Duration::from_secs(self.mtime_sec as u64) + nsec
+        } else {
+            UNIX_EPOCH - Duration::from_secs((-self.mtime_sec) as u64) + nsec
+        }
+    }
+
+    /// Returns true if the record doesn't describe a file
+    pub fn is_empty(&self) -> bool {
+        self.hash == 0
+    }
+
+    /// Decodes a record from the start of `d`
+    pub fn from_bytes(mut d: &[u8]) -> io::Result<FileMeta> {
+        Ok(FileMeta {
+            mtime_sec: try!(d.read_i64::<BigEndian>()),
+            mtime_nsec: try!(d.read_u32::<BigEndian>()),
+            size: try!(d.read_u64::<BigEndian>()),
+            hash: try!(d.read_u64::<BigEndian>()),
+        })
+    }
+
+    /// Encodes the record into `w`
+    pub fn write_to<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        try!(w.write_i64::<BigEndian>(self.mtime_sec));
+        try!(w.write_u32::<BigEndian>(self.mtime_nsec));
+        try!(w.write_u64::<BigEndian>(self.size));
+        w.write_u64::<BigEndian>(self.hash)
+    }
+}
+
+#[test]
+fn test_roundtrip() {
+    let m = FileMeta::new(Some(UNIX_EPOCH + Duration::new(1500000000, 42)), 1234, 0xdeadbeef);
+    let mut v = Vec::new();
+    m.write_to(&mut v).unwrap();
+    assert_eq!(v.len(), FILE_META_SIZE);
+    let m2 = FileMeta::from_bytes(&v).unwrap();
+    assert_eq!(m, m2);
+    assert_eq!(m2.modified(), UNIX_EPOCH + Duration::new(1500000000, 42));
+}
@@ -1,7 +1,14 @@
+mod error;
+mod meta;
+mod postlist;
 mod read;
 mod search;
 
+pub use self::error::{ReaderError, ReaderErrorKind, ReaderResult};
+pub use self::meta::{FileMeta, FILE_META_SIZE};
+pub use self::postlist::PostList;
 pub use self::read::IndexReader;
 pub use self::read::PostReader;
 pub use self::read::PostSet;
-pub use self::read::POST_ENTRY_SIZE;
+pub use self::read::{IndexVersion, DUPLICATE_ENTRY_SIZE, POST_ENTRY_SIZE, POST_ENTRY_SIZE_V2,
+                     SECTION_ENTRY_SIZE};
@@ -0,0 +1,275 @@
+// Copyright 2016 Vernon Jones.
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+// Posting list decoding.
+//
+// Version 1 posting lists are a plain varint delta stream, so the only way
+// to find a file ID in one is to decode everything before it.
+//
+// Version 2 posting lists are split into blocks of POST_BLOCK_SIZE file IDs
+// and preceded by a skip table with one entry per block:
+//
+// 	last file ID in the block [4]
+// 	offset of the block, from the end of the skip table [4]
+//
+// Each block has the form:
+//
+// 	bit width [1]
+// 	deltas, packed into bit width bits each, least significant bit first
+//
+// and is padded to a whole byte.  The first delta of a list is the first
+// file ID itself; every other delta is the difference from the previous
+// file ID, including the first delta of a block, whose previous file ID is
+// the last entry of the preceding block in the skip table.  That way any
+// block can be decoded on its own, and seeking to a file ID only needs a
+// binary search over the skip table and decoding a single block.
+//
+// The number of blocks follows from the file count in the posting list
+// index, so there's no terminator.
+
+use byteorder::{BigEndian, ByteOrder};
+use consts::POST_BLOCK_SIZE;
+use libvarint;
+
+use super::error::{ReaderError, ReaderErrorKind, ReaderResult};
+use super::read::{FileID, IndexVersion};
+
+/// Size of a skip table entry in a version 2 posting list
+pub const SKIP_ENTRY_SIZE: usize = 4 + 4;
+
+pub fn corrupt_list(trigram: u32, why: &str) -> ReaderError {
+    ReaderError::new(ReaderErrorKind::CorruptPostingList,
+                     format!("corrupt posting list for trigram {:#08x}: {}", trigram, why))
+}
+
+/// Reads the next file ID delta from a version 1 posting list and
+/// advances `d` past it.
+fn read_delta(d: &mut &[u8], trigram: u32) -> ReaderResult<u64> {
+    let (delta, n) = match libvarint::read_uvarint(d) {
+        Ok(r) => r,
+        Err(_) => return Err(corrupt_list(trigram, "bad varint")),
+    };
+    if n == 0 || delta == 0 {
+        return Err(corrupt_list(trigram, "list is shorter than its count"));
+    }
+    *d = &d[n as usize..];
+    Ok(delta)
+}
+
+/// A cursor over the file IDs in one posting list, in increasing order.
+#[derive(Debug)]
+pub struct PostList<'a> {
+    trigram: u32,
+    version: IndexVersion,
+    count: usize,
+    // version 1: the undecoded rest of the list
+    d: &'a [u8],
+    fileid: i64,
+    read: usize,
+    // version 2
+    skip: &'a [u8],
+    blocks: &'a [u8],
+    block: Vec<FileID>,
+    pos: usize,
+    next_block: usize,
+    // file ID found by seek, returned again by the next call to next
+    peeked: Option<FileID>,
+}
+
+impl<'a> PostList<'a> {
+    /// Creates a cursor over the `count` file IDs of a posting list.
+    /// `data` starts just after the list's trigram.
+    pub fn new(data: &'a [u8],
+               trigram: u32,
+               count: usize,
+               version: IndexVersion)
+               -> ReaderResult<PostList<'a>> {
+        let (skip, blocks) = match version {
+            IndexVersion::V1 => (&data[..0], &data[..0]),
+            IndexVersion::V2 => {
+                let skip_len = num_blocks(count).saturating_mul(SKIP_ENTRY_SIZE);
+                if skip_len > data.len() {
+                    return Err(corrupt_list(trigram, "skip table is truncated"));
+                }
+                data.split_at(skip_len)
+            }
+        };
+        Ok(PostList {
+            trigram: trigram,
+            version: version,
+            count: count,
+            d: data,
+            fileid: -1,
+            read: 0,
+            skip: skip,
+            blocks: blocks,
+            block: Vec::new(),
+            pos: 0,
+            next_block: 0,
+            peeked: None,
+        })
+    }
+
+    /// Returns the number of file IDs in the list
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the trigram this list belongs to
+    pub fn trigram(&self) -> u32 {
+        self.trigram
+    }
+
+    /// Returns the next file ID in the list, or None at the end.
+    pub fn next(&mut self) -> ReaderResult<Option<FileID>> {
+        if let Some(id) = self.peeked.take() {
+            return Ok(Some(id));
+        }
+        self.advance()
+    }
+
+    fn advance(&mut self) -> ReaderResult<Option<FileID>> {
+        match self.version {
+            IndexVersion::V1 => {
+                if self.read == self.count {
+                    return Ok(None);
+                }
+                self.read += 1;
+                self.fileid += try!(read_delta(&mut self.d, self.trigram)) as i64;
+                Ok(Some(self.fileid as FileID))
+            }
+            IndexVersion::V2 => {
+                if self.pos == self.block.len() {
+                    if self.next_block == num_blocks(self.count) {
+                        return Ok(None);
+                    }
+                    let n = self.next_block;
+                    try!(self.load_block(n));
+                }
+                self.pos += 1;
+                Ok(Some(self.block[self.pos - 1]))
+            }
+        }
+    }
+
+    /// Skips ahead to the first file ID that is at least `target` and
+    /// returns it, or None if there isn't one.  The cursor is left on the
+    /// returned ID, so the next call to `seek` or `next` can return it
+    /// again.  The cursor never moves backwards, so IDs before the current
+    /// position aren't considered.
+    pub fn seek(&mut self, target: FileID) -> ReaderResult<Option<FileID>> {
+        if let Some(id) = self.peeked {
+            if id >= target {
+                return Ok(Some(id));
+            }
+            self.peeked = None;
+        }
+        self.peeked = try!(self.seek_forward(target));
+        Ok(self.peeked)
+    }
+
+    fn seek_forward(&mut self, target: FileID) -> ReaderResult<Option<FileID>> {
+        if self.version == IndexVersion::V1 {
+            while let Some(id) = try!(self.advance()) {
+                if id >= target {
+                    return Ok(Some(id));
+                }
+            }
+            return Ok(None);
+        }
+
+        let in_block = self.pos < self.block.len() &&
+                       *self.block.last().unwrap() >= target;
+        if !in_block {
+            // binary search the skip table for the first block that can hold target
+            let (mut lo, mut hi) = (self.next_block, num_blocks(self.count));
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                if self.skip_entry(mid).0 < target {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            if lo == num_blocks(self.count) {
+                self.block.clear();
+                self.pos = 0;
+                self.next_block = lo;
+                return Ok(None);
+            }
+            try!(self.load_block(lo));
+        }
+        while self.block[self.pos] < target {
+            self.pos += 1;
+        }
+        self.pos += 1;
+        Ok(Some(self.block[self.pos - 1]))
+    }
+
+    /// Returns the (last file ID, block offset) skip table entry for block `n`
+    fn skip_entry(&self, n: usize) -> (FileID, usize) {
+        let e = &self.skip[n * SKIP_ENTRY_SIZE..];
+        (BigEndian::read_u32(e), BigEndian::read_u32(&e[4..]) as usize)
+    }
+
+    /// Decodes block `n` into `self.block`
+    fn load_block(&mut self, n: usize) -> ReaderResult<()> {
+        let (last, offset) = self.skip_entry(n);
+        let len = ::std::cmp::min(POST_BLOCK_SIZE, self.count - n * POST_BLOCK_SIZE);
+        let mut prev = if n == 0 {
+            None
+        } else {
+            Some(self.skip_entry(n - 1).0)
+        };
+        if offset >= self.blocks.len() {
+            return Err(corrupt_list(self.trigram, "block offset out of range"));
+        }
+        let width = self.blocks[offset] as usize;
+        let data = &self.blocks[offset + 1..];
+        if width > 32 {
+            return Err(corrupt_list(self.trigram, "bad bit width"));
+        }
+        if (len * width + 7) / 8 > data.len() {
+            return Err(corrupt_list(self.trigram, "block is truncated"));
+        }
+
+        self.block.clear();
+        let mask = (1u64 << width) - 1;
+        let mut acc = 0u64;
+        let mut bits = 0;
+        let mut d = data.iter();
+        for _ in 0..len {
+            while bits < width {
+                acc |= (*d.next().unwrap() as u64) << bits;
+                bits += 8;
+            }
+            let delta = (acc & mask) as u32;
+            acc >>= width;
+            bits -= width;
+            let id = match prev {
+                None => Some(delta),
+                Some(_) if delta == 0 => None,
+                Some(p) => p.checked_add(delta),
+            };
+            match id {
+                Some(id) => {
+                    self.block.push(id);
+                    prev = Some(id);
+                }
+                None => return Err(corrupt_list(self.trigram, "file IDs out of order")),
+            }
+        }
+        if prev != Some(last) {
+            return Err(corrupt_list(self.trigram, "block doesn't match its skip entry"));
+        }
+        self.pos = 0;
+        self.next_block = n + 1;
+        Ok(())
+    }
+}
+
+/// Number of blocks needed for `count` file IDs
+fn num_blocks(count: usize) -> usize {
+    (count + POST_BLOCK_SIZE - 1) / POST_BLOCK_SIZE
+}
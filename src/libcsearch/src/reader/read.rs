@@ -7,12 +7,15 @@
 //
 // An index stored on disk has the format:
 //
-// 	"csearch index 1\n"
+// 	"csearch index 2\n"
 // 	list of paths
 // 	list of names
 // 	list of posting lists
+// 	optional sections
 // 	name index
 // 	posting list index
+// 	checksum section
+// 	section table
 // 	trailer
 //
 // The list of paths is a sorted sequence of NUL-terminated file or directory names.
@@ -24,6 +27,16 @@
 // the next to file #1, and so on.  The list ends with an
 // empty name ("\x00").
 //
+// In a version 2 index the names are front-coded, since sorted paths
+// mostly share long directory prefixes.  Each name has the form:
+//
+// 	length of the prefix shared with the previous name [v]
+// 	rest of the name, NUL-terminated
+//
+// Every 16th name (file #0, #16, #32, ...) is a restart point that doesn't
+// share anything with the name before it, so a name can be rebuilt from the
+// closest restart point before it, found through the name index.
+//
 // The list of posting lists are a sequence of posting lists.
 // Each posting list has the form:
 //
@@ -38,15 +51,20 @@
 // not recorded at all.  The list of posting lists ends with an entry
 // with trigram "\xff\xff\xff" and a delta list consisting a single zero.
 //
+// That's the version 1 encoding.  In a version 2 index the trigram is
+// followed by a block-compressed list instead, described in postlist.rs,
+// which can be searched for a file ID without decoding all of it.  The
+// final "\xff\xff\xff" entry is just the trigram.
+//
 // The indexes enable efficient random access to the lists.  The name
-// index is a sequence of 4-byte big-endian values listing the byte
+// index is a sequence of 8-byte big-endian values listing the byte
 // offset in the name list where each name begins.  The posting list
 // index is a sequence of index entries describing each successive
 // posting list.  Each index entry has the form:
 //
 // 	trigram [3]
 // 	file count [4]
-// 	offset [4]
+// 	offset [8]
 //
 // Index entries are only written for the non-empty posting lists,
 // so finding the posting list for a specific trigram requires a
@@ -54,35 +72,160 @@
 // of the possible trigrams are never seen, so omitting the missing
 // ones represents a significant storage savings.
 //
+// The section table describes the optional sections, which are stored
+// between the posting lists and the name index.  Each entry has the form:
+//
+// 	section id [4]
+// 	offset [8]
+// 	length [8]
+//
+// Readers ignore sections with an id they don't recognize, so new sections
+// can be added without changing the format version.
+//
+// The root section holds the directory that paths and names were stored
+// relative to, for indexes built to be moved between machines.  Relative
+// paths and names start with "." standing in for the root, see pathbytes.rs.
+//
+// The options section records the limits on indexed files the index was
+// built with, and the version of the tool that wrote it, as text.  It's
+// written and read by libcindex, see its writer/options.rs.
+//
+// The duplicates section lists files whose contents are identical to a
+// file indexed before them.  Their trigrams are only recorded in the
+// posting lists of the first one, so the index only needs one posting entry
+// for each copy of a vendored library.  It is a sequence of
+//
+// 	file ID of the file in the posting lists [4]
+// 	file ID of a file with the same contents [4]
+//
+// sorted by the first file ID and then the second.  Queries return both.
+//
+// The checksum section is the one optional section that follows the posting
+// list index, since it covers every section before it.  It is a sequence of
+//
+// 	section id [4]
+// 	XXH64 of the section contents [8]
+//
+// where the required sections use the ids 0x100 (path list) through 0x104
+// (posting list index), in the order listed above.  Each required section
+// ends where the next section in the file begins.
+//
 // The trailer has the form:
 //
-// 	offset of path list [4]
-// 	offset of name list [4]
-// 	offset of posting lists [4]
-// 	offset of name index [4]
-// 	offset of posting list index [4]
+// 	offset of path list [8]
+// 	offset of name list [8]
+// 	offset of posting lists [8]
+// 	offset of name index [8]
+// 	offset of posting list index [8]
+// 	offset of section table [8]
 // 	"\ncsearch trailr\n"
+//
+// Version 1 indexes ("csearch index 1\n", the format written by the Go
+// implementation) are also readable.  They have no optional sections or
+// section table, and every offset (trailer, name index and posting list
+// index) is stored in 4 bytes instead of 8, which limits them to 4 GB.
 
+use std::borrow::Cow;
 use std::collections::BTreeSet;
+use std::ffi::OsString;
 use std::path::Path;
-use std::io;
 use std::fmt;
 use std::fmt::Debug;
+use std::hash::Hasher;
 use std::io::Cursor;
 
-use consts::TRAILER_MAGIC;
+use consts::{MAGIC, MAGIC_PREFIX, MAGIC_V2, SECTION_FILE_META, SECTION_CHECKSUMS, SECTION_ROOT,
+             SECTION_DUPLICATES, TRAILER_MAGIC};
+use consts::NAME_RESTART_INTERVAL;
+use consts::{SECTION_PATHS, SECTION_NAMES, SECTION_POSTING_LISTS, SECTION_NAME_INDEX,
+             SECTION_POSTING_INDEX};
 use memmap::{Mmap, Protection};
 use byteorder::{BigEndian, ReadBytesExt};
 use libvarint;
+use twox_hash::XxHash64;
 
+use pathbytes;
 use regexp::{Query, QueryOperation};
 use super::search;
+use super::error::{ReaderError, ReaderErrorKind, ReaderResult};
+use super::meta::{FileMeta, FILE_META_SIZE};
+use super::postlist::{PostList, corrupt_list};
 
+/// Size of a posting list index entry in a version 1 index
 pub const POST_ENTRY_SIZE: usize = 3 + 4 + 4;
+/// Size of a posting list index entry in a version 2 index
+pub const POST_ENTRY_SIZE_V2: usize = 3 + 4 + 8;
+/// Size of a section table entry
+pub const SECTION_ENTRY_SIZE: usize = 4 + 8 + 8;
+/// Size of an entry in the duplicates section
+pub const DUPLICATE_ENTRY_SIZE: usize = 4 + 4;
 
 /// Simple alias for an ID representing a filename in the Index.
 pub type FileID = u32;
 
+/// An entry in the section table
+#[derive(Debug, Copy, Clone)]
+struct SectionEntry {
+    id: u32,
+    offset: usize,
+    len: usize,
+}
+
+/// The bytes of an index: a file mapped into memory, or a buffer
+enum IndexData {
+    Mapped(Mmap),
+    Bytes(Cow<'static, [u8]>),
+}
+
+impl IndexData {
+    /// Unsafe because a mapped file can change underneath the slice
+    unsafe fn as_slice(&self) -> &[u8] {
+        match *self {
+            IndexData::Mapped(ref m) => m.as_slice(),
+            IndexData::Bytes(ref b) => b,
+        }
+    }
+    fn len(&self) -> usize {
+        match *self {
+            IndexData::Mapped(ref m) => m.len(),
+            IndexData::Bytes(ref b) => b.len(),
+        }
+    }
+}
+
+/// On-disk format of an index
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IndexVersion {
+    /// The original format shared with the Go implementation, using 4-byte offsets
+    V1,
+    /// 8-byte offsets and optional sections
+    V2,
+}
+
+impl IndexVersion {
+    /// Size in bytes of an offset stored in the trailer or the name index
+    pub fn offset_size(&self) -> usize {
+        match *self {
+            IndexVersion::V1 => 4,
+            IndexVersion::V2 => 8,
+        }
+    }
+    /// Size in bytes of a posting list index entry
+    pub fn post_entry_size(&self) -> usize {
+        match *self {
+            IndexVersion::V1 => POST_ENTRY_SIZE,
+            IndexVersion::V2 => POST_ENTRY_SIZE_V2,
+        }
+    }
+    /// Number of offsets stored in the trailer
+    fn trailer_offsets(&self) -> usize {
+        match *self {
+            IndexVersion::V1 => 5,
+            IndexVersion::V2 => 6,
+        }
+    }
+}
+
 
 /// Representation of an Index
 ///
@@ -100,21 +243,26 @@ pub type FileID = u32;
 ///
 /// let idx = try!(IndexReader::open("foo.txt"));
 ///
-/// let matching_file_ids = idx.query(q);
+/// let matching_file_ids = try!(idx.query(q));
 ///
 /// for each in matching_file_ids.into_inner() {
-///    println!("filename = {}", idx.name(each));
+///    println!("filename = {:?}", idx.name(each));
 /// }
 /// # Ok(())
 /// # }
 /// ```
 pub struct IndexReader {
-    data: Mmap,
-    path_data: u32,
-    name_data: u32,
-    pub post_data: u32,
+    data: IndexData,
+    pub version: IndexVersion,
+    path_data: u64,
+    name_data: u64,
+    pub post_data: u64,
     name_index: usize,
     pub post_index: usize,
+    section_table: usize,
+    sections: Vec<SectionEntry>,
+    root: Option<Vec<u8>>,
+    root_map: Vec<(Vec<u8>, Vec<u8>)>,
     pub num_name: usize,
     pub num_post: usize,
 }
@@ -122,7 +270,8 @@ pub struct IndexReader {
 impl Debug for IndexReader {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         write!(f,
-               "({}, {}, {}, {}, {}, {}, {}",
+               "({:?}, {}, {}, {}, {}, {}, {}, {}",
+               self.version,
                self.path_data,
                self.name_data,
                self.post_data,
@@ -133,21 +282,51 @@ impl Debug for IndexReader {
     }
 }
 
-fn extract_data_from_mmap(data: &Mmap, offset: usize) -> u32 {
-    unsafe {
-        let mut buf = Cursor::new(&data.as_slice()[offset..offset + 4]);
-        buf.read_u32::<BigEndian>().unwrap()
+fn extract_data_from_slice(data: &[u8], version: IndexVersion, offset: usize) -> u64 {
+    let mut buf = Cursor::new(&data[offset..offset + version.offset_size()]);
+    match version {
+        IndexVersion::V1 => buf.read_u32::<BigEndian>().unwrap() as u64,
+        IndexVersion::V2 => buf.read_u64::<BigEndian>().unwrap(),
+    }
+}
+
+fn version_from_slice(header: &[u8]) -> ReaderResult<IndexVersion> {
+    if header.starts_with(MAGIC_V2.as_bytes()) {
+        Ok(IndexVersion::V2)
+    } else if header.starts_with(MAGIC.as_bytes()) {
+        Ok(IndexVersion::V1)
+    } else if header.starts_with(MAGIC_PREFIX.as_bytes()) {
+        let version = header[MAGIC_PREFIX.len()..]
+            .iter()
+            .take_while(|&&b| b != b'\n')
+            .take(16)
+            .map(|&b| b as char)
+            .collect::<String>();
+        Err(ReaderError::new(ReaderErrorKind::UnsupportedVersion,
+                             format!("unsupported index version {:?}", version)))
+    } else {
+        Err(ReaderError::new(ReaderErrorKind::BadMagic, "missing index header"))
     }
 }
 
+fn out_of_range(what: &str, offset: usize) -> ReaderError {
+    ReaderError::new(ReaderErrorKind::OffsetOutOfRange,
+                     format!("{} offset {} is out of range", what, offset))
+}
+
 
 impl IndexReader {
-    fn extract_data(&self, offset: usize) -> u32 {
-        unsafe {
-            let mut buf = Cursor::new(&self.data.as_slice()[offset..offset + 4]);
-            buf.read_u32::<BigEndian>().unwrap()
-        }
+    fn extract_data(&self, offset: usize) -> u64 {
+        extract_data_from_slice(unsafe { self.data.as_slice() }, self.version, offset)
     }
+    /// Open an index file from path, checking every section against its
+    /// checksum before returning. See `IndexReader::verify`.
+    pub fn open_verified<P: AsRef<Path>>(path: P) -> ReaderResult<IndexReader> {
+        let ix = try!(IndexReader::open(path));
+        try!(ix.verify());
+        Ok(ix)
+    }
+
     /// Open an index file from path
     ///
     /// ```no_run
@@ -157,75 +336,194 @@ impl IndexReader {
     /// let idx = try!(IndexReader::open("foo.txt"));
     /// # Ok(())
     /// # }
-    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<IndexReader> {
-        Mmap::open_path(path, Protection::Read).map(|m| {
-            let n = m.len() - (TRAILER_MAGIC.bytes().len()) - 5 * 4;
-            let path_data = extract_data_from_mmap(&m, n);
-            let name_data = extract_data_from_mmap(&m, n + 4);
-            let post_data = extract_data_from_mmap(&m, n + 8);
-            let name_index = extract_data_from_mmap(&m, n + 12) as usize;
-            let post_index = extract_data_from_mmap(&m, n + 16) as usize;
-            let num_name: usize = if post_index > name_index {
-                let d = (post_index - name_index) / 4;
-                if d == 0 {
-                    0
-                } else {
-                    (d - 1) as usize
+    /// ```
+    ///
+    /// The header, trailer and section offsets are checked before the
+    /// index is returned, so a truncated or foreign file is reported as a
+    /// `ReaderError` instead of causing a panic later on.
+    pub fn open<P: AsRef<Path>>(path: P) -> ReaderResult<IndexReader> {
+        let m = try!(Mmap::open_path(path, Protection::Read));
+        IndexReader::from_data(IndexData::Mapped(m))
+    }
+
+    /// Reads an index held in memory, such as one written by
+    /// `IndexWriter::in_memory`.  Takes either an owned buffer or a
+    /// `&'static [u8]`, e.g. from `include_bytes!`.
+    ///
+    /// ```
+    /// # use libcsearch::reader::{IndexReader, ReaderErrorKind};
+    /// let err = IndexReader::from_bytes(&b"not an index"[..]).unwrap_err();
+    /// assert_eq!(err.kind(), ReaderErrorKind::Truncated);
+    /// ```
+    ///
+    /// The same checks as in `IndexReader::open` are made.
+    pub fn from_bytes<B: Into<Cow<'static, [u8]>>>(data: B) -> ReaderResult<IndexReader> {
+        IndexReader::from_data(IndexData::Bytes(data.into()))
+    }
+
+    fn from_data(data: IndexData) -> ReaderResult<IndexReader> {
+        let m = unsafe { data.as_slice() };
+        let header_len = MAGIC.len();
+        if m.len() < header_len {
+            return Err(ReaderError::new(ReaderErrorKind::Truncated,
+                                        format!("index is only {} bytes long", m.len())));
+        }
+        let version = try!(version_from_slice(m));
+        let w = version.offset_size();
+        let trailer_len = TRAILER_MAGIC.len() + version.trailer_offsets() * w;
+        if m.len() < header_len + trailer_len {
+            return Err(ReaderError::new(ReaderErrorKind::Truncated,
+                                        format!("index is only {} bytes long", m.len())));
+        }
+        if !m.ends_with(TRAILER_MAGIC.as_bytes()) {
+            return Err(ReaderError::new(ReaderErrorKind::BadMagic, "missing index trailer"));
+        }
+        let n = m.len() - trailer_len;
+        let path_data = extract_data_from_slice(m, version, n);
+        let name_data = extract_data_from_slice(m, version, n + w);
+        let post_data = extract_data_from_slice(m, version, n + 2 * w);
+        let name_index = extract_data_from_slice(m, version, n + 3 * w) as usize;
+        let post_index = extract_data_from_slice(m, version, n + 4 * w) as usize;
+        let section_table = match version {
+            IndexVersion::V1 => n,
+            IndexVersion::V2 => extract_data_from_slice(m, version, n + 5 * w) as usize,
+        };
+
+        // every section has to start after the one before it, and the
+        // last one has to end before the trailer
+        let offsets = [("path list", path_data as usize),
+                       ("name list", name_data as usize),
+                       ("posting list", post_data as usize),
+                       ("name index", name_index),
+                       ("posting list index", post_index),
+                       ("section table", section_table)];
+        let mut prev = header_len;
+        for &(what, offset) in &offsets {
+            if offset < prev || offset > n {
+                return Err(out_of_range(what, offset));
+            }
+            prev = offset;
+        }
+        if (n - section_table) % SECTION_ENTRY_SIZE != 0 {
+            return Err(ReaderError::new(ReaderErrorKind::Truncated,
+                                        "section table has a partial entry"));
+        }
+
+        let sections = m[section_table..n]
+            .chunks(SECTION_ENTRY_SIZE)
+            .map(|e| {
+                SectionEntry {
+                    id: (&e[..4]).read_u32::<BigEndian>().unwrap(),
+                    offset: read_offset(&e[4..], IndexVersion::V2) as usize,
+                    len: read_offset(&e[12..], IndexVersion::V2) as usize,
                 }
-            } else {
+            })
+            .collect::<Vec<_>>();
+        for e in &sections {
+            let in_bounds = e.offset >= post_data as usize &&
+                            e.offset.checked_add(e.len).map_or(false, |end| end <= section_table);
+            if !in_bounds {
+                return Err(out_of_range(&format!("section {:#x}", e.id), e.offset));
+            }
+        }
+        // optional sections may follow the posting list index
+        let post_index_end = sections.iter()
+            .map(|e| e.offset)
+            .filter(|&o| o > post_index)
+            .fold(section_table, ::std::cmp::min);
+        let num_name: usize = if post_index > name_index {
+            let d = (post_index - name_index) / w;
+            if d == 0 {
                 0
-            };
-            let num_post = if n > (post_index as usize) {
-                (n - (post_index as usize)) / (3 + 4 + 4)
             } else {
-                0
-            };
-            IndexReader {
-                data: m,
-                path_data: path_data,
-                name_data: name_data,
-                post_data: post_data,
-                name_index: name_index,
-                post_index: post_index,
-                num_name: num_name,
-                num_post: num_post,
+                (d - 1) as usize
             }
+        } else {
+            0
+        };
+        let num_post = if post_index_end > post_index {
+            (post_index_end - post_index) / version.post_entry_size()
+        } else {
+            0
+        };
+        let root = sections.iter()
+            .find(|e| e.id == SECTION_ROOT)
+            .map(|e| m[e.offset..e.offset + e.len].to_vec());
+        Ok(IndexReader {
+            data: data,
+            version: version,
+            path_data: path_data,
+            name_data: name_data,
+            post_data: post_data,
+            name_index: name_index,
+            post_index: post_index,
+            section_table: section_table,
+            sections: sections,
+            root: root,
+            root_map: Vec::new(),
+            num_name: num_name,
+            num_post: num_post,
         })
     }
 
-    /// Takes a query and returns a list of matching file IDs.
-    pub fn query<'a>(&'a self, query: Query) -> PostSet<'a> {
+    /// Takes a query and returns a list of matching file IDs, including
+    /// the duplicates of every file that matched (see `duplicates`).
+    ///
+    /// Fails if one of the posting lists the query needs is corrupt.
+    pub fn query<'a>(&'a self, query: Query) -> ReaderResult<PostSet<'a>> {
+        let mut post_set = try!(self.query_posting_lists(query));
+        if self.section(SECTION_DUPLICATES).is_some() {
+            let dups = post_set.list
+                .iter()
+                .flat_map(|&id| self.duplicates(id))
+                .collect::<Vec<_>>();
+            post_set.list.extend(dups);
+        }
+        Ok(post_set)
+    }
+
+    /// Returns the files matching `query` that have posting entries of
+    /// their own
+    fn query_posting_lists<'a>(&'a self, query: Query) -> ReaderResult<PostSet<'a>> {
         // writeln!(io::stderr(), "query {:?}", query).unwrap();
         match query.operation {
-            QueryOperation::None => PostSet::new(self),
-            QueryOperation::All => PostSet {
+            QueryOperation::None => Ok(PostSet::new(self)),
+            QueryOperation::All => Ok(PostSet {
                 index: self,
                 list: (0..self.num_name as u32).collect::<BTreeSet<FileID>>()
-            },
+            }),
             QueryOperation::And => {
                 // writeln!(io::stderr(), "AND {:?}", query.trigram).unwrap();
-                let mut trigram_it = query.trigram
+                // start from the rarest trigram so that every intersection
+                // only has to look up a few file IDs in the longer lists
+                let mut trigrams = query.trigram
                     .into_iter()
                     .map(|t| {
                         (t[0] as u32) << 16 | (t[1] as u32) << 8 | (t[2] as u32)
-                    });
-                let mut sub_iter = query.sub.into_iter().map(|q| self.query(q));
-                let post_set = if let Some(i) = trigram_it.next() {
-                    let s = PostSet::new(self).or(i).unwrap_or(PostSet::new(self));
-                    Some(trigram_it.fold(s, |a, b| a.and(b).unwrap_or(PostSet::new(self))))
-                } else {
-                    sub_iter.next()
-                };
-                let post_set = if let Some(ps) = post_set {
-                    ps
+                    })
+                    .collect::<Vec<_>>();
+                trigrams.sort_by_key(|&t| self.find_list(t).0);
+                let mut trigram_it = trigrams.into_iter();
+                let mut sub_iter = query.sub.into_iter();
+                let mut post_set = if let Some(i) = trigram_it.next() {
+                    let mut s = try!(PostSet::new(self).or(i));
+                    for t in trigram_it {
+                        if s.list.is_empty() {
+                            break;
+                        }
+                        s = try!(s.and(t));
+                    }
+                    s
+                } else if let Some(q) = sub_iter.next() {
+                    try!(self.query_posting_lists(q))
                 } else {
-                    return PostSet::new(self);
+                    return Ok(PostSet::new(self));
                 };
-                let sub_iter = sub_iter.map(|q| q.into_inner());
-                sub_iter.fold(post_set, |mut a, b| {
-                    a.list = &a.list & &b;
-                    a
-                })
+                for q in sub_iter {
+                    let b = try!(self.query_posting_lists(q)).into_inner();
+                    post_set.list = &post_set.list & &b;
+                }
+                Ok(post_set)
             },
             QueryOperation::Or => {
                 // writeln!(io::stderr(), "OR {:?}", query.trigram).unwrap();
@@ -234,15 +532,15 @@ impl IndexReader {
                     .map(|t| {
                         (t[0] as u32) << 16 | (t[1] as u32) << 8 | (t[2] as u32)
                     });
-                let post_set = trigram_it.fold(PostSet::new(self), |a, b| {
-                    a.or(b).unwrap_or(PostSet::new(self))
-                });
+                let mut post_set = PostSet::new(self);
+                for t in trigram_it {
+                    post_set = try!(post_set.or(t));
+                }
                 // writeln!(io::stderr(), "post set size = {:?}", post_set.list.len()).unwrap();
-                query.sub.into_iter().map(|q| self.query(q).into_inner())
-                    .fold(post_set, |mut a, b| {
-                        a.list.extend(b.into_iter());
-                        a
-                    })
+                for q in query.sub {
+                    post_set.list.extend(try!(self.query_posting_lists(q)).into_inner());
+                }
+                Ok(post_set)
             }
         }
     }
@@ -252,38 +550,177 @@ impl IndexReader {
         self.data.len()
     }
 
+    /// Returns the on-disk format of the index
+    pub fn version(&self) -> IndexVersion {
+        self.version
+    }
+
     /// Returns the index as a slice
     pub unsafe fn as_slice(&self) -> &[u8] {
         self.data.as_slice()
     }
 
+    /// Returns the directory the index stores paths and names relative to,
+    /// or None if they're stored as absolute paths.
+    pub fn root(&self) -> Option<OsString> {
+        self.root.clone().map(pathbytes::from_bytes)
+    }
+
+    /// Makes every path and name in or under `old` appear to be in `new`
+    /// instead, so an index built in one place can be used in another.
+    ///
+    /// Relative names are resolved against the root first, so for an index
+    /// built with its root at `/build/src`, `map_root("/build/src",
+    /// "/home/me/src")` works the same way for relative and absolute names.
+    /// The first mapping that matches a name is used.
+    pub fn map_root<P1, P2>(&mut self, old: P1, new: P2)
+        where P1: AsRef<Path>,
+              P2: AsRef<Path>
+    {
+        let old = pathbytes::to_bytes(old.as_ref().as_os_str()).into_owned();
+        let new = pathbytes::to_bytes(new.as_ref().as_os_str()).into_owned();
+        self.root_map.push((old, new));
+    }
+
+    /// Turns a path or name as stored in the index into a full path
+    fn resolve(&self, stored: Vec<u8>) -> Vec<u8> {
+        let name = match self.root {
+            Some(ref root) => pathbytes::resolve(&stored, root),
+            None => stored,
+        };
+        self.root_map
+            .iter()
+            .filter_map(|&(ref old, ref new)| pathbytes::replace_dir(&name, old, new))
+            .next()
+            .unwrap_or(name)
+    }
+
     /// Returns all indexed paths
-    pub fn indexed_paths(&self) -> Vec<String> {
+    pub fn indexed_paths(&self) -> Vec<OsString> {
+        self.indexed_path_bytes().into_iter().map(pathbytes::from_bytes).collect()
+    }
+
+    /// Returns all indexed paths as raw bytes
+    pub fn indexed_path_bytes(&self) -> Vec<Vec<u8>> {
+        self.stored_paths().into_iter().map(|p| self.resolve(p)).collect()
+    }
+
+    /// Returns all indexed paths exactly as they're stored in the index,
+    /// without resolving them against the root.
+    pub fn stored_paths(&self) -> Vec<Vec<u8>> {
         let mut paths = Vec::new();
         let mut offset = self.path_data as usize;
         loop {
-            let s = self.extract_string_at(offset);
+            let s = self.bytes_at(offset);
             if s.len() == 0 {
                 break;
             }
             offset += s.len() + 1;
-            paths.push(s);
+            paths.push(s.to_vec());
         }
         paths
     }
 
     /// Returns the name of a file identified by file_id
-    pub fn name(&self, file_id: FileID) -> String {
+    pub fn name(&self, file_id: FileID) -> OsString {
+        pathbytes::from_bytes(self.name_bytes(file_id))
+    }
+
+    /// Returns the name of a file identified by file_id as raw bytes
+    pub fn name_bytes(&self, file_id: FileID) -> Vec<u8> {
+        self.resolve(self.stored_name(file_id))
+    }
+
+    /// Returns the name of a file identified by file_id exactly as it's
+    /// stored in the index, without resolving it against the root.
+    pub fn stored_name(&self, file_id: FileID) -> Vec<u8> {
         let file_id_usize = file_id as usize;
-        let offset = self.extract_data(self.name_index + 4 * file_id_usize);
-        self.extract_string_at((self.name_data + offset) as usize)
+        let w = self.version.offset_size();
+        if self.version == IndexVersion::V1 {
+            let offset = self.extract_data(self.name_index + w * file_id_usize);
+            return self.bytes_at((self.name_data + offset) as usize).to_vec();
+        }
+
+        // decode forward from the last restart point
+        let restart = file_id_usize - file_id_usize % NAME_RESTART_INTERVAL;
+        let offset = self.extract_data(self.name_index + w * restart);
+        let mut d = unsafe { &self.as_slice()[(self.name_data + offset) as usize..] };
+        let mut name = Vec::new();
+        for _ in restart..file_id_usize + 1 {
+            let (shared, n) = libvarint::read_uvarint(d).unwrap_or((0, 0));
+            d = &d[n as usize..];
+            let len = d.iter().position(|&b| b == 0).unwrap_or(d.len());
+            name.truncate(shared as usize);
+            name.extend_from_slice(&d[..len]);
+            d = &d[::std::cmp::min(len + 1, d.len())..];
+        }
+        name
     }
 
-    pub fn list_at(&self, offset: usize) -> (u32, u32, u32) {
+    /// Returns the metadata recorded for `file_id` when it was indexed,
+    /// or None if the index doesn't have any.
+    pub fn file_meta(&self, file_id: FileID) -> Option<FileMeta> {
+        if (file_id as usize) >= self.num_name {
+            return None;
+        }
+        let section = match self.section(SECTION_FILE_META) {
+            Some(s) => s,
+            None => return None,
+        };
+        let start = (file_id as usize) * FILE_META_SIZE;
+        if start + FILE_META_SIZE > section.len() {
+            return None;
+        }
+        match FileMeta::from_bytes(&section[start..]) {
+            Ok(m) if !m.is_empty() => Some(m),
+            _ => None,
+        }
+    }
+
+    /// Returns the files with the same contents as `file_id` that share its
+    /// posting entries, in file ID order.  They're left out of the posting
+    /// lists, so a query matching `file_id` matches them too.
+    pub fn duplicates(&self, file_id: FileID) -> Vec<FileID> {
+        let section = match self.section(SECTION_DUPLICATES) {
+            Some(s) => s,
+            None => return Vec::new(),
+        };
+        let n = section.len() / DUPLICATE_ENTRY_SIZE;
+        let entry = |i: usize| {
+            let mut d = &section[i * DUPLICATE_ENTRY_SIZE..];
+            (d.read_u32::<BigEndian>().unwrap(), d.read_u32::<BigEndian>().unwrap())
+        };
+        let first = search::search(n, |i| entry(i).0 >= file_id);
+        (first..n)
+            .map(entry)
+            .take_while(|&(id, _)| id == file_id)
+            .map(|(_, dup)| dup)
+            .collect()
+    }
+
+    /// Returns every (file ID in the posting lists, duplicate) pair in the
+    /// duplicates section, sorted
+    pub fn all_duplicates(&self) -> Vec<(FileID, FileID)> {
+        self.section(SECTION_DUPLICATES)
+            .unwrap_or(&[])
+            .chunks(DUPLICATE_ENTRY_SIZE)
+            .filter(|e| e.len() == DUPLICATE_ENTRY_SIZE)
+            .map(|mut e| (e.read_u32::<BigEndian>().unwrap(), e.read_u32::<BigEndian>().unwrap()))
+            .collect()
+    }
+
+    /// Returns the size in bytes of an entry in the posting list index
+    pub fn post_entry_size(&self) -> usize {
+        self.version.post_entry_size()
+    }
+
+    /// Returns the (trigram, count, offset) of the posting list index entry
+    /// at byte `offset` into the posting list index
+    pub fn list_at(&self, offset: usize) -> (u32, u32, u64) {
         let d: &[u8] = unsafe {
             let s = self.data.as_slice();
             let (_, right_side) = s.split_at(self.post_index + offset);
-            let (d, _) = right_side.split_at(POST_ENTRY_SIZE);
+            let (d, _) = right_side.split_at(self.post_entry_size());
             d
         };
         let tri_val = (d[0] as u32) << 16 | (d[1] as u32) << 8 | (d[2] as u32);
@@ -292,36 +729,129 @@ impl IndexReader {
             right.read_u32::<BigEndian>().unwrap()
         };
         let offset = {
-            let (_, mut right) = d.split_at(3 + 4);
-            right.read_u32::<BigEndian>().unwrap()
+            let (_, right) = d.split_at(3 + 4);
+            read_offset(right, self.version)
         };
         (tri_val, count, offset)
     }
 
-    /// Extract a null-terminated string from `offset`
-    fn extract_string_at(&self, offset: usize) -> String {
-        let mut index = 0;
-        let mut s = String::new();
-        unsafe {
-            let sl = self.as_slice();
-            while sl[offset + index] != 0 {
-                s.push(sl[offset + index] as char);
-                index += 1;
+    /// Returns a cursor over the posting list for `trigram`, or None if no
+    /// file contains it.
+    pub fn post_list(&self, trigram: u32) -> ReaderResult<Option<PostList>> {
+        let (count, offset) = self.find_list(trigram);
+        if count <= 0 {
+            return Ok(None);
+        }
+        self.post_list_at(trigram, count as usize, offset).map(Some)
+    }
+
+    /// Returns a cursor over the posting list of `count` file IDs at
+    /// `offset` into the posting lists, as listed in the posting list index.
+    pub fn post_list_at(&self, trigram: u32, count: usize, offset: u64) -> ReaderResult<PostList> {
+        let v = unsafe { self.data.as_slice() };
+        let start = self.post_data.saturating_add(offset).saturating_add(3);
+        if start > self.name_index as u64 {
+            return Err(corrupt_list(trigram, "offset is past the end of the posting lists"));
+        }
+        PostList::new(&v[start as usize..self.name_index], trigram, count, self.version)
+    }
+
+    /// Returns the contents of the optional section identified by `id`,
+    /// or None if the index doesn't have one.
+    pub fn section(&self, id: u32) -> Option<&[u8]> {
+        let s = unsafe { self.data.as_slice() };
+        self.sections
+            .iter()
+            .find(|e| e.id == id)
+            .map(|e| &s[e.offset..e.offset + e.len])
+    }
+
+    /// Returns the byte range of every section in the index, keyed by section id.
+    ///
+    /// The required sections don't record their length, so each one is
+    /// taken to end where the next section (of any kind) begins.
+    fn section_ranges(&self) -> Vec<(u32, usize, usize)> {
+        let mut starts = vec![self.path_data as usize,
+                              self.name_data as usize,
+                              self.post_data as usize,
+                              self.name_index,
+                              self.post_index,
+                              self.section_table];
+        starts.extend(self.sections.iter().map(|e| e.offset));
+        let end_of = |start: usize| {
+            starts.iter()
+                .cloned()
+                .filter(|&o| o > start)
+                .min()
+                .unwrap_or(self.section_table)
+        };
+        let mut ranges = vec![(SECTION_PATHS, self.path_data as usize),
+                              (SECTION_NAMES, self.name_data as usize),
+                              (SECTION_POSTING_LISTS, self.post_data as usize),
+                              (SECTION_NAME_INDEX, self.name_index),
+                              (SECTION_POSTING_INDEX, self.post_index)]
+            .into_iter()
+            .map(|(id, start)| (id, start, end_of(start)))
+            .collect::<Vec<_>>();
+        ranges.extend(self.sections.iter().map(|e| (e.id, e.offset, e.offset + e.len)));
+        ranges
+    }
+
+    /// Checks every section of the index against the checksum recorded
+    /// for it when the index was written.
+    ///
+    /// This reads the whole index, so it's meant for maintenance jobs rather
+    /// than for every search.  Indexes written without checksums (including
+    /// all version 1 indexes) have nothing to check and always pass.
+    pub fn verify(&self) -> ReaderResult<()> {
+        let mut checksums = match self.section(SECTION_CHECKSUMS) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let s = unsafe { self.data.as_slice() };
+        let ranges = self.section_ranges();
+        while !checksums.is_empty() {
+            let id = try!(checksums.read_u32::<BigEndian>());
+            let want = try!(checksums.read_u64::<BigEndian>());
+            let (start, end) = match ranges.iter().find(|r| r.0 == id) {
+                Some(&(_, start, end)) => (start, end),
+                None => {
+                    return Err(ReaderError::new(ReaderErrorKind::ChecksumMismatch,
+                                                format!("checksum for missing section {:#x}",
+                                                        id)))
+                }
+            };
+            if end > s.len() || start > end {
+                return Err(out_of_range(&format!("section {:#x}", id), start));
+            }
+            let mut h = XxHash64::with_seed(0);
+            h.write(&s[start..end]);
+            if h.finish() != want {
+                return Err(ReaderError::new(ReaderErrorKind::ChecksumMismatch,
+                                            format!("checksum mismatch in section {:#x}", id)));
             }
-            s
         }
+        Ok(())
+    }
+
+    /// Returns the NUL-terminated string at `offset`, without the NUL
+    fn bytes_at(&self, offset: usize) -> &[u8] {
+        let d = unsafe { &self.as_slice()[offset..] };
+        let len = d.iter().position(|&b| b == 0).unwrap_or(d.len());
+        &d[..len]
     }
 
     /// Returns the offset and size of a list
-    fn find_list(&self, trigram: u32) -> (isize, u32) {
+    fn find_list(&self, trigram: u32) -> (isize, u64) {
+        let entry_size = self.post_entry_size();
         let d: &[u8] = unsafe {
             let s = self.data.as_slice();
             let (_, right_side) = s.split_at(self.post_index);
-            let (d, _) = right_side.split_at(POST_ENTRY_SIZE * self.num_post);
+            let (d, _) = right_side.split_at(entry_size * self.num_post);
             d
         };
         let result = search::search(self.num_post, |i| {
-            let i_scaled = i * POST_ENTRY_SIZE;
+            let i_scaled = i * entry_size;
             let tri_val = (d[i_scaled] as u32) << 16 | (d[i_scaled + 1] as u32) << 8 |
                           (d[i_scaled + 2] as u32);
             tri_val >= trigram
@@ -329,7 +859,7 @@ impl IndexReader {
         if result >= self.num_post {
             return (0, 0);
         }
-        let result_scaled: usize = result * POST_ENTRY_SIZE;
+        let result_scaled: usize = result * entry_size;
         let tri_val = (d[result_scaled] as u32) << 16 | (d[result_scaled + 1] as u32) << 8 |
                       (d[result_scaled + 2] as u32);
         if tri_val != trigram {
@@ -340,20 +870,24 @@ impl IndexReader {
             right.read_i32::<BigEndian>().unwrap() as isize
         };
         let offset = {
-            let (_, mut right) = d.split_at(result_scaled + 3 + 4);
-            right.read_u32::<BigEndian>().unwrap()
+            let (_, right) = d.split_at(result_scaled + 3 + 4);
+            read_offset(right, self.version)
         };
         (count, offset)
     }
 }
 
+/// Reads a big-endian offset from the start of `d`, sized according to `version`
+fn read_offset(mut d: &[u8], version: IndexVersion) -> u64 {
+    match version {
+        IndexVersion::V1 => d.read_u32::<BigEndian>().unwrap() as u64,
+        IndexVersion::V2 => d.read_u64::<BigEndian>().unwrap(),
+    }
+}
+
 #[derive(Debug)]
 pub struct PostReader<'a, 'b> {
-    index: &'a IndexReader,
-    count: isize,
-    offset: u32,
-    fileid: i64,
-    d: &'a [u8],
+    list: PostList<'a>,
     restrict: &'b Option<BTreeSet<u32>>,
 }
 
@@ -361,93 +895,77 @@ impl<'a, 'b> PostReader<'a, 'b> {
     pub fn new(index: &'a IndexReader,
                trigram: u32,
                restrict: &'b Option<BTreeSet<u32>>)
-               -> Option<Self> {
-        let (count, offset) = index.find_list(trigram);
-        if count == 0 {
-            return None;
-        }
-        let view = unsafe {
-            let v = index.data.as_slice();
-            let split_point = (index.post_data as usize) + (offset as usize) + 3;
-            v.split_at(split_point).1
-        };
-        Some(PostReader {
-            index: index,
-            count: count,
-            offset: offset,
-            fileid: -1,
-            d: view,
-            restrict: restrict,
-        })
+               -> ReaderResult<Option<Self>> {
+        Ok(try!(index.post_list(trigram)).map(|l| {
+            PostReader {
+                list: l,
+                restrict: restrict,
+            }
+        }))
     }
     pub fn and(index: &'a IndexReader,
                list: BTreeSet<u32>,
                trigram: u32,
                restrict: &'b Option<BTreeSet<u32>>)
-               -> BTreeSet<u32> {
-        if let Some(mut r) = Self::new(index, trigram, restrict) {
+               -> ReaderResult<BTreeSet<u32>> {
+        if let Some(mut r) = try!(Self::new(index, trigram, restrict)) {
             let mut h = BTreeSet::new();
-            while r.next() {
-                let fileid = r.fileid;
-                if list.contains(&(fileid as u32)) {
-                    h.insert(fileid as u32);
+            for fileid in list {
+                match try!(r.list.seek(fileid)) {
+                    Some(f) if f == fileid && r.allowed(f) => {
+                        h.insert(f);
+                    }
+                    Some(_) => (),
+                    None => break,
                 }
             }
-            h
+            Ok(h)
         } else {
-            BTreeSet::new()
+            Ok(BTreeSet::new())
         }
     }
     pub fn or(index: &'a IndexReader,
               list: BTreeSet<u32>,
               trigram: u32,
               restrict: &'b Option<BTreeSet<u32>>)
-              -> BTreeSet<u32> {
-        if let Some(mut r) = Self::new(index, trigram, restrict) {
+              -> ReaderResult<BTreeSet<u32>> {
+        if let Some(mut r) = try!(Self::new(index, trigram, restrict)) {
             let mut h = list;
-            while r.next() {
-                h.insert(r.fileid as u32);
+            while let Some(fileid) = try!(r.next()) {
+                h.insert(fileid);
             }
-            h
+            Ok(h)
         } else {
-            BTreeSet::new()
+            Ok(list)
         }
     }
-    pub fn list(index: &'a IndexReader, trigram: u32, restrict: &Option<BTreeSet<u32>>) -> BTreeSet<u32> {
-        if let Some(mut r) = Self::new(index, trigram, restrict) {
+    pub fn list(index: &'a IndexReader,
+                trigram: u32,
+                restrict: &'b Option<BTreeSet<u32>>)
+                -> ReaderResult<BTreeSet<u32>> {
+        if let Some(mut r) = try!(Self::new(index, trigram, restrict)) {
             let mut x = BTreeSet::<u32>::new();
-            while r.next() {
-                x.insert(r.fileid as u32);
+            while let Some(fileid) = try!(r.next()) {
+                x.insert(fileid);
             }
-            x
+            Ok(x)
         } else {
-            BTreeSet::new()
+            Ok(BTreeSet::new())
         }
     }
-    // FIXME: refactor either to use rust iterator or don't look like an iterator
-    fn next(&mut self) -> bool {
-        while self.count > 0 {
-            self.count -= 1;
-            let (delta, n) = libvarint::read_uvarint(self.d).unwrap();
-            if n <= 0 || delta == 0 {
-                panic!("corrupt index");
-            }
-            self.d = self.d.split_at(n as usize).1;
-            self.fileid += delta as i64;
-            let is_fileid_found = match *self.restrict {
-                Some(ref r) if r.contains(&(self.fileid as u32)) => true,
-                None => true,
-                _ => false
-            };
-            if !is_fileid_found {
-                continue;
+    fn allowed(&self, fileid: u32) -> bool {
+        match *self.restrict {
+            Some(ref r) => r.contains(&fileid),
+            None => true,
+        }
+    }
+    fn next(&mut self) -> ReaderResult<Option<u32>> {
+        while let Some(fileid) = try!(self.list.next()) {
+            if self.allowed(fileid) {
+                return Ok(Some(fileid));
             }
-            return true;
         }
-        // list should end with terminating 0 delta
-        // FIXME: add bounds checking
-        self.fileid = -1;
-        return false;
+        Ok(None)
     }
 }
 
@@ -464,61 +982,40 @@ impl<'a> PostSet<'a> {
         }
     }
     pub fn into_inner(self) -> BTreeSet<u32> { self.list }
-    pub fn and(self, trigram: u32) -> Option<Self> {
-        let (mut d, count) = unsafe {
-            if let Some(tup) = Self::make_view(&self.index, trigram) {
-                tup
-            } else {
-                return None;
-            }
+    /// Keeps only the files that also appear in the posting list for `trigram`
+    ///
+    /// Each file in the set is looked up in the list rather than decoding
+    /// the whole list, so this is cheap when the set is small.
+    pub fn and(self, trigram: u32) -> ReaderResult<Self> {
+        let mut l = match try!(self.index.post_list(trigram)) {
+            Some(l) => l,
+            None => return Ok(PostSet::new(self.index)),
         };
-        let mut fileid = -1;
         let mut h = BTreeSet::new();
-        for _ in 0 .. count {
-            let (delta, n) = libvarint::read_uvarint(d).unwrap();
-            if n <= 0 || delta == 0 {
-                panic!("corrupt index");
-            }
-            d = d.split_at(n as usize).1;
-            fileid += delta as i64;
-            if self.list.contains(&(fileid as u32)) {
-                h.insert(fileid as u32);
+        for fileid in self.list {
+            match try!(l.seek(fileid)) {
+                Some(f) if f == fileid => {
+                    h.insert(f);
+                }
+                Some(_) => (),
+                None => break,
             }
         }
-        Some(PostSet {
+        Ok(PostSet {
             index: self.index,
             list: h
         })
     }
-    pub fn or(mut self, trigram: u32) -> Option<Self> {
-        let (mut d, count) = unsafe {
-            if let Some(tup) = Self::make_view(&self.index, trigram) {
-                tup
-            } else {
-                return Some(self);
-            }
+    /// Adds every file in the posting list for `trigram`
+    pub fn or(mut self, trigram: u32) -> ReaderResult<Self> {
+        let mut l = match try!(self.index.post_list(trigram)) {
+            Some(l) => l,
+            None => return Ok(self),
         };
-        let mut fileid = -1;
-        // writeln!(io::stderr(), "TRI 0x{:6x}: {}", trigram, count).unwrap();
-        for _ in 0 .. count {
-            let (delta, n) = libvarint::read_uvarint(d).unwrap();
-            if n <= 0 || delta == 0 {
-                panic!("corrupt index");
-            }
-            d = d.split_at(n as usize).1;
-            fileid += delta as i64;
-            self.list.insert(fileid as u32);
-        }
-        Some(self)
-    }
-    unsafe fn make_view(index: &'a IndexReader, trigram: u32) -> Option<(&'a [u8], usize)> {
-        let (count, offset) = index.find_list(trigram);
-        if count == 0 {
-            // writeln!(io::stderr(), "TRI 0x{:6x}: 0", trigram).unwrap();
-            return None;
+        // writeln!(io::stderr(), "TRI 0x{:6x}: {}", trigram, l.len()).unwrap();
+        while let Some(fileid) = try!(l.next()) {
+            self.list.insert(fileid);
         }
-        let v = index.data.as_slice();
-        let split_point = (index.post_data as usize) + (offset as usize) + 3;
-        Some((v.split_at(split_point).1, count as usize))
+        Ok(self)
     }
 }
@@ -0,0 +1,100 @@
+// Copyright 2016 Vernon Jones.
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file.
+
+//! Fetching the contents of indexed files back.
+//!
+//! A query only narrows a search down to the files that might match, so a
+//! searcher has to read each of them to find the lines that do.  Files are
+//! read from the filesystem by their names, but documents added with
+//! `IndexWriter::add_document` can live anywhere, so searchers read through
+//! a `ContentSource` that knows where to find them.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+
+use pathbytes;
+
+/// Opens indexed files and documents by name, as returned by
+/// `IndexReader::name_bytes`
+///
+/// ```
+/// # use std::io::{self, Cursor, Read};
+/// # use libcsearch::source::{ContentSource, FileSource};
+/// /// Documents stored in a table, with files for everything else
+/// struct Table(Vec<(String, String)>);
+///
+/// impl ContentSource for Table {
+///     fn open<'a>(&'a self, name: &[u8]) -> io::Result<Box<Read + 'a>> {
+///         match self.0.iter().find(|&&(ref id, _)| id.as_bytes() == name) {
+///             Some(&(_, ref row)) => Ok(Box::new(Cursor::new(row.as_bytes()))),
+///             None => FileSource.open(name),
+///         }
+///     }
+/// }
+/// ```
+pub trait ContentSource {
+    /// Returns a reader for the contents of `name`.  Fails with
+    /// `io::ErrorKind::NotFound` if there's no such file or document.
+    fn open<'a>(&'a self, name: &[u8]) -> io::Result<Box<Read + 'a>>;
+}
+
+/// Reads files from the filesystem, for indexes built from files
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileSource;
+
+impl ContentSource for FileSource {
+    fn open<'a>(&'a self, name: &[u8]) -> io::Result<Box<Read + 'a>> {
+        let f = try!(File::open(pathbytes::from_bytes(name.to_vec())));
+        Ok(Box::new(f))
+    }
+}
+
+/// Documents held in memory, keyed by their IDs
+#[derive(Debug, Clone, Default)]
+pub struct MemorySource {
+    documents: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl MemorySource {
+    pub fn new() -> MemorySource {
+        MemorySource::default()
+    }
+
+    /// Adds or replaces the document with ID `id`
+    pub fn insert<I, C>(&mut self, id: I, contents: C)
+        where I: Into<Vec<u8>>,
+              C: Into<Vec<u8>>
+    {
+        self.documents.insert(id.into(), contents.into());
+    }
+}
+
+impl ContentSource for MemorySource {
+    fn open<'a>(&'a self, name: &[u8]) -> io::Result<Box<Read + 'a>> {
+        match self.documents.get(name) {
+            Some(d) => Ok(Box::new(Cursor::new(&d[..]))),
+            None => {
+                Err(io::Error::new(io::ErrorKind::NotFound,
+                                   format!("no document {:?}", String::from_utf8_lossy(name))))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{ErrorKind, Read};
+
+    #[test]
+    fn test_memory_source() {
+        let mut source = MemorySource::new();
+        source.insert("db:1", "contents");
+        let mut s = String::new();
+        source.open(b"db:1").unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "contents");
+        assert_eq!(source.open(b"db:2").err().unwrap().kind(), ErrorKind::NotFound);
+    }
+}
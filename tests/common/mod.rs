@@ -7,6 +7,7 @@ extern crate libcsearch;
 use std::collections::BTreeMap;
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
 
 use self::libcindex::writer::IndexWriter;
@@ -22,6 +23,7 @@ pub fn build_flush_index<P: AsRef<Path>>(out: P,
                                          do_flush: bool,
                                          file_data: BTreeMap<&'static str, &'static str>) {
     let mut ix = IndexWriter::new(out.as_ref()).unwrap();
+    ix.created = UNIX_EPOCH;
     ix.add_paths(paths.into_iter().map(PathBuf::into_os_string));
     let mut files = file_data.keys().collect::<Vec<_>>();
     files.sort();
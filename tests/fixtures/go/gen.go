@@ -0,0 +1,31 @@
+// Writes an index with Go's codesearch index package, for use as a test
+// fixture.  See README.
+//
+//	go run gen.go OUT.csi NAME=CONTENTS...
+package main
+
+import (
+	"log"
+	"os"
+	"sort"
+	"strings"
+
+	"github.com/google/codesearch/index"
+)
+
+func main() {
+	if len(os.Args) < 2 {
+		log.Fatal("usage: go run gen.go OUT.csi NAME=CONTENTS...")
+	}
+	files := os.Args[2:]
+	sort.Strings(files)
+	ix := index.Create(os.Args[1])
+	for _, f := range files {
+		i := strings.Index(f, "=")
+		if i < 0 {
+			log.Fatalf("%s: expected NAME=CONTENTS", f)
+		}
+		ix.Add(f[:i], strings.NewReader(f[i+1:]))
+	}
+	ix.Flush()
+}
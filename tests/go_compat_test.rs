@@ -0,0 +1,127 @@
+// Compatibility with indexes written by the Go implementation.
+//
+// tests/fixtures/go/trivial.csi is the index Go's cindex writes for
+// trivial_files(); it's the trivialIndex golden file from Go's
+// index/write_test.go.  See tests/fixtures/go/README for adding more.
+
+extern crate tempfile;
+
+extern crate libcindex;
+extern crate libcsearch;
+
+mod common;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+
+use self::tempfile::NamedTempFile;
+use self::libcindex::merge::merge;
+use self::libcindex::writer::{IndexErrorKind, IndexWriter};
+use self::libcsearch::reader::{IndexReader, IndexVersion, PostReader};
+
+use common::tri;
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/go").join(name)
+}
+
+fn read_fixture(name: &str) -> Vec<u8> {
+    let mut v = Vec::new();
+    File::open(fixture(name)).unwrap().read_to_end(&mut v).unwrap();
+    v
+}
+
+fn trivial_files() -> BTreeMap<&'static str, &'static str> {
+    let mut d = BTreeMap::new();
+    d.insert("f0", "\n\n");
+    d.insert("file1", "\na\n");
+    d.insert("thefile2", "\nab\n");
+    d.insert("file3", "\nabc\n");
+    d.insert("afile4", "\ndabc\n");
+    d.insert("file5", "\nxyzw\n");
+    d
+}
+
+fn build_v1(out: &NamedTempFile, paths: &[&str], files: &BTreeMap<&'static str, &'static str>) {
+    let mut ix = IndexWriter::new(out.path()).unwrap();
+    ix.version = IndexVersion::V1;
+    ix.add_paths(paths.iter().map(OsString::from));
+    for (name, contents) in files {
+        ix.add(name, Cursor::new(contents.as_bytes()), contents.len() as u64).unwrap();
+    }
+    ix.flush().unwrap();
+}
+
+fn list(ix: &IndexReader, trigram: &str) -> BTreeSet<u32> {
+    let t = trigram.as_bytes();
+    PostReader::list(ix, tri(t[0], t[1], t[2]), &mut None).unwrap()
+}
+
+#[test]
+fn test_read_go_index() {
+    let ix = IndexReader::open(fixture("trivial.csi")).unwrap();
+    ix.verify().unwrap();
+    assert_eq!(ix.version(), IndexVersion::V1);
+    assert_eq!(ix.indexed_paths(), Vec::<OsString>::new());
+    let names = (0..ix.num_name as u32).map(|i| ix.name(i)).collect::<Vec<_>>();
+    assert_eq!(names,
+               ["afile4", "f0", "file1", "file3", "file5", "thefile2"]
+                   .iter()
+                   .map(OsString::from)
+                   .collect::<Vec<_>>());
+    assert_eq!(ix.file_meta(0), None);
+
+    assert_eq!(list(&ix, "\nab"), [3, 5].iter().cloned().collect());
+    assert_eq!(list(&ix, "abc"), [0, 3].iter().cloned().collect());
+    assert_eq!(list(&ix, "xyz"), [4].iter().cloned().collect());
+    assert_eq!(list(&ix, "qqq"), BTreeSet::new());
+}
+
+#[test]
+fn test_write_go_index() {
+    let f = NamedTempFile::new().unwrap();
+    build_v1(&f, &[], &trivial_files());
+    let mut out = Vec::new();
+    File::open(f.path()).unwrap().read_to_end(&mut out).unwrap();
+    assert_eq!(out, read_fixture("trivial.csi"));
+}
+
+#[test]
+fn test_merge_go_indexes() {
+    let mut files = BTreeMap::new();
+    files.insert("/src/b/x", "\nabc\n");
+    let f2 = NamedTempFile::new().unwrap();
+    build_v1(&f2, &["/src/b"], &files);
+
+    // both version 1, so the result stays readable by Go
+    let f3 = NamedTempFile::new().unwrap();
+    merge(f3.path(), fixture("trivial.csi"), f2.path()).unwrap();
+    let ix3 = IndexReader::open(f3.path()).unwrap();
+    assert_eq!(ix3.version(), IndexVersion::V1);
+    assert_eq!(ix3.num_name, 7);
+    assert_eq!(ix3.name(0), OsString::from("/src/b/x"));
+    assert_eq!(list(&ix3, "abc"), [0, 1, 4].iter().cloned().collect());
+
+    // mixing in a version 2 index gives a version 2 index
+    let f4 = NamedTempFile::new().unwrap();
+    common::build_index(f4.path(), vec![PathBuf::from("/src/b")], files);
+    let f5 = NamedTempFile::new().unwrap();
+    merge(f5.path(), fixture("trivial.csi"), f4.path()).unwrap();
+    let ix5 = IndexReader::open(f5.path()).unwrap();
+    ix5.verify().unwrap();
+    assert_eq!(ix5.version(), IndexVersion::V2);
+    assert_eq!(list(&ix5, "abc"), [0, 1, 4].iter().cloned().collect());
+}
+
+#[test]
+fn test_go_index_has_no_root() {
+    let f = NamedTempFile::new().unwrap();
+    let mut ix = IndexWriter::new(f.path()).unwrap();
+    ix.version = IndexVersion::V1;
+    ix.root = Some(PathBuf::from("/src"));
+    ix.add("/src/a", Cursor::new(&b"hello"[..]), 5).unwrap();
+    assert_eq!(ix.flush().unwrap_err().kind(), IndexErrorKind::FileNameError);
+}
@@ -10,7 +10,7 @@ use std::path::PathBuf;
 
 use self::tempfile::NamedTempFile;
 
-use self::libcindex::merge::merge;
+use self::libcindex::merge::{merge, remove};
 use self::libcsearch::reader::{PostReader, IndexReader};
 
 use common::{build_index, tri};
@@ -56,6 +56,7 @@ fn test_merge() {
     let ix1 = IndexReader::open(f1.path()).unwrap();
     let ix2 = IndexReader::open(f2.path()).unwrap();
     let ix3 = IndexReader::open(f3.path()).unwrap();
+    ix3.verify().unwrap();
 
     fn check_files(ix: &IndexReader, l: &[&'static str]) {
         for (i, fname) in l.iter().enumerate() {
@@ -70,7 +71,7 @@ fn test_merge() {
 
     fn check(ix: &IndexReader, trig: &str, l: &[u32]) {
         let t = trig.chars().collect::<Vec<char>>();
-        let l1 = PostReader::list(ix, tri(t[0], t[1], t[2]), &mut None);
+        let l1 = PostReader::list(ix, tri(t[0], t[1], t[2]), &mut None).unwrap();
         let mut h: BTreeSet<u32> = BTreeSet::new();
         h.extend(l.iter().cloned());
         assert_eq!(l1, h);
@@ -86,4 +87,186 @@ fn test_merge() {
     check(&ix3, "wor", &[0, 1, 2]);
     check(&ix3, "now", &[3, 4, 6]);
     check(&ix3, "pot", &[4, 5, 7]);
+
+    // file metadata follows each file to its new ID
+    let sizes = (0..ix3.num_name as u32)
+        .map(|i| ix3.file_meta(i).unwrap().size)
+        .collect::<Vec<_>>();
+    assert_eq!(sizes, vec![11, 13, 17, 11, 28, 24, 20, 31]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_merge_non_utf8_names() {
+    use std::ffi::{OsStr, OsString};
+    use std::io::Cursor;
+    use std::os::unix::ffi::OsStrExt;
+    use self::libcindex::writer::IndexWriter;
+
+    fn build(out: &NamedTempFile, paths: &[&[u8]], files: &[&[u8]]) {
+        let mut ix = IndexWriter::new(out.path()).unwrap();
+        ix.add_paths(paths.iter().map(|p| OsStr::from_bytes(p).to_os_string()));
+        for name in files {
+            ix.add(OsStr::from_bytes(name), Cursor::new(&b"contents"[..]), 8).unwrap();
+        }
+        ix.flush().unwrap();
+    }
+    let names = |ix: &IndexReader| {
+        (0..ix.num_name as u32).map(|i| ix.name(i)).collect::<Vec<OsString>>()
+    };
+    let os = |b: &[u8]| OsStr::from_bytes(b).to_os_string();
+
+    let f1 = NamedTempFile::new().unwrap();
+    build(&f1, &[b"/caf\xc3\xa9", b"/l\xfc"], &[b"/caf\xc3\xa9/a", b"/l\xfc/b"]);
+    let f2 = NamedTempFile::new().unwrap();
+    build(&f2, &[b"/l\xfc"], &[b"/l\xfc/c"]);
+    let f3 = NamedTempFile::new().unwrap();
+    merge(f3.path(), f1.path(), f2.path()).unwrap();
+
+    let ix1 = IndexReader::open(f1.path()).unwrap();
+    assert_eq!(ix1.indexed_paths(), vec![os(b"/caf\xc3\xa9"), os(b"/l\xfc")]);
+    assert_eq!(names(&ix1), vec![os(b"/caf\xc3\xa9/a"), os(b"/l\xfc/b")]);
+    assert_eq!(ix1.name_bytes(1), b"/l\xfc/b".to_vec());
+
+    let ix3 = IndexReader::open(f3.path()).unwrap();
+    assert_eq!(ix3.indexed_paths(), vec![os(b"/caf\xc3\xa9"), os(b"/l\xfc")]);
+    assert_eq!(names(&ix3), vec![os(b"/caf\xc3\xa9/a"), os(b"/l\xfc/c")]);
+}
+
+#[test]
+fn test_merge_relative() {
+    use std::ffi::OsString;
+    use std::io::{Cursor, ErrorKind};
+    use self::libcindex::writer::IndexWriter;
+
+    fn build(out: &NamedTempFile, root: Option<&str>, path: &str, file: &str) {
+        let mut ix = IndexWriter::new(out.path()).unwrap();
+        ix.root = root.map(PathBuf::from);
+        ix.add_paths(vec![OsString::from(path)]);
+        ix.add(file, Cursor::new(&b"contents"[..]), 8).unwrap();
+        ix.flush().unwrap();
+    }
+
+    let f1 = NamedTempFile::new().unwrap();
+    build(&f1, Some("/src"), "/src/a", "/src/a/x");
+    let f2 = NamedTempFile::new().unwrap();
+    build(&f2, Some("/src"), "/src/b", "/src/b/y");
+    let f3 = NamedTempFile::new().unwrap();
+    merge(f3.path(), f1.path(), f2.path()).unwrap();
+
+    let mut ix3 = IndexReader::open(f3.path()).unwrap();
+    ix3.verify().unwrap();
+    assert_eq!(ix3.root(), Some(OsString::from("/src")));
+    assert_eq!(ix3.stored_paths(), vec![b"./a".to_vec(), b"./b".to_vec()]);
+    ix3.map_root("/src", "/moved");
+    assert_eq!(ix3.name(0), OsString::from("/moved/a/x"));
+    assert_eq!(ix3.name(1), OsString::from("/moved/b/y"));
+
+    // names relative to different roots can't be merged
+    let f4 = NamedTempFile::new().unwrap();
+    build(&f4, None, "/src/b", "/src/b/y");
+    let err = merge(f3.path(), f1.path(), f4.path()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_merge_options() {
+    use std::io::Cursor;
+    use self::libcindex::writer::{IndexOptions, IndexWriter, StoredOptions};
+
+    let options = IndexOptions::builder().max_file_len(100).build().unwrap();
+    let f1 = NamedTempFile::new().unwrap();
+    build_index(f1.path(),
+                MERGE_PATHS_1.iter().map(PathBuf::from).collect(),
+                merge_files_1());
+    let f2 = NamedTempFile::new().unwrap();
+    {
+        let mut ix = IndexWriter::new(f2.path()).unwrap();
+        ix.set_options(options.clone());
+        ix.add_paths(vec![PathBuf::from("/b").into_os_string()]);
+        ix.add("/b/www", Cursor::new(&b"world wide indeed"[..]), 17).unwrap();
+        ix.flush().unwrap();
+    }
+    let f3 = NamedTempFile::new().unwrap();
+    merge(f3.path(), f1.path(), f2.path()).unwrap();
+
+    // the newer index's options are kept
+    let ix3 = IndexReader::open_verified(f3.path()).unwrap();
+    assert_eq!(StoredOptions::read(&ix3).unwrap().unwrap().options, options);
+}
+
+#[test]
+fn test_merge_duplicates() {
+    use std::io::Cursor;
+    use self::libcindex::writer::IndexWriter;
+
+    fn build(f: &NamedTempFile, paths: &[&str], files: &[(&str, &str)]) {
+        let mut ix = IndexWriter::new(f.path()).unwrap();
+        ix.deduplicate = true;
+        ix.add_paths(paths.iter().map(|p| PathBuf::from(p).into_os_string()));
+        for &(name, contents) in files {
+            ix.add(name, Cursor::new(contents.as_bytes()), contents.len() as u64).unwrap();
+        }
+        ix.flush().unwrap();
+    }
+
+    let f1 = NamedTempFile::new().unwrap();
+    build(&f1,
+          &["/a", "/b", "/c"],
+          &[("/a/lib.c", "old lib"), ("/b/lib.c", "old lib"), ("/c/lib.c", "old lib")]);
+    let f2 = NamedTempFile::new().unwrap();
+    build(&f2, &["/a"], &[("/a/lib.c", "new lib")]);
+    let f3 = NamedTempFile::new().unwrap();
+    merge(f3.path(), f1.path(), f2.path()).unwrap();
+
+    // /a/lib.c held the posting entries for all three; /b/lib.c takes them over
+    let ix3 = IndexReader::open_verified(f3.path()).unwrap();
+    assert_eq!(ix3.num_name, 3);
+    assert_eq!(ix3.duplicates(0), vec![]);
+    assert_eq!(ix3.duplicates(1), vec![2]);
+    assert_eq!(PostReader::list(&ix3, tri('o', 'l', 'd'), &None).unwrap(),
+               [1].iter().cloned().collect::<BTreeSet<u32>>());
+    assert_eq!(PostReader::list(&ix3, tri('l', 'i', 'b'), &None).unwrap(),
+               [0, 1].iter().cloned().collect::<BTreeSet<u32>>());
+}
+
+#[test]
+fn test_remove() {
+    use std::io::{Cursor, ErrorKind};
+    use self::libcindex::writer::IndexWriter;
+
+    let f1 = NamedTempFile::new().unwrap();
+    {
+        let mut ix = IndexWriter::new(f1.path()).unwrap();
+        ix.root = Some(PathBuf::from("/src"));
+        ix.deduplicate = true;
+        ix.add_paths(["/src/a", "/src/b", "/src/bb"].iter().map(|p| p.into()));
+        for &(name, contents) in &[("/src/a/x", "hello world"),
+                                   ("/src/a/y", "old lib"),
+                                   ("/src/b/sub/x", "goodbye world"),
+                                   ("/src/b/z", "old lib"),
+                                   ("/src/bb/x", "world peace")] {
+            ix.add(name, Cursor::new(contents.as_bytes()), contents.len() as u64).unwrap();
+        }
+        ix.flush().unwrap();
+    }
+    let f2 = NamedTempFile::new().unwrap();
+    remove(f2.path(), f1.path(), &["/src/a", "/src/b/sub"]).unwrap();
+
+    let ix2 = IndexReader::open_verified(f2.path()).unwrap();
+    assert_eq!(ix2.stored_paths(), vec![b"./b".to_vec(), b"./bb".to_vec()]);
+    assert_eq!(ix2.num_name, 2);
+    assert_eq!(ix2.name_bytes(0), b"/src/b/z".to_vec());
+    assert_eq!(ix2.name_bytes(1), b"/src/bb/x".to_vec());
+    assert_eq!(ix2.file_meta(1).map(|m| m.size), Some(11));
+    // /src/b/z was a duplicate of /src/a/y, and takes over its entries
+    assert_eq!(ix2.duplicates(0), vec![]);
+    assert_eq!(PostReader::list(&ix2, tri('o', 'l', 'd'), &None).unwrap(),
+               [0].iter().cloned().collect::<BTreeSet<u32>>());
+    assert_eq!(PostReader::list(&ix2, tri('w', 'o', 'r'), &None).unwrap(),
+               [1].iter().cloned().collect::<BTreeSet<u32>>());
+    assert_eq!(PostReader::list(&ix2, tri('h', 'e', 'l'), &None).unwrap(), BTreeSet::new());
+
+    let err = remove(f2.path(), f1.path(), &["/elsewhere"]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
 }
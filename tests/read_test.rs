@@ -1,13 +1,17 @@
 extern crate tempfile;
 
+extern crate libcindex;
 extern crate libcsearch;
 
 mod common;
 
 use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::io::Cursor;
 
 use self::tempfile::NamedTempFile;
-use self::libcsearch::reader::{PostReader, IndexReader};
+use self::libcindex::writer::IndexWriter;
+use self::libcsearch::reader::{PostReader, PostSet, IndexReader};
 
 use common::{tri, build_index};
 
@@ -43,9 +47,9 @@ fn make_index() -> IndexReader {
 #[test]
 fn test_postreader_list() {
     let ix = make_index();
-    assert_eq!(PostReader::list(&ix, tri('S', 'e', 'a'), &mut None),
+    assert_eq!(PostReader::list(&ix, tri('S', 'e', 'a'), &mut None).unwrap(),
                set![1, 3]);
-    assert_eq!(PostReader::list(&ix, tri('G', 'o', 'o'), &mut None),
+    assert_eq!(PostReader::list(&ix, tri('G', 'o', 'o'), &mut None).unwrap(),
                set![1, 2, 3]);
 }
 
@@ -53,14 +57,14 @@ fn test_postreader_list() {
 fn test_postreader_and() {
     let ix = make_index();
     assert_eq!(PostReader::and(&ix,
-                               PostReader::list(&ix, tri('S', 'e', 'a'), &mut None),
+                               PostReader::list(&ix, tri('S', 'e', 'a'), &mut None).unwrap(),
                                tri('G', 'o', 'o'),
-                               &mut None),
+                               &mut None).unwrap(),
                set![1, 3]);
     assert_eq!(PostReader::and(&ix,
-                               PostReader::list(&ix, tri('G', 'o', 'o'), &mut None),
+                               PostReader::list(&ix, tri('G', 'o', 'o'), &mut None).unwrap(),
                                tri('S', 'e', 'a'),
-                               &mut None),
+                               &mut None).unwrap(),
                set![1, 3]);
 }
 
@@ -68,13 +72,115 @@ fn test_postreader_and() {
 fn test_postreader_or() {
     let ix = make_index();
     assert_eq!(PostReader::or(&ix,
-                              PostReader::list(&ix, tri('G', 'o', 'o'), &mut None),
+                              PostReader::list(&ix, tri('G', 'o', 'o'), &mut None).unwrap(),
                               tri('S', 'e', 'a'),
-                              &mut None),
+                              &mut None).unwrap(),
                set![1, 2, 3]);
     assert_eq!(PostReader::or(&ix,
-                              PostReader::list(&ix, tri('S', 'e', 'a'), &mut None),
+                              PostReader::list(&ix, tri('S', 'e', 'a'), &mut None).unwrap(),
                               tri('G', 'o', 'o'),
-                              &mut None),
+                              &mut None).unwrap(),
                set![1, 2, 3]);
 }
+
+// Many files with a common trigram, a few with a rare one, so the
+// posting lists span several blocks.  The files are identical, so they'd
+// share one posting entry if they were deduplicated.
+fn make_big_index(f: &NamedTempFile) -> IndexReader {
+    {
+        let mut ix = IndexWriter::new(f.path()).unwrap();
+        ix.deduplicate = false;
+        for i in 0..1000 {
+            let contents = if i % 97 == 0 { "common rare" } else { "common" };
+            ix.add(&format!("file{:04}", i), Cursor::new(contents.as_bytes()), 0).unwrap();
+        }
+        ix.flush().unwrap();
+    }
+    IndexReader::open(f.path()).unwrap()
+}
+
+#[test]
+fn test_post_list_seek() {
+    let f = NamedTempFile::new().unwrap();
+    let ix = make_big_index(&f);
+    let mut l = ix.post_list(tri('c', 'o', 'm')).unwrap().unwrap();
+    assert_eq!(l.len(), 1000);
+    assert_eq!(l.next().unwrap(), Some(0));
+    assert_eq!(l.seek(500).unwrap(), Some(500));
+    assert_eq!(l.seek(300).unwrap(), Some(500));
+    assert_eq!(l.next().unwrap(), Some(500));
+    assert_eq!(l.next().unwrap(), Some(501));
+    assert_eq!(l.seek(999).unwrap(), Some(999));
+    assert_eq!(l.seek(1000).unwrap(), None);
+    assert_eq!(l.next().unwrap(), None);
+
+    let mut l = ix.post_list(tri('r', 'a', 'r')).unwrap().unwrap();
+    assert_eq!(l.seek(98).unwrap(), Some(194));
+    assert!(ix.post_list(tri('x', 'y', 'z')).unwrap().is_none());
+}
+
+#[test]
+fn test_and_across_blocks() {
+    let f = NamedTempFile::new().unwrap();
+    let ix = make_big_index(&f);
+    let rare = (0..1000).filter(|i| i % 97 == 0).collect::<BTreeSet<u32>>();
+    assert_eq!(PostReader::list(&ix, tri('c', 'o', 'm'), &None).unwrap().len(), 1000);
+    let s = PostSet::new(&ix).or(tri('r', 'a', 'r')).ok().unwrap();
+    let s = s.and(tri('c', 'o', 'm')).ok().unwrap();
+    assert_eq!(s.into_inner(), rare);
+    assert_eq!(PostReader::and(&ix, (0..1000).collect(), tri('a', 'r', 'e'), &None).unwrap(),
+               rare);
+}
+
+#[test]
+fn test_front_coded_names() {
+    let f = NamedTempFile::new().unwrap();
+    let ix = make_big_index(&f);
+    assert_eq!(ix.num_name, 1000);
+    for i in 0..1000 {
+        assert_eq!(ix.name(i), OsString::from(format!("file{:04}", i)));
+    }
+}
+
+#[test]
+fn test_relative_index() {
+    use self::libcindex::writer::IndexErrorKind;
+
+    let f = NamedTempFile::new().unwrap();
+    {
+        let mut ix = IndexWriter::new(f.path()).unwrap();
+        ix.root = Some("/build/src".into());
+        ix.add_paths(vec![OsString::from("/build/src/a"), OsString::from("/build/src/b")]);
+        ix.add("/build/src/a/x", Cursor::new(&b"hello world"[..]), 11).unwrap();
+        ix.add("/build/src/b/y", Cursor::new(&b"goodbye world"[..]), 13).unwrap();
+        let err = ix.add("/elsewhere/z", Cursor::new(&b"hello"[..]), 5).unwrap_err();
+        assert_eq!(err.kind(), IndexErrorKind::FileNameError);
+        ix.flush().unwrap();
+    }
+
+    let mut ix = IndexReader::open(f.path()).unwrap();
+    ix.verify().unwrap();
+    assert_eq!(ix.num_name, 2);
+    assert_eq!(ix.root(), Some(OsString::from("/build/src")));
+    assert_eq!(ix.stored_paths(), vec![b"./a".to_vec(), b"./b".to_vec()]);
+    assert_eq!(ix.stored_name(0), b"./a/x".to_vec());
+    assert_eq!(ix.indexed_paths(),
+               vec![OsString::from("/build/src/a"), OsString::from("/build/src/b")]);
+    assert_eq!(ix.name(1), OsString::from("/build/src/b/y"));
+
+    ix.map_root("/build/src", "/home/me/src");
+    assert_eq!(ix.indexed_paths(),
+               vec![OsString::from("/home/me/src/a"), OsString::from("/home/me/src/b")]);
+    assert_eq!(ix.name(0), OsString::from("/home/me/src/a/x"));
+    assert_eq!(ix.name_bytes(1), b"/home/me/src/b/y".to_vec());
+}
+
+#[test]
+fn test_map_root_absolute() {
+    let f = NamedTempFile::new().unwrap();
+    let mut ix = make_big_index(&f);
+    assert_eq!(ix.root(), None);
+    ix.map_root("file0001", "other");
+    assert_eq!(ix.name(1), OsString::from("other"));
+    assert_eq!(ix.name(10), OsString::from("file0010"));
+}
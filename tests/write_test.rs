@@ -1,4 +1,6 @@
+extern crate regex_syntax;
 extern crate tempfile;
+extern crate twox_hash;
 
 extern crate libcindex;
 extern crate libcsearch;
@@ -6,15 +8,33 @@ extern crate libcsearch;
 mod common;
 
 use std::collections::BTreeMap;
-use std::io::Read;
-use std::ops::DerefMut;
+use std::collections::BTreeSet;
+use std::fs;
+use std::hash::Hasher;
+use std::io::{Read, Write};
 use std::num::Wrapping;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::u32;
 
 use self::tempfile::NamedTempFile;
+use self::twox_hash::XxHash64;
+use self::libcindex::writer::{IndexOptions, IndexWriter, StoredOptions, TOOL_VERSION};
+use self::libcsearch::reader::{IndexReader, IndexVersion, PostReader, PostSet, ReaderErrorKind};
 
 
-use common::build_flush_index;
+use common::{build_flush_index, tri};
+
+macro_rules! set {
+    ( $( $x:expr ),* ) => {
+        {
+            let mut temp_set = BTreeSet::new();
+            $(
+                temp_set.insert($x);
+            )*
+            temp_set
+        }
+    }
+}
 
 
 fn trivial_files() -> BTreeMap<&'static str, &'static str> {
@@ -28,106 +48,165 @@ fn trivial_files() -> BTreeMap<&'static str, &'static str> {
     d
 }
 
-fn trivial_index() -> Vec<u8> {
+fn trivial_index(version: u32) -> Vec<u8> {
+    let off = |value: u32| offset_to_vec(value, version);
     let mut s = Vec::<u8>::new();
     // header
-    s.extend_from_slice("csearch index 1\n".as_bytes());
+    if version == 1 {
+        s.extend_from_slice("csearch index 1\n".as_bytes());
+    } else {
+        s.extend_from_slice("csearch index 2\n".as_bytes());
+    }
 
     // list of paths
     s.extend_from_slice("\x00".as_bytes());
 
     // list of names
-    s.extend_from_slice("afile4\x00".as_bytes());
-    s.extend_from_slice("f0\x00".as_bytes());
-    s.extend_from_slice("file1\x00".as_bytes());
-    s.extend_from_slice("file3\x00".as_bytes());
-    s.extend_from_slice("file5\x00".as_bytes());
-    s.extend_from_slice("thefile2\x00".as_bytes());
-    s.extend_from_slice("\x00".as_bytes());
+    let names_start = s.len();
+    let mut name_offsets = Vec::new();
+    if version == 1 {
+        for name in &["afile4", "f0", "file1", "file3", "file5", "thefile2", ""] {
+            name_offsets.push((s.len() - names_start) as u32);
+            s.extend_from_slice(name.as_bytes());
+            s.push(0);
+        }
+    } else {
+        // front-coded: shared prefix length, rest of the name
+        for &(shared, rest) in &[(0, "afile4"),
+                                 (0, "f0"),
+                                 (1, "ile1"),
+                                 (4, "3"),
+                                 (4, "5"),
+                                 (0, "thefile2"),
+                                 (0, "")] {
+            name_offsets.push((s.len() - names_start) as u32);
+            s.push(shared);
+            s.extend_from_slice(rest.as_bytes());
+            s.push(0);
+        }
+    }
+    let names_len = (s.len() - names_start) as u32;
 
     // list of posting lists
-    s.extend("\na\n".as_bytes());
-    s.extend_from_slice(&mut file_list(vec![2])); // file1
-    s.extend("\nab".as_bytes());
-    s.extend_from_slice(&mut file_list(vec![3, 5])); // file3, thefile2
-    s.extend("\nda".as_bytes());
-    s.extend_from_slice(&mut file_list(vec![0])); // afile4
-    s.extend("\nxy".as_bytes());
-    s.extend_from_slice(&mut file_list(vec![4])); // file5
-    s.extend("ab\n".as_bytes());
-    s.extend_from_slice(&mut file_list(vec![5])); // thefile2
-    s.extend("abc".as_bytes());
-    s.extend_from_slice(&mut file_list(vec![0, 3])); // afile4, file3
-    s.extend("bc\n".as_bytes());
-    s.extend_from_slice(&mut file_list(vec![0, 3])); // afile4, file3
-    s.extend("dab".as_bytes());
-    s.extend_from_slice(&mut file_list(vec![0])); // afile4
-    s.extend("xyz".as_bytes());
-    s.extend_from_slice(&mut file_list(vec![4])); // file5
-    s.extend("yzw".as_bytes());
-    s.extend_from_slice(&mut file_list(vec![4])); // file5
-    s.extend("zw\n".as_bytes());
-    s.extend_from_slice(&mut file_list(vec![4])); // file5
-    s.push(0xff);
-    s.push(0xff);
-    s.push(0xff);
-    s.extend_from_slice(&mut file_list(vec![]));
+    let lists = vec![("\na\n", vec![2]), // file1
+                     ("\nab", vec![3, 5]), // file3, thefile2
+                     ("\nda", vec![0]), // afile4
+                     ("\nxy", vec![4]), // file5
+                     ("ab\n", vec![5]), // thefile2
+                     ("abc", vec![0, 3]), // afile4, file3
+                     ("bc\n", vec![0, 3]), // afile4, file3
+                     ("dab", vec![0]), // afile4
+                     ("xyz", vec![4]), // file5
+                     ("yzw", vec![4]), // file5
+                     ("zw\n", vec![4]), // file5
+                     ("", vec![])]; // end of the lists, trigram "\xff\xff\xff"
+    let post_start = s.len();
+    let mut post_offsets = Vec::new();
+    for &(t, ref ids) in &lists {
+        post_offsets.push((s.len() - post_start) as u32);
+        if ids.is_empty() {
+            s.extend_from_slice(&[0xff, 0xff, 0xff]);
+        } else {
+            s.extend(t.as_bytes());
+        }
+        if version == 1 {
+            s.extend(file_list(ids.clone()));
+        } else if !ids.is_empty() {
+            s.extend(block_list(ids));
+        }
+    }
+    let post_len = (s.len() - post_start) as u32;
+
+    // file metadata
+    let meta_len = if version > 1 {
+        let files = trivial_files();
+        for name in &["afile4", "f0", "file1", "file3", "file5", "thefile2"] {
+            let contents = files[name].as_bytes();
+            let mut h = XxHash64::with_seed(0);
+            h.write(contents);
+            s.extend_from_slice(&[0; 8 + 4]); // no modification time
+            s.extend(offset_to_vec(contents.len() as u32, 2));
+            s.extend(u32_to_vec((h.finish() >> 32) as u32));
+            s.extend(u32_to_vec(h.finish() as u32));
+        }
+        6 * 28
+    } else {
+        0
+    };
+
+    // options, written at the UNIX epoch
+    let options_len = if version > 1 {
+        let options = format!("max_trigram_count=30000\nmax_utf8_invalid=0.1\n\
+                               max_file_len=1073741824\nmax_line_len=2000\n\
+                               tool_version={}\ncreated=0\n",
+                              TOOL_VERSION);
+        s.extend(options.as_bytes());
+        options.len() as u32
+    } else {
+        0
+    };
+    let sections_len = meta_len + options_len;
 
     // name index
-    s.extend_from_slice(&mut u32_to_vec(0));
-    s.extend_from_slice(&mut u32_to_vec(6 + 1));
-    s.extend_from_slice(&mut u32_to_vec(6 + 1 + 2 + 1));
-    s.extend_from_slice(&mut u32_to_vec(6 + 1 + 2 + 1 + 5 + 1));
-    s.extend_from_slice(&mut u32_to_vec(6 + 1 + 2 + 1 + 5 + 1 + 5 + 1));
-    s.extend_from_slice(&mut u32_to_vec(6 + 1 + 2 + 1 + 5 + 1 + 5 + 1 + 5 + 1));
-    s.extend_from_slice(&mut u32_to_vec(6 + 1 + 2 + 1 + 5 + 1 + 5 + 1 + 5 + 1 + 8 + 1));
+    for &offset in &name_offsets {
+        s.extend_from_slice(&mut off(offset));
+    }
 
     // posting list index,
-    s.extend("\na\n".as_bytes());
-    s.extend_from_slice(&mut u32_to_vec(1));
-    s.extend_from_slice(&mut u32_to_vec(0));
-    s.extend("\nab".as_bytes());
-    s.extend_from_slice(&mut u32_to_vec(2));
-    s.extend_from_slice(&mut u32_to_vec(5));
-    s.extend("\nda".as_bytes());
-    s.extend_from_slice(&mut u32_to_vec(1));
-    s.extend_from_slice(&mut u32_to_vec(5 + 6));
-    s.extend("\nxy".as_bytes());
-    s.extend_from_slice(&mut u32_to_vec(1));
-    s.extend_from_slice(&mut u32_to_vec(5 + 6 + 5));
-    s.extend("ab\n".as_bytes());
-    s.extend_from_slice(&mut u32_to_vec(1));
-    s.extend_from_slice(&mut u32_to_vec(5 + 6 + 5 + 5));
-    s.extend("abc".as_bytes());
-    s.extend_from_slice(&mut u32_to_vec(2));
-    s.extend_from_slice(&mut u32_to_vec(5 + 6 + 5 + 5 + 5));
-    s.extend("bc\n".as_bytes());
-    s.extend_from_slice(&mut u32_to_vec(2));
-    s.extend_from_slice(&mut u32_to_vec(5 + 6 + 5 + 5 + 5 + 6));
-    s.extend("dab".as_bytes());
-    s.extend_from_slice(&mut u32_to_vec(1));
-    s.extend_from_slice(&mut u32_to_vec(5 + 6 + 5 + 5 + 5 + 6 + 6));
-    s.extend("xyz".as_bytes());
-    s.extend_from_slice(&mut u32_to_vec(1));
-    s.extend_from_slice(&mut u32_to_vec(5 + 6 + 5 + 5 + 5 + 6 + 6 + 5));
-    s.extend("yzw".as_bytes());
-    s.extend_from_slice(&mut u32_to_vec(1));
-    s.extend_from_slice(&mut u32_to_vec(5 + 6 + 5 + 5 + 5 + 6 + 6 + 5 + 5));
-    s.extend("zw\n".as_bytes());
-    s.extend_from_slice(&mut u32_to_vec(1));
-    s.extend_from_slice(&mut u32_to_vec(5 + 6 + 5 + 5 + 5 + 6 + 6 + 5 + 5 + 5));
-    s.push(0xff);
-    s.push(0xff);
-    s.push(0xff);
-    s.extend(u32_to_vec(0));
-    s.extend_from_slice(&mut u32_to_vec(5 + 6 + 5 + 5 + 5 + 6 + 6 + 5 + 5 + 5 + 5));
+    for (&(t, ref ids), &offset) in lists.iter().zip(post_offsets.iter()) {
+        if ids.is_empty() {
+            s.extend_from_slice(&[0xff, 0xff, 0xff]);
+        } else {
+            s.extend(t.as_bytes());
+        }
+        s.extend_from_slice(&mut u32_to_vec(ids.len() as u32));
+        s.extend_from_slice(&mut off(offset));
+    }
+
+    let name_index_len = 7 * (version * 4);
+    let post_index_len = 12 * (3 + 4 + version * 4);
+    let post_index_end = 16 + 1 + names_len + post_len + sections_len + name_index_len +
+                         post_index_len;
+
+    if version > 1 {
+        // checksums
+        let bounds = [16,
+                      16 + 1,
+                      16 + 1 + names_len,
+                      16 + 1 + names_len + post_len,
+                      16 + 1 + names_len + post_len + meta_len,
+                      16 + 1 + names_len + post_len + sections_len,
+                      16 + 1 + names_len + post_len + sections_len + name_index_len,
+                      post_index_end];
+        for (i, &id) in [0x100, 0x101, 0x102, 1, 4, 0x103, 0x104].iter().enumerate() {
+            let mut h = XxHash64::with_seed(0);
+            h.write(&s[bounds[i] as usize..bounds[i + 1] as usize]);
+            s.extend(u32_to_vec(id));
+            s.extend(u32_to_vec((h.finish() >> 32) as u32));
+            s.extend(u32_to_vec(h.finish() as u32));
+        }
+
+        // section table
+        s.extend(u32_to_vec(1));
+        s.extend_from_slice(&mut off(16 + 1 + names_len + post_len));
+        s.extend_from_slice(&mut off(meta_len));
+        s.extend(u32_to_vec(4));
+        s.extend_from_slice(&mut off(16 + 1 + names_len + post_len + meta_len));
+        s.extend_from_slice(&mut off(options_len));
+        s.extend(u32_to_vec(2));
+        s.extend_from_slice(&mut off(post_index_end));
+        s.extend_from_slice(&mut off(7 * 12));
+    }
 
     // trailer
-    s.extend_from_slice(&mut u32_to_vec(16));
-    s.extend_from_slice(&mut u32_to_vec(16 + 1));
-    s.extend_from_slice(&mut u32_to_vec(16 + 1 + 38));
-    s.extend_from_slice(&mut u32_to_vec(16 + 1 + 38 + 62));
-    s.extend_from_slice(&mut u32_to_vec(16 + 1 + 38 + 62 + 28));
+    s.extend_from_slice(&mut off(16));
+    s.extend_from_slice(&mut off(16 + 1));
+    s.extend_from_slice(&mut off(16 + 1 + names_len));
+    s.extend_from_slice(&mut off(16 + 1 + names_len + post_len + sections_len));
+    s.extend_from_slice(&mut off(16 + 1 + names_len + post_len + sections_len + name_index_len));
+    if version > 1 {
+        s.extend_from_slice(&mut off(post_index_end + 7 * 12));
+    }
 
     s.extend_from_slice("\ncsearch trailr\n".as_bytes());
 
@@ -151,6 +230,35 @@ fn file_list(list: Vec<u32>) -> Vec<u8> {
     buf
 }
 
+// Encodes a version 2 posting list that fits in a single block
+fn block_list(list: &[u32]) -> Vec<u8> {
+    let mut buf = u32_to_vec(*list.last().unwrap());
+    buf.extend(u32_to_vec(0));
+    let deltas = list.iter()
+        .enumerate()
+        .map(|(i, &x)| if i == 0 { x } else { x - list[i - 1] })
+        .collect::<Vec<_>>();
+    let width = 32 - deltas.iter().max().unwrap().leading_zeros() as usize;
+    buf.push(width as u8);
+    let mut bits = 0u64;
+    for (i, &d) in deltas.iter().enumerate() {
+        bits |= (d as u64) << (i * width);
+    }
+    for i in 0..(deltas.len() * width + 7) / 8 {
+        buf.push((bits >> (8 * i)) as u8);
+    }
+    buf
+}
+
+fn offset_to_vec(value: u32, version: u32) -> Vec<u8> {
+    let mut v = Vec::new();
+    if version > 1 {
+        v.extend_from_slice(&[0, 0, 0, 0]);
+    }
+    v.extend(u32_to_vec(value));
+    v
+}
+
 fn u32_to_vec(value: u32) -> Vec<u8> {
     let mut v = Vec::new();
     v.push((value >> 24) as u8);
@@ -170,6 +278,172 @@ fn test_trivial_write_disk() {
     test_write(true);
 }
 
+#[test]
+fn test_read_v1() {
+    let mut f = NamedTempFile::new().unwrap();
+    f.write_all(&trivial_index(1)).unwrap();
+    f.flush().unwrap();
+    check_trivial_index(&IndexReader::open(f.path()).unwrap(), IndexVersion::V1);
+}
+
+#[test]
+fn test_read_v2() {
+    let f = NamedTempFile::new().unwrap();
+    build_flush_index(f.path(), vec![], false, trivial_files());
+    check_trivial_index(&IndexReader::open(f.path()).unwrap(), IndexVersion::V2);
+}
+
+fn check_trivial_index(ix: &IndexReader, version: IndexVersion) {
+    assert_eq!(ix.version, version);
+    assert_eq!(ix.num_name, 6);
+    let names = (0..ix.num_name as u32).map(|i| ix.name(i)).collect::<Vec<_>>();
+    assert_eq!(names, vec!["afile4", "f0", "file1", "file3", "file5", "thefile2"]);
+    assert_eq!(PostReader::list(ix, tri('a', 'b', 'c'), &None).unwrap(), set![0, 3]);
+    assert_eq!(PostReader::list(ix, tri('\n', 'a', 'b'), &None).unwrap(), set![3, 5]);
+    assert_eq!(PostReader::list(ix, tri('x', 'x', 'x'), &None).unwrap(), set![]);
+    if version == IndexVersion::V1 {
+        assert_eq!(ix.file_meta(0), None);
+    } else {
+        assert_eq!(ix.file_meta(0).map(|m| m.size), Some(6));
+        assert_eq!(ix.file_meta(1).map(|m| m.size), Some(2));
+        assert_eq!(ix.file_meta(6), None);
+    }
+}
+
+#[test]
+fn test_add_file_meta() {
+    let mut src = NamedTempFile::new().unwrap();
+    src.write_all(b"some file contents\n").unwrap();
+    src.flush().unwrap();
+    let f = NamedTempFile::new().unwrap();
+    {
+        let mut ix = IndexWriter::new(f.path()).unwrap();
+        ix.add_file(src.path()).unwrap();
+        ix.flush().unwrap();
+    }
+    let ix = IndexReader::open(f.path()).unwrap();
+    let meta = ix.file_meta(0).unwrap();
+    let mut h = XxHash64::with_seed(0);
+    h.write(b"some file contents\n");
+    assert_eq!(meta.size, 19);
+    assert_eq!(meta.hash, h.finish());
+    assert_eq!(meta.modified(), fs::metadata(src.path()).unwrap().modified().unwrap());
+}
+
+#[test]
+fn test_verify() {
+    let mut f = NamedTempFile::new().unwrap();
+    build_flush_index(f.path(), vec![], false, trivial_files());
+    IndexReader::open_verified(f.path()).unwrap();
+
+    // flip a bit in the posting lists
+    let mut data = Vec::new();
+    f.read_to_end(&mut data).unwrap();
+    data[16 + 1 + 36 + 10] ^= 0x40;
+    let mut corrupt = NamedTempFile::new().unwrap();
+    corrupt.write_all(&data).unwrap();
+    corrupt.flush().unwrap();
+    let ix = IndexReader::open(corrupt.path()).unwrap();
+    assert!(ix.verify().is_err());
+    assert_eq!(IndexReader::open_verified(corrupt.path()).unwrap_err().kind(),
+               ReaderErrorKind::ChecksumMismatch);
+}
+
+fn open_err(data: &[u8]) -> ReaderErrorKind {
+    IndexReader::from_bytes(data.to_vec()).unwrap_err().kind()
+}
+
+#[test]
+fn test_open_invalid() {
+    let good = trivial_index(2);
+    assert_eq!(open_err(b"csearch"), ReaderErrorKind::Truncated);
+    assert_eq!(open_err(&good[..good.len() / 2]), ReaderErrorKind::BadMagic);
+    assert_eq!(open_err(&good[..40]), ReaderErrorKind::Truncated);
+
+    let mut bad = good.clone();
+    bad[0] = b'C';
+    assert_eq!(open_err(&bad), ReaderErrorKind::BadMagic);
+
+    let mut bad = good.clone();
+    bad[14] = b'7';
+    assert_eq!(open_err(&bad), ReaderErrorKind::UnsupportedVersion);
+
+    // point the name index past the section table
+    let mut bad = good.clone();
+    let n = bad.len() - 16 - 6 * 8;
+    bad[n + 3 * 8 + 4] = 0x7f;
+    assert_eq!(open_err(&bad), ReaderErrorKind::OffsetOutOfRange);
+
+    // a section that runs into the section table
+    let mut bad = good.clone();
+    let table = n - 2 * 20;
+    bad[table + 12] = 0x7f;
+    assert_eq!(open_err(&bad), ReaderErrorKind::OffsetOutOfRange);
+}
+
+#[test]
+fn test_corrupt_posting_list() {
+    // claim the first posting list is much longer than it is
+    let mut data = trivial_index(1);
+    let n = data.len() - 16 - 5 * 4;
+    let post_index = u32::from_be_bytes([data[n + 16], data[n + 17], data[n + 18], data[n + 19]]);
+    data[post_index as usize + 4] = 0x7f;
+    let mut f = NamedTempFile::new().unwrap();
+    f.write_all(&data).unwrap();
+    f.flush().unwrap();
+
+    let ix = IndexReader::open(f.path()).unwrap();
+    let (trigram, _, _) = ix.list_at(0);
+    assert_eq!(PostReader::list(&ix, trigram, &None).unwrap_err().kind(),
+               ReaderErrorKind::CorruptPostingList);
+    assert_eq!(PostSet::new(&ix).or(trigram).err().unwrap().kind(),
+               ReaderErrorKind::CorruptPostingList);
+    // other lists are still readable
+    assert_eq!(PostReader::list(&ix, tri('a', 'b', 'c'), &None).unwrap(), set![0, 3]);
+}
+
+fn write_with_budget(out: &NamedTempFile,
+                     files: &BTreeMap<String, String>,
+                     budget: Option<usize>) -> Vec<u8> {
+    {
+        let mut ix = IndexWriter::new(out.path()).unwrap();
+        ix.created = UNIX_EPOCH;
+        if let Some(b) = budget {
+            ix.post_mem_budget = b;
+        }
+        for (name, contents) in files {
+            ix.add(name, contents.as_bytes(), contents.len() as u64).unwrap();
+        }
+        ix.flush().unwrap();
+    }
+    let mut data = Vec::new();
+    fs::File::open(out.path()).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
+
+#[test]
+fn test_write_spilled_runs() {
+    let trivial = trivial_files()
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let f = NamedTempFile::new().unwrap();
+    // 3 post entries per run
+    assert_eq!(write_with_budget(&f, &trivial, Some(3 * 8)), trivial_index(2));
+
+    let mut files = BTreeMap::new();
+    for i in 0..300 {
+        files.insert(format!("file{:03}", i),
+                     format!("common text {} and {}\n", i * 7919 % 1000, i % 13));
+    }
+    let f2 = NamedTempFile::new().unwrap();
+    let spilled = write_with_budget(&f2, &files, Some(1000));
+    let f3 = NamedTempFile::new().unwrap();
+    assert_eq!(spilled, write_with_budget(&f3, &files, None));
+    let ix = IndexReader::open_verified(f2.path()).unwrap();
+    assert_eq!(PostReader::list(&ix, tri('c', 'o', 'm'), &None).unwrap().len(), 300);
+}
+
 fn test_write(do_flush: bool) {
     let mut f = NamedTempFile::new().unwrap();
     {
@@ -178,8 +452,8 @@ fn test_write(do_flush: bool) {
     }
 
     let mut data = Vec::new();
-    f.deref_mut().read_to_end(&mut data).unwrap();
-    let want = trivial_index();
+    f.as_file_mut().read_to_end(&mut data).unwrap();
+    let want = trivial_index(2);
     if data != want {
         let mut i = 0;
         while i < data.len() && i < want.len() && data[i] == want[i] {
@@ -193,3 +467,427 @@ fn test_write(do_flush: bool) {
                i);
     }
 }
+
+#[test]
+fn test_add_files_parallel() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    let mut files = Vec::new();
+    for i in 0..200 {
+        let p = dir.join(format!("file{:03}", i));
+        let contents = if i % 50 == 7 {
+            // skipped as binary
+            b"bin\x00ary".to_vec()
+        } else {
+            format!("shared words {} and {}\n", i * 7919 % 1000, i % 17).into_bytes()
+        };
+        fs::File::create(&p).unwrap().write_all(&contents).unwrap();
+        files.push(p);
+    }
+    files.push(dir.join("missing"));
+
+    let build = |jobs: usize| {
+        let f = NamedTempFile::new().unwrap();
+        let mut skipped = Vec::new();
+        {
+            let mut ix = IndexWriter::new(f.path()).unwrap();
+            ix.created = UNIX_EPOCH;
+            ix.add_files(files.iter().cloned(), jobs, |f, _| skipped.push(f.clone())).unwrap();
+            ix.flush().unwrap();
+        }
+        let mut data = Vec::new();
+        fs::File::open(f.path()).unwrap().read_to_end(&mut data).unwrap();
+        (data, skipped)
+    };
+    let (serial, skipped) = build(1);
+    assert_eq!(skipped,
+               vec![dir.join("file007"),
+                    dir.join("file057"),
+                    dir.join("file107"),
+                    dir.join("file157"),
+                    dir.join("missing")]);
+    for &jobs in &[2, 4, 16] {
+        let (parallel, parallel_skipped) = build(jobs);
+        assert!(parallel == serial, "{} jobs wrote a different index", jobs);
+        assert_eq!(parallel_skipped, skipped);
+    }
+}
+
+#[test]
+fn test_add_files_worker_panic() {
+    use std::path::Path;
+    use self::libcindex::writer::{FileFilter, IndexErrorKind, IndexResult};
+
+    struct PanicOn(&'static str);
+
+    impl FileFilter for PanicOn {
+        fn check_file(&self, path: &Path, _size: u64) -> IndexResult<()> {
+            if path.ends_with(self.0) {
+                panic!("can't index {}", self.0);
+            }
+            Ok(())
+        }
+    }
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let files = (0..20).map(|i| dir.path().join(format!("file{:02}", i))).collect::<Vec<_>>();
+    for f in &files {
+        fs::File::create(f).unwrap().write_all(b"some text\n").unwrap();
+    }
+    let out = NamedTempFile::new().unwrap();
+    let mut ix = IndexWriter::new(out.path()).unwrap();
+    ix.add_filter(PanicOn("file13"));
+    let err = ix.add_files(files, 4, |f, e| panic!("{}: {}", f.display(), e)).unwrap_err();
+    assert_eq!(err.kind(), IndexErrorKind::WorkerFailed);
+    let msg = err.to_string();
+    assert!(msg.contains("file13") && msg.contains("can't index file13"), "{}", msg);
+}
+
+#[test]
+fn test_stored_options() {
+    let options = IndexOptions::builder()
+        .max_line_len(1)
+        .max_trigram_count(1000)
+        .build()
+        .unwrap();
+    let f = NamedTempFile::new().unwrap();
+    {
+        let mut ix = IndexWriter::new(f.path()).unwrap();
+        ix.set_options(options.clone());
+        for (name, contents) in trivial_files() {
+            // "afile4", "file3" and "file5" have lines that are too long,
+            // not counting the first two bytes
+            let _ = ix.add(name, contents.as_bytes(), contents.len() as u64);
+        }
+        ix.flush().unwrap();
+    }
+    let ix = IndexReader::open_verified(f.path()).unwrap();
+    assert_eq!(ix.num_name, 3);
+    let stored = StoredOptions::read(&ix).unwrap().unwrap();
+    assert_eq!(stored.options, options);
+    assert_eq!(stored.tool_version, TOOL_VERSION);
+    assert!(stored.created > UNIX_EPOCH);
+
+    // version 1 has nowhere to put them
+    {
+        let mut ix = IndexWriter::new(f.path()).unwrap();
+        ix.version = IndexVersion::V1;
+        ix.flush().unwrap();
+    }
+    let ix = IndexReader::open(f.path()).unwrap();
+    assert_eq!(StoredOptions::read(&ix).unwrap(), None);
+}
+
+#[test]
+fn test_custom_filter() {
+    use std::path::Path;
+    use self::libcindex::writer::{ContentFilter, FileFilter, IndexError, IndexErrorKind,
+                                  IndexResult};
+
+    /// Skips generated protobuf code, by name or by its header
+    struct NoGenerated;
+    struct Header(Vec<u8>);
+
+    impl FileFilter for NoGenerated {
+        fn check_file(&self, path: &Path, _size: u64) -> IndexResult<()> {
+            if path.to_string_lossy().ends_with(".pb.go") {
+                return Err(IndexError::new(IndexErrorKind::Filtered, "generated"));
+            }
+            Ok(())
+        }
+        fn content_filter(&self, _path: &Path, _size: u64) -> Option<Box<ContentFilter>> {
+            Some(Box::new(Header(Vec::new())))
+        }
+    }
+
+    impl ContentFilter for Header {
+        fn check(&mut self, chunk: &[u8]) -> IndexResult<()> {
+            let n = 17 - self.0.len();
+            self.0.extend(chunk.iter().take(n));
+            Ok(())
+        }
+        fn finish(&mut self) -> IndexResult<()> {
+            if self.0 == b"// Code generated" {
+                return Err(IndexError::new(IndexErrorKind::Filtered, "generated"));
+            }
+            Ok(())
+        }
+    }
+
+    let f = NamedTempFile::new().unwrap();
+    let mut ix = IndexWriter::new(f.path()).unwrap();
+    ix.add_filter(NoGenerated);
+    let mut add = |name: &str, contents: &str| {
+        ix.add(name, contents.as_bytes(), contents.len() as u64).map_err(|e| e.kind())
+    };
+    assert_eq!(add("x.pb.go", "package x\n"), Err(IndexErrorKind::Filtered));
+    assert_eq!(add("y.go", "// Code generated by protoc\n"),
+               Err(IndexErrorKind::Filtered));
+    assert_eq!(add("z.go", "package z\n"), Ok(()));
+    // names are checked before contents, and the built-in filters first
+    assert_eq!(add("z.pb.go", "bin\x00ary"), Err(IndexErrorKind::Filtered));
+    assert_eq!(add("w.go", "// Code generated\x00"),
+               Err(IndexErrorKind::BinaryDataPresent));
+    ix.flush().unwrap();
+    assert_eq!(IndexReader::open(f.path()).unwrap().num_name, 1);
+}
+
+#[test]
+fn test_flush_stats() {
+    use self::libcindex::writer::{IndexErrorKind, LARGEST_POSTING_LISTS};
+
+    let f = NamedTempFile::new().unwrap();
+    let mut ix = IndexWriter::new(f.path()).unwrap();
+    for (name, contents) in trivial_files() {
+        ix.add(name, contents.as_bytes(), contents.len() as u64).unwrap();
+    }
+    ix.add("binary", &b"bin\x00ary"[..], 7).unwrap_err();
+    ix.add_file("/nonexistent/file").unwrap_err();
+    assert_eq!(ix.stats().files_indexed, 6);
+    let stats = ix.flush().unwrap();
+
+    assert_eq!(stats.files_indexed, 6);
+    assert_eq!(stats.total_skipped(), 2);
+    assert_eq!(stats.files_skipped[&IndexErrorKind::BinaryDataPresent], 1);
+    assert_eq!(stats.files_skipped[&IndexErrorKind::IoError(std::io::ErrorKind::NotFound)],
+               1);
+    assert_eq!(stats.bytes_read, 2 + 3 + 4 + 5 + 6 + 6);
+    assert_eq!(stats.distinct_trigrams, 11);
+    assert_eq!(stats.index_bytes, fs::metadata(f.path()).unwrap().len());
+    assert_eq!(stats.largest_posting_lists.len(), LARGEST_POSTING_LISTS);
+    assert_eq!(&stats.largest_posting_lists[..4],
+               &[(tri('\n', 'a', 'b'), 2),
+                 (tri('a', 'b', 'c'), 2),
+                 (tri('b', 'c', '\n'), 2),
+                 (tri('\n', 'a', '\n'), 1)]);
+    assert!(stats.merge_time <= stats.flush_time);
+}
+
+#[test]
+fn test_deduplicate() {
+    use self::libcsearch::regexp::RegexInfo;
+    use self::regex_syntax::Expr;
+
+    let files = [("/v/a/lib.c", "int lib(void);\n"),
+                 ("/v/b/lib.c", "int lib(void);\n"),
+                 ("/v/c/main.c", "int main(void);\n"),
+                 ("/v/d/lib.c", "int lib(void);\n")];
+    let f = NamedTempFile::new().unwrap();
+    let build = |deduplicate: bool| {
+        let mut ix = IndexWriter::new(f.path()).unwrap();
+        if deduplicate {
+            ix.deduplicate = true;
+        }
+        for &(name, contents) in &files {
+            ix.add(name, contents.as_bytes(), contents.len() as u64).unwrap();
+        }
+        ix.flush().unwrap()
+    };
+    // off by default
+    assert_eq!(build(false).duplicate_files, 0);
+    let stats = build(true);
+    assert_eq!(stats.files_indexed, 4);
+    assert_eq!(stats.duplicate_files, 2);
+
+    let ix = IndexReader::open_verified(f.path()).unwrap();
+    assert_eq!(ix.num_name, 4);
+    assert_eq!(ix.duplicates(0), vec![1, 3]);
+    assert_eq!(ix.duplicates(2), vec![]);
+    assert_eq!(ix.file_meta(3).map(|m| m.size), Some(15));
+    assert_eq!(PostReader::list(&ix, tri('l', 'i', 'b'), &None).unwrap(),
+               set![0]);
+    let query = |re: &str| {
+        let q = RegexInfo::new(Expr::parse(re).unwrap()).unwrap().query;
+        ix.query(q).unwrap().into_inner()
+    };
+    assert_eq!(query(r"lib\("), set![0, 1, 3]);
+    assert_eq!(query(r"int"), set![0, 1, 2, 3]);
+    assert_eq!(query(r"main"), set![2]);
+}
+
+#[test]
+fn test_reuse_previous() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    // files are only reused if they were modified before the previous
+    // index was started
+    let modified = UNIX_EPOCH + Duration::from_secs(1500000000);
+    let started = modified + Duration::from_secs(60);
+    let write = |name: &str, contents: &str| {
+        let mut f = fs::File::create(dir.join(name)).unwrap();
+        f.write_all(contents.as_bytes()).unwrap();
+        f.set_modified(modified).unwrap();
+    };
+    let build = |names: &[&str],
+                 previous: Option<&NamedTempFile>,
+                 options: IndexOptions,
+                 created: SystemTime| {
+        let f = NamedTempFile::new().unwrap();
+        let stats = {
+            let mut ix = IndexWriter::new(f.path()).unwrap();
+            ix.created = created;
+            ix.deduplicate = true;
+            ix.set_options(options);
+            if let Some(p) = previous {
+                ix.set_previous(IndexReader::open(p.path()).unwrap());
+            }
+            ix.add_paths(vec![dir.as_os_str().to_os_string()]);
+            for name in names {
+                ix.add_file(dir.join(name)).unwrap();
+            }
+            ix.flush().unwrap()
+        };
+        let mut data = Vec::new();
+        fs::File::open(f.path()).unwrap().read_to_end(&mut data).unwrap();
+        (f, data, stats)
+    };
+    write("a.c", "int alpha;\n");
+    write("b.c", "int beta;\n");
+    write("c.c", "int alpha;\n");
+    write("d.c", "int delta;\n");
+    let old_names = ["a.c", "b.c", "c.c", "d.c"];
+    let (old, _, _) = build(&old_names, None, IndexOptions::default(), started);
+
+    write("b.c", "int bravo;\n");
+    fs::remove_file(dir.join("d.c")).unwrap();
+    write("e.c", "int echo;\n");
+    let names = ["a.c", "b.c", "c.c", "e.c"];
+    let (_, fresh, _) = build(&names, None, IndexOptions::default(), UNIX_EPOCH);
+    let (new, reused, stats) = build(&names, Some(&old), IndexOptions::default(), UNIX_EPOCH);
+    assert_eq!(stats.files_indexed, 4);
+    assert_eq!(stats.files_reused, 2);
+    assert_eq!(stats.duplicate_files, 1);
+    assert_eq!(stats.bytes_read, 21);
+    assert!(reused == fresh, "reusing files wrote a different index");
+
+    let ix = IndexReader::open_verified(new.path()).unwrap();
+    assert_eq!(PostReader::list(&ix, tri('a', 'l', 'p'), &None).unwrap(), set![0]);
+    assert_eq!(ix.duplicates(0), vec![2]);
+    assert_eq!(PostReader::list(&ix, tri('r', 'a', 'v'), &None).unwrap(), set![1]);
+    assert_eq!(PostReader::list(&ix, tri('e', 'l', 't'), &None).unwrap(), set![]);
+
+    // files indexed with other options might have been skipped
+    let options = IndexOptions::builder().max_line_len(500).build().unwrap();
+    let (_, _, stats) = build(&names, Some(&old), options, UNIX_EPOCH);
+    assert_eq!(stats.files_reused, 0);
+
+    // files modified as the index was started could have been rewritten
+    // since without their modification times changing
+    let (old, _, _) = build(&names, None, IndexOptions::default(), modified);
+    let (_, _, stats) = build(&names, Some(&old), IndexOptions::default(), UNIX_EPOCH);
+    assert_eq!(stats.files_reused, 0);
+}
+
+#[test]
+fn test_reuse_previous_filtered() {
+    use std::path::Path;
+    use self::libcindex::writer::{FileFilter, IndexError, IndexErrorKind, IndexResult};
+
+    struct Skip(&'static str);
+
+    impl FileFilter for Skip {
+        fn check_file(&self, path: &Path, _size: u64) -> IndexResult<()> {
+            if path.ends_with(self.0) {
+                return Err(IndexError::new(IndexErrorKind::Filtered, "skipped"));
+            }
+            Ok(())
+        }
+    }
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let files = ["a.c", "b.c"].iter().map(|n| dir.path().join(n)).collect::<Vec<_>>();
+    for f in &files {
+        let mut f = fs::File::create(f).unwrap();
+        f.write_all(b"int alpha;\n").unwrap();
+        f.set_modified(UNIX_EPOCH + Duration::from_secs(1500000000)).unwrap();
+    }
+    let old = NamedTempFile::new().unwrap();
+    {
+        let mut ix = IndexWriter::new(old.path()).unwrap();
+        for f in &files {
+            ix.add_file(f).unwrap();
+        }
+        ix.flush().unwrap();
+    }
+
+    // b.c hasn't changed, but it's skipped now
+    let new = NamedTempFile::new().unwrap();
+    let mut ix = IndexWriter::new(new.path()).unwrap();
+    ix.set_previous(IndexReader::open(old.path()).unwrap());
+    ix.add_filter(Skip("b.c"));
+    ix.add_file(&files[0]).unwrap();
+    assert_eq!(ix.add_file(&files[1]).unwrap_err().kind(), IndexErrorKind::Filtered);
+    let stats = ix.flush().unwrap();
+    assert_eq!(stats.files_indexed, 1);
+    assert_eq!(stats.files_reused, 0);
+    assert_eq!(IndexReader::open(new.path()).unwrap().num_name, 1);
+}
+
+#[test]
+fn test_add_document() {
+    use self::libcindex::writer::IndexErrorKind;
+    use self::libcsearch::source::{ContentSource, MemorySource};
+
+    let docs = [("db:queries/1", "SELECT name FROM users"),
+                ("db:queries/2", "DELETE FROM users")];
+    let f = NamedTempFile::new().unwrap();
+    {
+        let mut ix = IndexWriter::new(f.path()).unwrap();
+        ix.root = Some("/src".into());
+        ix.add_paths(vec!["/src".into()]);
+        let err = ix.add_document("", &b"x"[..], 1).unwrap_err();
+        assert_eq!(err.kind(), IndexErrorKind::FileNameError);
+        let err = ix.add_document("./x", &b"x"[..], 1).unwrap_err();
+        assert_eq!(err.kind(), IndexErrorKind::FileNameError);
+        for &(id, contents) in &docs {
+            ix.add_document(id, contents.as_bytes(), contents.len() as u64).unwrap();
+        }
+        ix.add("/src/users.sql", &b"CREATE TABLE users"[..], 18).unwrap();
+        ix.flush().unwrap();
+    }
+
+    // IDs aren't resolved against the root like file names are
+    let ix = IndexReader::open_verified(f.path()).unwrap();
+    assert_eq!(ix.num_name, 3);
+    assert_eq!(ix.name_bytes(1), b"db:queries/2".to_vec());
+    assert_eq!(ix.name_bytes(2), b"/src/users.sql".to_vec());
+    assert_eq!(ix.file_meta(1).map(|m| m.size), Some(17));
+
+    let mut source = MemorySource::new();
+    for &(id, contents) in &docs {
+        source.insert(id, contents);
+    }
+    let found = PostReader::list(&ix, tri('S', 'E', 'L'), &None).unwrap();
+    assert_eq!(found, set![0]);
+    let mut contents = String::new();
+    source.open(&ix.name_bytes(0)).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, docs[0].1);
+}
+
+#[test]
+fn test_in_memory() {
+    use std::io::{Cursor, ErrorKind};
+
+    let mut ix = IndexWriter::in_memory();
+    ix.created = UNIX_EPOCH;
+    // spill runs too, which also stay in memory
+    ix.post_mem_budget = 3 * 8;
+    for (name, contents) in trivial_files() {
+        ix.add(name, contents.as_bytes(), contents.len() as u64).unwrap();
+    }
+    let (buf, stats) = ix.finish().unwrap();
+    let data = buf.into_inner();
+    assert_eq!(stats.index_bytes, data.len() as u64);
+    assert_eq!(data, trivial_index(2));
+    let ix = IndexReader::from_bytes(data).unwrap();
+    ix.verify().unwrap();
+    check_trivial_index(&ix, IndexVersion::V2);
+
+    let ix = IndexReader::from_bytes(&include_bytes!("fixtures/go/trivial.csi")[..]).unwrap();
+    check_trivial_index(&ix, IndexVersion::V1);
+
+    let mut c = Cursor::new(Vec::new());
+    c.write_all(b"header").unwrap();
+    assert_eq!(IndexWriter::from_writer(c).err().unwrap().kind(),
+               ErrorKind::InvalidInput);
+}